@@ -0,0 +1,137 @@
+// Inner loops the dispatch loop spends its time in, run with `cargo bench`.
+use criterion::{ criterion_group, criterion_main, Criterion };
+use zub::{ ir::*, vm::* };
+
+fn run(program: &[ExprNode]) -> VM {
+    let mut vm = VM::new();
+    vm.exec(program, false).unwrap();
+    vm
+}
+
+// Assignments leave their value behind, which loops have to drop
+fn assign(builder: &mut IrBuilder, lhs: ExprNode, rhs: ExprNode) {
+    builder.mutate(lhs, rhs);
+    builder.emit(Expr::Pop.node(TypeInfo::nil()))
+}
+
+fn fib() -> Vec<ExprNode> {
+    let mut builder = IrBuilder::new();
+
+    let fib = builder.function(Binding::global("fib"), &["n"], |builder| {
+        let n = builder.var(Binding::local("n", 1, 1));
+        let fib = builder.var(Binding::global("fib"));
+
+        let lhs = builder.call(fib.clone(), vec![builder.binary(n.clone(), BinaryOp::Sub, builder.number(1.0))], None);
+        let rhs = builder.call(fib, vec![builder.binary(n.clone(), BinaryOp::Sub, builder.number(2.0))], None);
+
+        let small = builder.binary(n.clone(), BinaryOp::Lt, builder.number(2.0));
+        let result = builder.ternary(small, n, Some(builder.binary(lhs, BinaryOp::Add, rhs)));
+
+        builder.ret(Some(result))
+    });
+    builder.emit(fib);
+
+    let call = builder.call(builder.var(Binding::global("fib")), vec![builder.number(20.0)], None);
+    builder.bind(Binding::global("result"), call);
+
+    builder.build()
+}
+
+// Sums the numbers below 100000 in locals
+fn loops() -> Vec<ExprNode> {
+    let mut builder = IrBuilder::new();
+
+    let sum = builder.function(Binding::global("sum"), &[], |builder| {
+        builder.bind(Binding::local("i", 1, 1), builder.number(0.0));
+        builder.bind(Binding::local("total", 1, 1), builder.number(0.0));
+
+        let i = builder.var(Binding::local("i", 1, 1));
+        let cond = builder.binary(i, BinaryOp::Lt, builder.number(100000.0));
+
+        let body = builder.while_(cond, |builder| {
+            let i = builder.var(Binding::local("i", 1, 1));
+            let total = builder.var(Binding::local("total", 1, 1));
+
+            let next = builder.binary(total.clone(), BinaryOp::Add, i.clone());
+            assign(builder, total, next);
+            let next = builder.binary(i.clone(), BinaryOp::Add, builder.number(1.0));
+            assign(builder, i, next);
+        });
+        builder.emit(body);
+
+        builder.ret(Some(builder.var(Binding::local("total", 1, 1))))
+    });
+    builder.emit(sum);
+
+    let call = builder.call(builder.var(Binding::global("sum")), vec![], None);
+    builder.bind(Binding::global("result"), call);
+
+    builder.build()
+}
+
+// Appends to a string a thousand times
+fn strings() -> Vec<ExprNode> {
+    let mut builder = IrBuilder::new();
+
+    builder.bind(Binding::global("text"), builder.string(""));
+    builder.bind(Binding::global("i"), builder.number(0.0));
+
+    let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(1000.0));
+
+    let body = builder.while_(cond, |builder| {
+        let text = builder.var(Binding::global("text"));
+        let i = builder.var(Binding::global("i"));
+
+        let next = builder.binary(text.clone(), BinaryOp::Add, i.clone());
+        assign(builder, text, next);
+        let next = builder.binary(i.clone(), BinaryOp::Add, builder.number(1.0));
+        assign(builder, i, next);
+    });
+    builder.emit(body);
+
+    builder.build()
+}
+
+// Reads and writes a dict entry ten thousand times
+fn dicts() -> Vec<ExprNode> {
+    let mut builder = IrBuilder::new();
+
+    let keys = vec![builder.string("a"), builder.string("b"), builder.string("c")];
+    let values = vec![builder.number(1.0), builder.number(2.0), builder.number(3.0)];
+
+    builder.bind(Binding::global("counts"), builder.dict(keys, values));
+    builder.bind(Binding::global("i"), builder.number(0.0));
+
+    let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(10000.0));
+
+    let body = builder.while_(cond, |builder| {
+        let counts = builder.var(Binding::global("counts"));
+        let i = builder.var(Binding::global("i"));
+
+        let current = builder.binary(counts.clone(), BinaryOp::Index, builder.string("b"));
+        let set = builder.set_element(counts, builder.string("b"), builder.binary(current, BinaryOp::Add, builder.number(1.0)));
+        builder.emit(set);
+
+        let next = builder.binary(i.clone(), BinaryOp::Add, builder.number(1.0));
+        assign(builder, i, next);
+    });
+    builder.emit(body);
+
+    builder.build()
+}
+
+fn benches(c: &mut Criterion) {
+    let programs = [
+        ("fib", fib()),
+        ("loops", loops()),
+        ("strings", strings()),
+        ("dicts", dicts()),
+    ];
+
+    for (name, program) in programs.iter() {
+        c.bench_function(name, |b| b.iter(|| run(program)));
+    }
+}
+
+criterion_group!(vm, benches);
+criterion_main!(vm);
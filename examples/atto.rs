@@ -3,7 +3,7 @@ use zub::{ir::*, vm::*};
 fn parse_expr(
     builder: &mut IrBuilder,
     slice: &mut &[&str],
-    get_binding: &impl Fn(&str) -> Option<(Binding, usize)>,
+    get_binding: &impl Fn(&str) -> Option<(Binding, Option<usize>)>,
 ) -> Option<Node<Expr>> {
     match *slice {
         [] => None,
@@ -42,8 +42,6 @@ fn parse_expr(
             } {
                 Some(val)
             } else if let Some((binding, args)) = get_binding(ident) {
-                let args = (0..args).map(|_| parse_expr(builder, slice, get_binding)).collect::<Option<_>>()?;
-
                 let mut inner_binding = binding.clone();
 
                 if inner_binding.depth == Some(0) {
@@ -57,11 +55,16 @@ fn parse_expr(
                     //      if sum { let's go with upvalue ... limiting param names for now }
                 }
 
-                Some(builder.call(
-                    builder.var(inner_binding),
-                    args,
-                    None,
-                ))
+                let var = builder.var(inner_binding);
+
+                // Parameters are plain values, only functions get called
+                if let Some(args) = args {
+                    let args = (0..args).map(|_| parse_expr(builder, slice, get_binding)).collect::<Option<_>>()?;
+
+                    Some(builder.call(var, args, None))
+                } else {
+                    Some(var)
+                }
             } else {
                 None
             }
@@ -78,7 +81,7 @@ fn parse_fn<'a>(
         [] => None,
         ["fn", name, ..] => {
             let params = slice[2..]
-                .into_iter()
+                .iter()
                 .take_while(|token| **token != "is")
                 .copied()
                 .collect::<Vec<_>>();
@@ -86,16 +89,16 @@ fn parse_fn<'a>(
             *slice = &slice[3 + params.len()..];
 
             let func = builder.function(
-                Binding::local(*name, 0, 0),
+                Binding::local(name, 0, 0),
                 &params,
                 |builder| {
                     let body = parse_expr(builder, slice, &|ident| if ident == *name {
-                        Some((Binding::local(ident, 1, 0), params.len()))
-                    } else if params.contains(&&ident) {
-                        Some((Binding::local(ident, 1, 1), 0))
+                        Some((Binding::local(ident, 1, 0), Some(params.len())))
+                    } else if params.contains(&ident) {
+                        Some((Binding::local(ident, 1, 1), None))
                     } else {
                         get_binding(ident)
-                            .map(|args| (Binding::local(ident, 1, 1), args))
+                            .map(|args| (Binding::local(ident, 1, 1), Some(args)))
                     });
 
                     builder.ret(Some(body.unwrap()));
@@ -110,7 +113,7 @@ fn parse_fn<'a>(
     }
 }
 
-const CODE: &'static str = r#"
+const CODE: &str = r#"
 fn sum x is
     if = x 0
         1
@@ -142,6 +145,10 @@ fn main() {
 
     let mut vm = VM::new();
 
-    vm.exec(&build, false);
+    if let Err(err) = vm.exec(&build, false) {
+        eprintln!("{}", err);
+        return
+    }
+
     println!("{:?}", vm.globals["entry"]);
 }
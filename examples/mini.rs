@@ -118,6 +118,7 @@ impl Op {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 enum Statement {
     Let(String, Expression, Binding),
@@ -132,6 +133,7 @@ enum Statement {
     Expression(Expression)
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 enum Expression {
     Number(f64),
@@ -305,11 +307,9 @@ impl<'p> Parser<'p> {
                         Statement::Return(None)
                     )
                 } else {
-                    let a = Some(
+                    Some(
                         Statement::Return(Some(self.parse_expression().unwrap()))
-                    );
-
-                    a
+                    )
                 }
             }
 
@@ -318,14 +318,12 @@ impl<'p> Parser<'p> {
                 None
             }
 
-            c => {
-                let a = Some(
+            _ => {
+                Some(
                     Statement::Expression(
                         self.parse_expression().unwrap()
                     )
-                );
-
-                a
+                )
             },
         }
     }
@@ -334,7 +332,7 @@ impl<'p> Parser<'p> {
         use self::Token::*;
 
         if self.current() != LCurly {
-            panic!("Expected `{`")
+            panic!("Expected `{}`", "{")
         }
 
         self.next();
@@ -360,13 +358,13 @@ impl<'p> Parser<'p> {
         let cur = self.current();
 
         let expr = match cur {
-            Number(ref n) => {
+            Number(n) => {
                 Expression::Number(
-                    n.clone().parse::<f64>().unwrap()
+                    n.parse::<f64>().unwrap()
                 )
             },
             Ident(ref n) => {
-                if let Some(depth) = self.depth_table.get(&n.to_string()) {
+                if let Some(depth) = self.depth_table.get(*n) {
                     let mut binding = depth.clone();
 
                     if binding.depth.is_some() {
@@ -480,8 +478,6 @@ impl<'p> Parser<'p> {
     }
 
     fn parse_binary(&mut self, left: Expression) -> Expression {
-        use self::Token::*;
-        
         let mut expr_stack = vec!(left);
         let mut op_stack   = vec!(self.current().to_op().unwrap());
         self.next();
@@ -490,7 +486,7 @@ impl<'p> Parser<'p> {
 
         expr_stack.push(self.parse_expression().unwrap());
 
-        while op_stack.len() > 0 {
+        while !op_stack.is_empty() {
             while let Some(op) = self.current().to_op() {
                 self.next();
                 let precedence = op.prec();
@@ -548,21 +544,22 @@ impl<'p> Parser<'p> {
         self.top += 1
     }
 
-    fn current(&self) -> Token {
-        self.tokens[self.top.clone()].clone()
+    fn current(&self) -> Token<'_> {
+        self.tokens[self.top].clone()
     }
 
     fn current_slice(&self) -> Option<&str> {
         use self::Token::*;
 
         match self.current() {
-            Number(ref s) |
-            Ident(ref s) => Some(s),
+            Number(s) |
+            Ident(s) => Some(s),
             _ => None
         }
     }
 
-    fn peek(&self) -> Token {
+    #[allow(dead_code)]
+    fn peek(&self) -> Token<'_> {
         self.tokens[self.top + 1].clone()
     }
 }
@@ -575,7 +572,7 @@ fn codegen_expr(builder: &IrBuilder, expr: &Expression) -> ExprNode {
             builder.number(*n)
         },
 
-        Var(name, depth) => {
+        Var(_name, depth) => {
             builder.var(depth.clone())
         },
 
@@ -583,17 +580,17 @@ fn codegen_expr(builder: &IrBuilder, expr: &Expression) -> ExprNode {
             let mut args_ir = Vec::new();
 
             for arg in args.iter() {
-                args_ir.push(codegen_expr(&builder, arg))
+                args_ir.push(codegen_expr(builder, arg))
             }
 
-            let callee_ir = codegen_expr(&builder, callee);
+            let callee_ir = codegen_expr(builder, callee);
 
             builder.call(callee_ir, args_ir, None)
         },
 
         Binary(left, op, right) => {
-            let left  = codegen_expr(&builder, left);
-            let right = codegen_expr(&builder, right);
+            let left  = codegen_expr(builder, left);
+            let right = codegen_expr(builder, right);
 
             builder.binary(left, op.to_ir(), right)
         },
@@ -603,11 +600,11 @@ fn codegen_expr(builder: &IrBuilder, expr: &Expression) -> ExprNode {
             let mut vals_ir = Vec::new();
 
             for key in keys.iter() {
-                keys_ir.push(codegen_expr(&builder, key))
+                keys_ir.push(codegen_expr(builder, key))
             }
 
             for value in values.iter() {
-                vals_ir.push(codegen_expr(&builder, value))
+                vals_ir.push(codegen_expr(builder, value))
             }
 
             builder.dict(keys_ir, vals_ir)
@@ -617,45 +614,39 @@ fn codegen_expr(builder: &IrBuilder, expr: &Expression) -> ExprNode {
     }
 }
 
-fn codegen(builder: &mut IrBuilder, ast: &Vec<Statement>) {
+fn codegen(builder: &mut IrBuilder, ast: &[Statement]) {
     use self::Statement::*;
     
     for s in ast.iter() {
         match s {
-            Let(name, expr, var) => {
-                let right = codegen_expr(&builder, expr);
+            Let(_name, expr, var) => {
+                let right = codegen_expr(builder, expr);
                 builder.bind(var.clone(), right)
             },
 
             Global(name, expr) => {
-                let right = codegen_expr(&builder, expr);
+                let right = codegen_expr(builder, expr);
                 builder.bind(Binding::global(name), right)
             },
 
-            Fun(name, params, body, var) => {
+            Fun(_name, params, body, var) => {
                 let params = params.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
 
-                let fun = builder.function(var.clone(), &params.as_slice(), |mut builder| {
-                    codegen(&mut builder, body)
+                let fun = builder.function(var.clone(), params.as_slice(), |builder| {
+                    codegen(builder, body)
                 });
 
                 builder.emit(fun);
             },
 
             Return(ref val) => {
-                let value = if let Some(v) = val {
-                    Some(
-                        codegen_expr(&builder, v)
-                    )
-                } else {
-                    None
-                };
+                let value = val.as_ref().map(|v| codegen_expr(builder, v));
 
                 builder.ret(value)
             },
 
             Expression(ref expr) => {
-                let expr = codegen_expr(&builder, expr);
+                let expr = codegen_expr(builder, expr);
                 builder.emit(expr)
             },
 
@@ -664,7 +655,7 @@ fn codegen(builder: &mut IrBuilder, ast: &Vec<Statement>) {
     }
 }
 
-const TEST: &'static str = r#"
+const TEST: &str = r#"
 let bar = 13.37;
 
 fn foo() {
@@ -693,7 +684,11 @@ fn main() {
     println!("{:#?}", ir);
 
     let mut vm = VM::new();
-    vm.exec(&ir, true);
+
+    if let Err(err) = vm.exec(&ir, true) {
+        eprintln!("{}", err);
+        return
+    }
 
     println!("{:#?}", vm.globals)
 }
\ No newline at end of file
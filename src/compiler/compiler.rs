@@ -1,4 +1,5 @@
 use super::chunk::{ Chunk, Op };
+use super::peephole;
 use super::*;
 
 #[derive(Debug, Clone)]
@@ -11,7 +12,7 @@ pub struct Local {
 
 #[derive(Debug, Clone)]
 struct UpValue {
-    pub index: u8,
+    pub index: u16,
     pub is_local: bool,
 }
 
@@ -24,6 +25,11 @@ pub struct CompileState {
     scope_depth: usize,
     breaks: Vec<usize>,
     method: bool,
+    handlers: usize,      // exception handlers active at this point of the function
+    loop_handlers: usize, // ... and when the innermost loop was entered
+    finally: Vec<(usize, ExprNode)>, // `finally` blocks of the trys being compiled, with the handlers outside each
+    long_jumps: bool,     // every jump takes a u32, for functions past 64 KiB
+    overflowed: bool,     // a short jump couldn't reach its target, compile again with long ones
 }
 
 impl CompileState {
@@ -45,26 +51,31 @@ impl CompileState {
             scope_depth,
             breaks: Vec::new(),
             method,
+            handlers: 0,
+            loop_handlers: 0,
+            finally: Vec::new(),
+            long_jumps: false,
+            overflowed: false,
         }
     }
 
-    fn capture_local(&mut self, var: &str) -> Option<u8> {
+    fn capture_local(&mut self, var: &str) -> Option<u16> {
         for (i, local) in self.locals.iter_mut().enumerate().rev() {
             if local.name == var {
                 local.captured = true;
 
-                return Some(i as u8)
+                return Some(i as u16)
             }
         }
 
         None
     }
 
-    fn add_local(&mut self, var: &str, depth: usize) -> u8 {
+    fn add_local(&mut self, var: &str, depth: usize) -> Result<u16, CompileError> {
         let depth = self.scope_depth - depth;
 
-        if self.locals.len() == std::u8::MAX as usize {
-            panic!("local variable overflow")
+        if self.locals.len() == u16::MAX as usize {
+            return Err(CompileError::TooManyLocals { name: var.into(), line: self.line })
         }
 
         self.locals.push(
@@ -76,28 +87,28 @@ impl CompileState {
             }
         );
 
-        (self.locals.len() - 1) as u8
+        Ok((self.locals.len() - 1) as u16)
     }
 
-    fn resolve_local(&mut self, var: &str) -> u8 {
+    fn resolve_local(&mut self, var: &str) -> Result<u16, CompileError> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name == var {
-                return i as u8
+                return Ok(i as u16)
             }
         }
 
-        panic!("TODO: unresolved var: {} in {:#?}", var, self.locals)
+        Err(CompileError::UnresolvedLocal { name: var.into(), line: self.line })
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
+    fn add_upvalue(&mut self, name: &str, index: u16, is_local: bool) -> Result<u16, CompileError> {
         for (i, upval) in self.upvalues.iter().enumerate() {
             if upval.index == index && upval.is_local == is_local {
-                return i as u8
+                return Ok(i as u16)
             }
         }
 
-        if self.upvalues.len() == std::u8::MAX as usize {
-            panic!("too many upvalues, not cool")
+        if self.upvalues.len() == u16::MAX as usize {
+            Err(CompileError::TooManyUpValues { name: name.into(), line: self.line })
         } else {
             self.upvalues.push(
                 UpValue {
@@ -106,10 +117,11 @@ impl CompileState {
                 }
             );
 
-            (self.upvalues.len() - 1) as u8
+            Ok((self.upvalues.len() - 1) as u16)
         }
     }
 
+    #[allow(dead_code)]
     fn begin_scope(&mut self) {
         self.scope_depth += 1;
     }
@@ -138,6 +150,14 @@ impl CompileState {
         ops.into_iter().rev().for_each(|op| self.emit(op))
     }
 
+    // Forgets the most recent local, returning the op that drops it from the stack.
+    fn pop_local(&mut self) -> Op {
+        match self.locals.pop() {
+            Some(ref local) if local.captured => Op::CloseUpValue,
+            _ => Op::Pop,
+        }
+    }
+
     fn emit(&mut self, op: Op) {
         self.function.chunk_mut().write(op, self.line);
     }
@@ -159,6 +179,7 @@ pub struct Compiler<'g> {
     heap: &'g mut Heap<Object>,
     pub states: Vec<CompileState>,
     pub locals_cache: Vec<Local>,
+    errors: Vec<CompileError>,
 }
 
 impl<'g> Compiler<'g> {
@@ -167,185 +188,391 @@ impl<'g> Compiler<'g> {
             heap,
             states: Vec::new(),
             locals_cache: Vec::new(),
+            errors: Vec::new(),
         }
     }
 
-    pub fn compile(&mut self, exprs: &[ExprNode]) -> Function {
+    pub fn compile(&mut self, exprs: &[ExprNode]) -> Result<Function, Vec<CompileError>> {
+        self.compile_script(exprs, None)
+    }
+
+    pub fn compile_from(&mut self, exprs: &[ExprNode], locals: Vec<Local>) -> Result<Function, Vec<CompileError>> {
+        self.compile_script(exprs, Some(locals))
+    }
+
+    fn compile_script(&mut self, exprs: &[ExprNode], locals: Option<Vec<Local>>) -> Result<Function, Vec<CompileError>> {
+        let function = match self.script_body(exprs, &locals, false) {
+            Some(function) => function,
+            None => self.script_body(exprs, &locals, true).expect("long jumps reach anywhere"),
+        };
+
+        if self.errors.is_empty() {
+            Ok(function)
+        } else {
+            Err(self.errors.drain(..).collect())
+        }
+    }
+
+    fn script_body(&mut self, exprs: &[ExprNode], locals: &Option<Vec<Local>>, long_jumps: bool) -> Option<Function> {
+        let attempt = self.start_attempt();
+
         self.start_function(false, "<zub>", 0, 0);
+        self.state_mut().long_jumps = long_jumps;
 
-        for expr in exprs.iter() {
-            self.compile_expr(expr)
+        if let Some(locals) = locals {
+            self.state_mut().locals = locals.clone()
         }
 
-        self.emit_return(None);
-        self.end_function()
+        self.compile_body(exprs);
+
+        if let Err(err) = self.emit_return(None) {
+            self.errors.push(err)
+        }
+
+        self.end_attempt(attempt)
     }
 
-    pub fn compile_from(&mut self, exprs: &[ExprNode], locals: Vec<Local>) -> Function {
-        self.start_function(false, "<zub>", 0, 0);
-        self.states.last_mut().unwrap().locals = locals;
+    // Where a jump has to go is only known once the code in between is compiled, so functions start
+    // out with short jumps. If one turns out to be too short, the function is compiled again with long ones.
+    fn start_attempt(&self) -> (usize, usize) {
+        (self.errors.len(), self.locals_cache.len())
+    }
 
-        for expr in exprs.iter() {
-            self.compile_expr(expr)
+    fn end_attempt(&mut self, (errors, locals): (usize, usize)) -> Option<Function> {
+        if self.state_mut().overflowed {
+            self.states.pop();
+            self.errors.truncate(errors);
+            self.locals_cache.truncate(locals);
+
+            None
+        } else {
+            Some(self.end_function())
         }
+    }
 
-        self.emit_return(None);
-        self.end_function()
+    // Compiles a list of statements, collecting errors instead of stopping at the first one.
+    fn compile_body(&mut self, exprs: &[ExprNode]) {
+        for expr in exprs.iter() {
+            if let Err(err) = self.compile_expr(expr) {
+                self.errors.push(err)
+            }
+        }
     }
 
-    fn compile_expr(&mut self, expr: &ExprNode) {
+    fn compile_expr(&mut self, expr: &ExprNode) -> Result<(), CompileError> {
         use self::Expr::*;
 
+        if let Some(line) = expr.line() {
+            self.state_mut().line = line
+        }
+
         match expr.inner() {
-            Literal(ref lit) => self.emit_constant(lit),
+            Literal(ref lit) => self.emit_constant(lit)?,
             Unary(ref op, ref node) => {
-                self.compile_expr(node);
+                self.compile_expr(node)?;
 
                 use self::UnaryOp::*;
 
                 match op {
                     Neg => self.emit(Op::Neg),
-                    Not => self.emit(Op::Not)
+                    Not => self.emit(Op::Not),
+                    BitNot => self.emit(Op::BitNot),
                 }
             },
 
-            Var(ref var) => self.var_get(var),
+            Var(ref var) => self.var_get(var)?,
             Mutate(ref lhs, ref rhs) => {
                 // Currently just handling Var
                 if let Var(ref var) = lhs.inner() {
-                    self.compile_expr(rhs);
+                    self.compile_expr(rhs)?;
 
                     if var.is_upvalue() {
-                        let idx = self.resolve_upvalue(var.name());
+                        let idx = self.resolve_upvalue(var.name())?;
 
-                        self.emit(Op::SetUpValue);
-                        self.emit_byte(idx)
+                        self.emit_indexed(Op::SetUpValue, Op::SetUpValueLong, idx)
+                    } else if var.depth.is_none() { // Global
+                        self.set_global(var.name())?
                     } else {
-                        if var.depth.is_none() { // Global
-                            self.set_global(var.name())
-                        } else {
-                            let idx = self.state_mut().resolve_local(var.name());
+                        let idx = self.state_mut().resolve_local(var.name())?;
 
-                            self.emit(Op::SetLocal);
-                            self.emit_byte(idx)
-                        }
+                        self.emit_indexed(Op::SetLocal, Op::SetLocalLong, idx)
                     }
+                } else if let GetProperty(ref object, ref name) = lhs.inner() {
+                    self.compile_expr(object)?;
+                    self.compile_expr(rhs)?;
+
+                    let idx = self.string_constant(name)?;
+
+                    self.emit_indexed(Op::SetProperty, Op::SetPropertyLong, idx)
                 } else {
-                    // When classes are a thing, this is where we handle setting properties
-                    panic!("can't mutate non-variable")
+                    return Err(CompileError::InvalidAssignment { line: self.line() })
                 }
             },
 
-            Return(val) => self.emit_return((*val).clone()),
+            Return(val) => self.emit_return((*val).clone())?,
 
             Function(ref ir_func) => {
-                self.var_define(&ir_func.var, None);
-
-                self.function_decl(ir_func);
+                // Locals claim their slot up front so the body can refer to itself,
+                // globals can only be defined once the closure is on the stack.
+                if ir_func.var.depth.is_some() {
+                    self.var_define(&ir_func.var, None)?;
+                    self.function_decl(ir_func)?;
+                } else {
+                    self.function_decl(ir_func)?;
+                    self.var_define(&ir_func.var, None)?;
+                }
             },
 
             AnonFunction(ref ir_func) => {
-                self.function_decl(ir_func);
+                self.function_decl(ir_func)?;
+            }
+
+            Class(ref class) => {
+                let count = class.methods.len();
+
+                if count > u8::MAX as usize {
+                    return Err(CompileError::TooManyMethods { name: class.var.name().into(), line: self.line() })
+                }
+
+                // The superclass lives in a local called `super`, for methods to capture
+                if let Some(ref superclass) = class.superclass {
+                    self.compile_expr(superclass)?;
+                    self.state_mut().add_local("super", 0)?;
+                }
+
+                // Methods go on the stack as closures, to be collected by `Class`
+                for method in class.methods.iter() {
+                    self.function_decl(method)?
+                }
+
+                let idx = self.string_constant(class.var.name())?;
+
+                self.emit_indexed(Op::Class, Op::ClassLong, idx);
+                self.emit_byte(count as u8);
+
+                if class.superclass.is_some() {
+                    self.emit(Op::Inherit)
+                }
+
+                self.var_define(&class.var, None)?;
+
+                // A global class leaves `super` on top of the stack, where it can be dropped.
+                // Local classes sit above it, so it lives on until the end of the function.
+                if class.superclass.is_some() && class.var.depth.is_none() {
+                    let op = self.state_mut().pop_local();
+                    self.emit(op)
+                }
+            }
+
+            Super(ref name) => {
+                self.named_get("self")?;
+                self.named_get("super")?;
+
+                let idx = self.string_constant(name)?;
+
+                self.emit_indexed(Op::GetSuper, Op::GetSuperLong, idx)
+            }
+
+            GetProperty(ref object, ref name) => {
+                self.compile_expr(object)?;
+
+                let idx = self.string_constant(name)?;
+
+                self.emit_indexed(Op::GetProperty, Op::GetPropertyLong, idx)
+            }
+
+            Invoke(ref object, ref name, ref args) => {
+                let arity = args.len();
+
+                if arity > u8::MAX as usize {
+                    return Err(CompileError::TooManyArguments { count: arity, line: self.line() })
+                }
+
+                self.compile_expr(object)?;
+
+                for arg in args.iter() {
+                    self.compile_expr(arg)?
+                }
+
+                let idx = self.string_constant(name)?;
+
+                self.emit_invoke(Op::Invoke, Op::InvokeLong, arity as u8, idx)
             }
 
             Not(ref expr) => {
-                self.compile_expr(expr);
+                self.compile_expr(expr)?;
                 self.emit(Op::Not)
             }
 
             Neg(ref expr) => {
-                self.compile_expr(expr);
+                self.compile_expr(expr)?;
                 self.emit(Op::Neg)
             }
 
+            Call(ref call) if matches!(call.callee.inner(), Super(_)) => {
+                let arity = call.args.len();
+
+                if arity > u8::MAX as usize {
+                    return Err(CompileError::TooManyArguments { count: arity, line: self.line() })
+                }
+
+                let name = match call.callee.inner() {
+                    Super(ref name) => name,
+                    _ => unreachable!(),
+                };
+
+                self.named_get("self")?;
+
+                for arg in call.args.iter() {
+                    self.compile_expr(arg)?
+                }
+
+                self.named_get("super")?;
+
+                let idx = self.string_constant(name)?;
+
+                self.emit_invoke(Op::SuperInvoke, Op::SuperInvokeLong, arity as u8, idx)
+            },
+
             Call(ref call) => {
                 let arity = call.args.len();
 
-                if arity > 8 {
-                    panic!("That's a lot of arguments. But I will fix this limitation asap.")
+                if arity > u8::MAX as usize {
+                    return Err(CompileError::TooManyArguments { count: arity, line: self.line() })
                 }
 
-                self.compile_expr(&call.callee);
+                self.compile_expr(&call.callee)?;
 
                 for arg in call.args.iter() {
-                    self.compile_expr(arg)
+                    self.compile_expr(arg)?
                 }
 
-                self.emit(Op::Call(arity as u8))
+                // Up to 8 arguments get an op of their own
+                if arity <= 8 {
+                    self.emit(Op::Call(arity as u8))
+                } else {
+                    self.emit(Op::CallN);
+                    self.emit_byte(arity as u8)
+                }
             },
 
             List(ref content) => {
+                let count = self.element_count(content.len())?;
+
                 for el in content.iter().rev() {
-                    self.compile_expr(el)
+                    self.compile_expr(el)?
                 }
 
-                self.emit(Op::List);
-                self.emit_byte(content.len() as u8)
+                self.emit_indexed(Op::List, Op::ListLong, count)
             },
 
             SetElement(ref list, ref index, ref value) => {
-                self.compile_expr(value);
-                self.compile_expr(index);
-                self.compile_expr(list);
+                self.compile_expr(value)?;
+                self.compile_expr(index)?;
+                self.compile_expr(list)?;
 
                 self.emit(Op::SetElement);
             },
 
             Dict(keys, values) => {
+                let count = self.element_count(keys.len())?;
+
                 for (key, val) in keys.iter().zip(values.iter()) {
-                    self.compile_expr(key);
-                    self.compile_expr(val);
+                    self.compile_expr(key)?;
+                    self.compile_expr(val)?;
                 }
 
-                self.emit(Op::Dict);
-                self.emit_byte(keys.len() as u8);
+                self.emit_indexed(Op::Dict, Op::DictLong, count)
             },
 
             If(ref cond, ref then, ref els) => {
-                self.compile_expr(cond);
+                self.compile_expr(cond)?;
 
                 let else_jmp = self.emit_jze();
 
                 self.emit(Op::Pop);
-                self.compile_expr(then);
+                self.compile_expr(then)?;
 
                 let end_jmp = self.emit_jmp();
 
-                self.patch_jmp(else_jmp);
+                self.patch_jmp(else_jmp)?;
                 self.emit(Op::Pop);
 
-                if let &Some(ref els) = els {
-                    self.compile_expr(els)
+                if let Some(ref els) = els {
+                    self.compile_expr(els)?
                 }
 
-                self.patch_jmp(end_jmp)
+                self.patch_jmp(end_jmp)?
             },
 
             While(ref cond, ref body) => {
                 let ip = self.ip();
 
-                self.compile_expr(cond);
+                self.compile_expr(cond)?;
 
                 let end_jmp = self.emit_jze();
 
+                let outer_handlers = self.state_mut().loop_handlers;
+                self.state_mut().loop_handlers = self.state_mut().handlers;
+
                 self.emit(Op::Pop);
-                self.compile_expr(body);
+                let body = self.compile_expr(body);
 
-                self.emit_loop(ip);
-                self.patch_jmp(end_jmp);
+                self.state_mut().loop_handlers = outer_handlers;
+                body?;
+
+                self.emit_loop(ip)?;
+                self.patch_jmp(end_jmp)?;
 
                 self.emit(Op::Pop);
 
                 for b in self.state_mut().breaks() {
-                    self.patch_jmp(b)
+                    self.patch_jmp(b)?
                 }
             },
 
             Break => {
+                // Leaving a `try` by breaking out of its loop runs its `finally` and drops its handler too
+                let loop_handlers = self.state_mut().loop_handlers;
+
+                self.emit_finally(loop_handlers, false)?;
+
+                let state = self.state_mut();
+
+                for _ in state.loop_handlers..state.handlers {
+                    self.emit(Op::PopHandler)
+                }
+
                 let jmp = self.emit_jmp();
                 self.state_mut().add_break(jmp)
             },
 
+            Try { ref body, ref catch_binding, ref handler, ref finally } => {
+                let catch = catch_binding.as_ref().zip(handler.as_ref());
+
+                self.try_decl(body, catch, finally.as_ref())?
+            },
+
+            Throw(ref value) => {
+                self.compile_expr(value)?;
+                self.emit(Op::Throw)
+            },
+
+            Coroutine(ref function) => {
+                self.compile_expr(function)?;
+                self.emit(Op::Coroutine)
+            },
+
+            Yield(ref value) => {
+                self.compile_expr(value)?;
+                self.emit(Op::Yield)
+            },
+
+            CoroutineStatus(ref coroutine) => {
+                self.compile_expr(coroutine)?;
+                self.emit(Op::CoroutineStatus)
+            },
+
             Pop => {
                 self.emit(Op::Pop)
             }
@@ -355,49 +582,57 @@ impl<'g> Compiler<'g> {
 
                 match op {
                     And => {
-                        self.compile_expr(lhs);
+                        self.compile_expr(lhs)?;
 
                         let short_circuit_jmp = self.emit_jze();
 
                         self.emit(Op::Pop);
-                        self.compile_expr(rhs);
+                        self.compile_expr(rhs)?;
 
-                        self.patch_jmp(short_circuit_jmp);
+                        self.patch_jmp(short_circuit_jmp)?;
                     },
 
                     Or => {
-                        self.compile_expr(lhs);
+                        self.compile_expr(lhs)?;
 
                         let else_jmp = self.emit_jze();
                         let end_jmp = self.emit_jmp();
 
-                        self.patch_jmp(else_jmp);
+                        self.patch_jmp(else_jmp)?;
                         self.emit(Op::Pop);
 
-                        self.compile_expr(rhs);
+                        self.compile_expr(rhs)?;
 
-                        self.patch_jmp(end_jmp)
+                        self.patch_jmp(end_jmp)?
                     },
 
                     Index => {
-                        self.compile_expr(rhs);
-                        self.compile_expr(lhs);
-        
+                        self.compile_expr(rhs)?;
+                        self.compile_expr(lhs)?;
+
                         self.emit(Op::Index);
                     }
 
                     _ => {
                         // This looks kinda funny, but it's an ok way of matching I guess
 
-                        self.compile_expr(lhs); // will handle type in the future :)
-                        self.compile_expr(rhs);
+                        self.compile_expr(lhs)?; // will handle type in the future :)
+                        self.compile_expr(rhs)?;
 
                         match op {
                             Add => self.emit(Op::Add),
                             Sub => self.emit(Op::Sub),
                             Rem => self.emit(Op::Rem),
                             Mul => self.emit(Op::Mul),
+                            Div if expr.type_info().is_int() || (lhs.type_info().is_int() && rhs.type_info().is_int()) => {
+                                self.emit(Op::IntDiv)
+                            },
                             Div => self.emit(Op::Div),
+                            BitAnd => self.emit(Op::BitAnd),
+                            BitOr => self.emit(Op::BitOr),
+                            BitXor => self.emit(Op::BitXor),
+                            Shl => self.emit(Op::Shl),
+                            Shr => self.emit(Op::Shr),
 
                             Equal => self.emit(Op::Equal),
                             Gt => self.emit(Op::Greater),
@@ -426,108 +661,214 @@ impl<'g> Compiler<'g> {
             },
 
             Bind(ref var, ref init) => {
-                self.compile_expr(init);
-                self.var_define(var, None);
+                self.compile_expr(init)?;
+                self.var_define(var, None)?;
             },
 
             BindGlobal(ref var, ref init) => {
-                self.compile_expr(init);
-                self.var_define(var, None)
+                self.compile_expr(init)?;
+                self.var_define(var, None)?
             },
 
             Block(ref body) => for node in body {
-                self.compile_expr(node)
+                self.compile_expr(node)?
             },
 
-            _ => todo!()
+            Data(_) => return Err(CompileError::Unsupported { what: "data expressions", line: self.line() }),
         }
-    }
-
 
+        Ok(())
+    }
 
-    fn var_get(&mut self, var: &Binding) {
+    fn var_get(&mut self, var: &Binding) -> Result<(), CompileError> {
         if var.is_upvalue() {
-            let idx = self.resolve_upvalue(var.name());
+            let idx = self.resolve_upvalue(var.name())?;
 
-            self.emit(Op::GetUpValue);
-            self.emit_byte(idx);
+            self.emit_indexed(Op::GetUpValue, Op::GetUpValueLong, idx)
         } else {
             // local time B)
             if var.depth.is_none() {
-                self.emit(Op::GetGlobal);
-                let idx = self.string_constant(var.name());
-                self.emit_byte(idx)
+                let idx = self.string_constant(var.name())?;
+
+                self.emit_indexed(Op::GetGlobal, Op::GetGlobalLong, idx)
             } else {
-                let idx = self.state_mut().resolve_local(var.name());
+                let idx = self.state_mut().resolve_local(var.name())?;
 
-                self.emit(Op::GetLocal);
-                self.emit_byte(idx)
+                self.emit_indexed(Op::GetLocal, Op::GetLocalLong, idx)
             }
         }
+
+        Ok(())
     }
 
-    fn var_define(&mut self, var: &Binding, constant: Option<u8>) {
-        // If there's depth, it's a local
-        if let Some(depth) = var.depth {
-            self.state_mut().add_local(var.name(), depth);
-            self.state_mut().resolve_local(var.name());
+    fn try_decl(&mut self, body: &ExprNode, catch: Option<(&Binding, &ExprNode)>, finally: Option<&ExprNode>) -> Result<(), CompileError> {
+        // Anything escaping the body or the handler runs `finally`, and is then thrown again
+        let rethrow_jmp = finally.map(|finally| {
+            let outer = self.state_mut().handlers;
+            self.state_mut().finally.push((outer, finally.clone()));
+
+            self.emit_push_handler()
+        });
+
+        if let Some((binding, handler)) = catch {
+            let catch_jmp = self.emit_push_handler();
+
+            self.compile_scoped(body)?;
+            self.emit_pop_handler();
+
+            let end_jmp = self.emit_jmp();
+
+            // The VM drops the handler and leaves the thrown value on the stack
+            self.patch_jmp(catch_jmp)?;
+
+            self.state_mut().add_local(binding.name(), 0)?;
+            self.compile_scoped(handler)?;
+
+            let op = self.state_mut().pop_local();
+            self.emit(op);
+
+            self.patch_jmp(end_jmp)?
         } else {
-            self.emit(Op::DefineGlobal);
+            self.compile_scoped(body)?
+        }
 
-            let idx = constant.unwrap_or_else(|| {
-                self.string_constant(var.name())
-            });
+        if let (Some(finally), Some(rethrow_jmp)) = (finally, rethrow_jmp) {
+            self.state_mut().finally.pop();
+            self.emit_pop_handler();
+            self.compile_scoped(finally)?;
 
-            self.emit_byte(idx)
+            let end_jmp = self.emit_jmp();
+
+            self.patch_jmp(rethrow_jmp)?;
+
+            // The pending value sits below anything `finally` declares
+            self.state_mut().add_local("", 0)?;
+            self.compile_scoped(finally)?;
+            self.state_mut().locals.pop();
+
+            self.emit(Op::Throw);
+            self.patch_jmp(end_jmp)?
         }
+
+        Ok(())
     }
 
-    fn set_global(&mut self, name: &str) {
-        self.emit(Op::SetGlobal);
+    // Runs the `finally` blocks of every try being left for one outside `handlers`, innermost first,
+    // dropping their handlers on the way. A `pending` value, such as what's being returned, stays on
+    // top of the stack throughout. The code after this is still inside the trys.
+    fn emit_finally(&mut self, handlers: usize, pending: bool) -> Result<(), CompileError> {
+        let state = self.state_mut();
+        let (finally, outer_handlers) = (state.finally.clone(), state.handlers);
+
+        while let Some((outer, body)) = self.state_mut().finally.last().cloned() {
+            if outer < handlers {
+                break
+            }
 
-        let idx = {
-            let chunk = self.states.last_mut()
-                .unwrap()
-                .function
-                .chunk_mut();
+            self.state_mut().finally.pop();
 
-            chunk.string_constant(self.heap, name)
-        };
+            while self.state_mut().handlers > outer {
+                self.emit_pop_handler()
+            }
+
+            if pending {
+                self.state_mut().add_local("", 0)?;
+            }
 
-        self.emit_byte(idx)
+            self.compile_scoped(&body)?;
+
+            if pending {
+                self.state_mut().locals.pop();
+            }
+        }
+
+        let state = self.state_mut();
+        state.finally = finally;
+        state.handlers = outer_handlers;
+
+        Ok(())
     }
 
-    fn function_decl(&mut self, f: &IrFunction) {
-        let name = f.var.name();
-        let decl = f.body.borrow();
+    // Compiles `expr`, dropping any locals it declared once it's done.
+    fn compile_scoped(&mut self, expr: &ExprNode) -> Result<(), CompileError> {
+        let locals = self.state_mut().locals.len();
 
-        let params = &decl.params;
-        let body = &decl.inner;
-        let arity = params.len() as u8;
+        self.compile_expr(expr)?;
 
-        self.start_function(decl.method, name, arity, 1);
+        while self.state_mut().locals.len() > locals {
+            let op = self.state_mut().pop_local();
+            self.emit(op)
+        }
+
+        Ok(())
+    }
 
-        for p in params {
-            self.state_mut().add_local(p.name(), 0);
-            self.state_mut().resolve_local(p.name());
+    // Reads a variable the compiler introduced itself, like `self` or `super`, wherever it lives.
+    fn named_get(&mut self, name: &str) -> Result<(), CompileError> {
+        if let Ok(idx) = self.state_mut().resolve_local(name) {
+            self.emit_indexed(Op::GetLocal, Op::GetLocalLong, idx)
+        } else {
+            let idx = self.resolve_upvalue(name)?;
+
+            self.emit_indexed(Op::GetUpValue, Op::GetUpValueLong, idx)
         }
 
-        for expr in body.iter() {
-            self.compile_expr(expr)
+        Ok(())
+    }
+
+    fn var_define(&mut self, var: &Binding, constant: Option<u16>) -> Result<(), CompileError> {
+        // If there's depth, it's a local
+        if let Some(depth) = var.depth {
+            self.state_mut().add_local(var.name(), depth)?;
+        } else {
+            let idx = match constant {
+                Some(idx) => idx,
+                None => self.string_constant(var.name())?,
+            };
+
+            self.emit_indexed(Op::DefineGlobal, Op::DefineGlobalLong, idx)
         }
 
-        self.state_mut().end_scope();
+        Ok(())
+    }
 
-        let upvalues = self.state_mut().upvalues.clone();
+    fn set_global(&mut self, name: &str) -> Result<(), CompileError> {
+        let idx = self.string_constant(name)?;
 
-        let function = self.end_function(); // Might delete later, felt cute
-        let handle = self.heap.insert(Object::Function(function)).into_handle();
+        self.emit_indexed(Op::SetGlobal, Op::SetGlobalLong, idx);
 
-        let value = Value::object(handle);
-        let idx = self.chunk_mut().add_constant(value);
+        Ok(())
+    }
+
+    fn function_decl(&mut self, f: &IrFunction) -> Result<(), CompileError> {
+        let name = f.var.name();
+        let decl = f.body.borrow();
+
+        if decl.params.len() > u8::MAX as usize {
+            return Err(CompileError::TooManyParameters { name: name.into(), count: decl.params.len(), line: self.line() })
+        }
+
+        let (function, upvalues) = match self.function_body(name, &decl, false) {
+            Some(compiled) => compiled,
+            None => self.function_body(name, &decl, true).expect("long jumps reach anywhere"),
+        };
 
-        self.emit(Op::Closure);
-        self.emit_byte(idx);
+        let rooted = self.heap.insert(Object::Function(function));
+
+        let value = Value::object(rooted.handle());
+        let idx = self.add_constant(value)?;
+        self.chunk_mut().pin(rooted);
+
+        // The long form widens the upvalue indexes as well as the constant
+        let wide = idx > u8::MAX as u16 || upvalues.iter().any(|u| u.index > u8::MAX as u16);
+
+        if wide {
+            self.emit(Op::ClosureLong);
+            self.chunk_mut().write_u16(idx)
+        } else {
+            self.emit(Op::Closure);
+            self.emit_byte(idx as u8)
+        }
 
         for upvalue in upvalues {
             self.emit_byte(
@@ -538,14 +879,49 @@ impl<'g> Compiler<'g> {
                 }
             );
 
-            self.emit_byte(upvalue.index)
+            if wide {
+                self.chunk_mut().write_u16(upvalue.index)
+            } else {
+                self.emit_byte(upvalue.index as u8)
+            }
         }
+
+        Ok(())
+    }
+
+    fn function_body(&mut self, name: &str, decl: &IrFunctionBody, long_jumps: bool) -> Option<(Function, Vec<UpValue>)> {
+        let attempt = self.start_attempt();
+
+        self.start_function(decl.method, name, decl.params.len() as u8, 1);
+        self.state_mut().long_jumps = long_jumps;
+
+        for p in decl.params.iter() {
+            if let Err(err) = self.state_mut().add_local(p.name(), 0) {
+                self.errors.push(err)
+            }
+        }
+
+        self.compile_body(&decl.inner);
+
+        self.state_mut().end_scope();
+
+        // Falling off the end of a function returns nil
+        if let Err(err) = self.emit_return(None) {
+            self.errors.push(err)
+        }
+
+        let upvalues = self.state_mut().upvalues.clone();
+
+        self.end_attempt(attempt).map(|function| (function, upvalues))
     }
 
     fn start_function(&mut self, method: bool, name: &str, arity: u8, scope: usize) {
         let next_function = FunctionBuilder::new(name, arity);
         let reserved_var = if method { "self" } else { "" };
-        let state = CompileState::new(method, reserved_var, next_function, scope);
+        let mut state = CompileState::new(method, reserved_var, next_function, scope);
+
+        // A function starts out on the line it is declared on
+        state.line = self.states.last().map(|s| s.line).unwrap_or(0);
 
         self.states.push(state)
     }
@@ -558,38 +934,41 @@ impl<'g> Compiler<'g> {
         self.locals_cache.extend(state.locals.clone());
 
         state.function.set_upvalue_count(state.upvalues.len());
-        state.function.build()
+
+        peephole::optimize(state.function.build(), self.heap)
     }
 
-    fn resolve_upvalue(&mut self, name: &str) -> u8 {
+    fn resolve_upvalue(&mut self, name: &str) -> Result<u16, CompileError> {
         let end = self.states.len() - 1;
 
-        let (scope, mut index) =
+        let found =
             self.states[..end].iter_mut()
                 .enumerate()
                 .rev()
-                .filter_map(|(i, enclosing)| {
+                .find_map(|(i, enclosing)| {
                     enclosing.capture_local(name).map(|local| (i, local))
-                })
-                .next()
-                .expect(&format!("upvalue marked during resolution, but wasn't found: {}", name));
+                });
 
+        let (scope, mut index) = match found {
+            Some(found) => found,
+            None => return Err(CompileError::UnresolvedLocal { name: name.into(), line: self.line() }),
+        };
 
-        index = self.states[scope + 1].add_upvalue(index, true);
+        index = self.states[scope + 1].add_upvalue(name, index, true)?;
 
         if scope >= self.states.len() - 2 {
             // if we're one scope from current function
-            index
+            Ok(index)
         } else {
             for enclosing in &mut self.states[scope + 2..] {
-                index = enclosing.add_upvalue(index, false)
+                index = enclosing.add_upvalue(name, index, false)?
             }
 
-            index
+            Ok(index)
         }
     }
 
-    fn emit_return(&mut self, ret: Option<ExprNode>) {
+    fn emit_return(&mut self, ret: Option<ExprNode>) -> Result<(), CompileError> {
         let state = self.state_mut();
         let initializer = state.function.name() == "init" && state.method;
 
@@ -597,12 +976,15 @@ impl<'g> Compiler<'g> {
             self.emit(Op::GetLocal);
             self.emit_byte(0)
         } else if let Some(ref expr) = ret {
-            self.compile_expr(expr)
+            self.compile_expr(expr)?
         } else {
             self.emit(Op::Nil)
         }
 
-        self.emit(Op::Return)
+        self.emit_finally(0, true)?;
+        self.emit(Op::Return);
+
+        Ok(())
     }
 
     fn state_mut(&mut self) -> &mut CompileState {
@@ -623,16 +1005,26 @@ impl<'g> Compiler<'g> {
             .chunk
     }
 
-    fn line(&mut self) -> usize {
-        self.states.last_mut()
+    fn line(&self) -> usize {
+        self.states.last()
             .expect("states to be non-empty")
             .line
     }
 
-    fn string_constant(&mut self, s: &str) -> u8 {
+    fn string_constant(&mut self, s: &str) -> Result<u16, CompileError> {
+        let line = self.line();
         let chunk = self.states.last_mut().unwrap().function.chunk_mut();
 
         chunk.string_constant(self.heap, s)
+            .ok_or(CompileError::TooManyConstants { line })
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<u16, CompileError> {
+        let line = self.line();
+
+        self.chunk_mut()
+            .add_constant(value)
+            .ok_or(CompileError::TooManyConstants { line })
     }
 
     fn emit(&mut self, op: Op) {
@@ -644,79 +1036,151 @@ impl<'g> Compiler<'g> {
         self.chunk_mut().write_byte(byte);
     }
 
-    fn emit_constant(&mut self, lit: &Literal) {
+    // Indexes that don't fit a byte need the long form of the op, which takes a u16.
+    fn element_count(&self, count: usize) -> Result<u16, CompileError> {
+        if count > u16::MAX as usize {
+            return Err(CompileError::TooManyElements { count, line: self.line() })
+        }
+
+        Ok(count as u16)
+    }
+
+    fn emit_indexed(&mut self, short: Op, long: Op, idx: u16) {
+        if idx <= u8::MAX as u16 {
+            self.emit(short);
+            self.emit_byte(idx as u8)
+        } else {
+            self.emit(long);
+            self.chunk_mut().write_u16(idx)
+        }
+    }
+
+    fn emit_invoke(&mut self, short: Op, long: Op, arity: u8, idx: u16) {
+        if idx <= u8::MAX as u16 {
+            self.emit(short);
+            self.emit_byte(arity);
+            self.emit_byte(idx as u8)
+        } else {
+            self.emit(long);
+            self.emit_byte(arity);
+            self.chunk_mut().write_u16(idx)
+        }
+    }
+
+    fn emit_constant(&mut self, lit: &Literal) -> Result<(), CompileError> {
         use self::Literal::*;
 
         match *lit {
             Nil     => self.emit(Op::Nil),
             Boolean(b) => self.emit(if b { Op::True} else { Op::False } ),
-            Number(n) => self.emit_number_literal(n),
+            Number(n) => self.emit_immediate(Value::float(n)),
+            Int(n) => self.emit_immediate(Value::int(n)),
             String(ref s) => {
-                let idx = {
-                    let chunk = self.states.last_mut().unwrap().function.chunk_mut();
-                    chunk.string_constant(self.heap, s)
-                };
+                let idx = self.string_constant(s)?;
 
-                self.emit(Op::Constant(idx))
+                if idx <= u8::MAX as u16 {
+                    self.emit(Op::Constant(idx as u8))
+                } else {
+                    self.emit(Op::ConstantLong(idx))
+                }
             },
-
-            _ => panic!("not a constant")
         }
+
+        Ok(())
     }
 
-    fn emit_number_literal(&mut self, n: f64) {
+    fn emit_immediate(&mut self, value: Value) {
         self.emit(Op::Immediate);
 
-        let value = Value::float(n).to_raw();
+        let value = value.to_raw();
         let chunk = self.chunk_mut();
 
         chunk.write_u64(value)
     }
 
     fn emit_jze(&mut self) -> usize {
-        let line = self.line();
-        let chunk = self.chunk_mut();
+        self.emit_forward(Op::JumpIfFalse, Op::JumpIfFalseLong)
+    }
 
-        chunk.write(Op::JumpIfFalse, line);
-        chunk.write_byte(0xff);
-        chunk.write_byte(0xff);
+    fn emit_jmp(&mut self) -> usize {
+        self.emit_forward(Op::Jump, Op::JumpLong)
+    }
 
-        chunk.len() - 2
+    fn emit_push_handler(&mut self) -> usize {
+        self.state_mut().handlers += 1;
+        self.emit_forward(Op::PushHandler, Op::PushHandlerLong)
     }
 
-    fn emit_jmp(&mut self) -> usize {
+    // Emits a jump to be patched once its target is known, returning where its operand goes.
+    fn emit_forward(&mut self, short: Op, long: Op) -> usize {
         let line = self.line();
+        let long_jumps = self.state_mut().long_jumps;
         let chunk = self.chunk_mut();
 
-        chunk.write(Op::Jump, line);
-        chunk.write_byte(0xff);
-        chunk.write_byte(0xff);
-        chunk.len() - 2
+        if long_jumps {
+            chunk.write(long, line);
+            chunk.write_u32(u32::MAX);
+            chunk.len() - 4
+        } else {
+            chunk.write(short, line);
+            chunk.write_u16(u16::MAX);
+            chunk.len() - 2
+        }
     }
 
-    fn emit_loop(&mut self, ip: usize) {
+    fn emit_pop_handler(&mut self) {
+        self.state_mut().handlers -= 1;
+        self.emit(Op::PopHandler)
+    }
+
+    // Loops know how far back they go, so they're only long when they have to be.
+    fn emit_loop(&mut self, ip: usize) -> Result<(), CompileError> {
         let line = self.line();
         let chunk = self.chunk_mut();
         let sub = chunk.len() - ip + 3;
 
-        let lo = (sub & 0xff) as u8;
-        let hi = ((sub >> 8) & 0xff) as u8;
+        if sub <= u16::MAX as usize {
+            chunk.write(Op::Loop, line);
+            chunk.write_u16(sub as u16);
+
+            return Ok(())
+        }
+
+        let sub = sub + 2;
+
+        if sub > u32::MAX as usize {
+            return Err(CompileError::JumpTooFar { line })
+        }
+
+        chunk.write(Op::LoopLong, line);
+        chunk.write_u32(sub as u32);
 
-        chunk.write(Op::Loop, line);
-        chunk.write_byte(lo);
-        chunk.write_byte(hi);
+        Ok(())
     }
 
     fn ip(&self) -> usize {
         self.chunk().len()
     }
 
-    fn patch_jmp(&mut self, idx: usize) {
+    fn patch_jmp(&mut self, idx: usize) -> Result<(), CompileError> {
         let jmp = self.ip();
-        let lo = (jmp & 0xff) as u8;
-        let hi = ((jmp >> 8) & 0xff) as u8;
 
-        self.chunk_mut().write_byte_at(idx, lo);
-        self.chunk_mut().write_byte_at(idx + 1, hi);
+        if self.state_mut().long_jumps {
+            if jmp > u32::MAX as usize {
+                return Err(CompileError::JumpTooFar { line: self.line() })
+            }
+
+            for (i, byte) in (jmp as u32).to_le_bytes().iter().enumerate() {
+                self.chunk_mut().write_byte_at(idx + i, *byte)
+            }
+        } else if jmp > u16::MAX as usize {
+            self.state_mut().overflowed = true
+        } else {
+            for (i, byte) in (jmp as u16).to_le_bytes().iter().enumerate() {
+                self.chunk_mut().write_byte_at(idx + i, *byte)
+            }
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,74 @@
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    UnresolvedLocal { name: String, line: usize },
+    TooManyArguments { count: usize, line: usize },
+    TooManyParameters { name: String, count: usize, line: usize },
+    TooManyLocals { name: String, line: usize },
+    TooManyUpValues { name: String, line: usize },
+    TooManyConstants { line: usize },
+    TooManyMethods { name: String, line: usize },
+    TooManyElements { count: usize, line: usize },
+    InvalidAssignment { line: usize },
+    JumpTooFar { line: usize },
+    Unsupported { what: &'static str, line: usize },
+}
+
+impl CompileError {
+    pub fn line(&self) -> usize {
+        use self::CompileError::*;
+
+        match *self {
+            UnresolvedLocal { line, .. }
+            | TooManyArguments { line, .. }
+            | TooManyParameters { line, .. }
+            | TooManyLocals { line, .. }
+            | TooManyUpValues { line, .. }
+            | TooManyConstants { line }
+            | TooManyMethods { line, .. }
+            | TooManyElements { line, .. }
+            | InvalidAssignment { line }
+            | JumpTooFar { line }
+            | Unsupported { line, .. } => line,
+        }
+    }
+
+    // The binding the error is about, if any
+    pub fn name(&self) -> Option<&str> {
+        use self::CompileError::*;
+
+        match self {
+            UnresolvedLocal { name, .. }
+            | TooManyParameters { name, .. }
+            | TooManyLocals { name, .. }
+            | TooManyUpValues { name, .. }
+            | TooManyMethods { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CompileError::*;
+
+        write!(f, "[line {}] ", self.line())?;
+
+        match self {
+            UnresolvedLocal { name, .. } => write!(f, "unresolved variable `{}`", name),
+            TooManyArguments { count, .. } => write!(f, "too many arguments in call: {}", count),
+            TooManyParameters { name, count, .. } => write!(f, "too many parameters in function `{}`: {}", name, count),
+            TooManyLocals { name, .. } => write!(f, "too many local variables, when declaring `{}`", name),
+            TooManyUpValues { name, .. } => write!(f, "too many captured variables, when capturing `{}`", name),
+            TooManyConstants { .. } => write!(f, "too many constants in one function"),
+            TooManyMethods { name, .. } => write!(f, "too many methods in class `{}`", name),
+            TooManyElements { count, .. } => write!(f, "too many elements in literal: {}", count),
+            InvalidAssignment { .. } => write!(f, "can only assign to variables"),
+            JumpTooFar { .. } => write!(f, "function is too large to jump across"),
+            Unsupported { what, .. } => write!(f, "{} is not supported yet", what),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -1,6 +1,10 @@
+#[allow(clippy::module_inception)]
 pub mod compiler;
+pub mod error;
+pub mod peephole;
 
 use super::vm::*;
 use super::ir::*;
 
-pub use self::compiler::*;
\ No newline at end of file
+pub use self::compiler::*;
+pub use self::error::*;
\ No newline at end of file
@@ -0,0 +1,317 @@
+use super::*;
+use crate::vm::verifier::Span;
+
+use std::collections::{ HashMap, HashSet };
+use std::convert::TryFrom;
+
+// Opcodes the pass looks for, as `Op::write` encodes them
+const NOT: u8 = 0x07;
+const ADD: u8 = 0x03;
+const EQUAL: u8 = 0x09;
+const GREATER: u8 = 0x0a;
+const LESS: u8 = 0x0b;
+const JUMP: u8 = 0x0c;
+const JUMP_IF_FALSE: u8 = 0x0d;
+const POP: u8 = 0x0e;
+const GET_LOCAL: u8 = 0x11;
+const IMMEDIATE: u8 = 0x13;
+const NIL: u8 = 0x14;
+const TRUE: u8 = 0x15;
+const FALSE: u8 = 0x16;
+const LOOP: u8 = 0x20;
+const GET_UPVALUE: u8 = 0x22;
+const PUSH_HANDLER: u8 = 0x33;
+const CONSTANT: u8 = 0x01;
+const CONSTANT_LONG: u8 = 0x39;
+const GET_LOCAL_LONG: u8 = 0x3d;
+const GET_UPVALUE_LONG: u8 = 0x3f;
+const JUMP_LONG: u8 = 0x49;
+const JUMP_IF_FALSE_LONG: u8 = 0x4a;
+const LOOP_LONG: u8 = 0x4b;
+const PUSH_HANDLER_LONG: u8 = 0x4c;
+const GREATER_EQUAL: u8 = 0x4d;
+const LESS_EQUAL: u8 = 0x4e;
+const NOT_EQUAL: u8 = 0x4f;
+const JUMP_IF_FALSE_POP: u8 = 0x50;
+const ADD_CONST: u8 = 0x51;
+const GET_LOCAL_0: u8 = 0x52;
+const GET_LOCAL_3: u8 = 0x55;
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    bytes: Vec<u8>, // jump operands are rewritten from `target` on the way out
+    line: usize,
+    target: Option<usize>, // index of the instruction jumped to
+    live: bool,
+}
+
+// Cleans up the bytecode of a freshly compiled function: fuses the pairs the compiler emits
+// for `>=`, `<=`, `!=`, conditions and `+ <number>`, drops values pushed only to be popped,
+// and sends jumps landing on jumps straight to where they end up. Instructions are only ever
+// fused or dropped, never moved past each other, and nothing is fused across a jump target.
+//
+// Jumps keep their width, code only shrinks. If anything looks off, the function is returned as it was.
+pub fn optimize(function: Function, heap: &Heap<Object>) -> Function {
+    let instructions = match Verifier::new(&function, heap).spans().and_then(|spans| decode(&function, &spans)) {
+        Some(instructions) => instructions,
+        None => return function,
+    };
+
+    let mut pass = Peephole { instructions, targeted: HashSet::new() };
+
+    while pass.round() {}
+
+    let chunk = match pass.encode(function.chunk()) {
+        Some(chunk) => chunk,
+        None => return function,
+    };
+
+    let mut builder = FunctionBuilder::new(function.name(), function.arity());
+
+    builder.chunk = chunk;
+    builder.set_upvalue_count(function.upvalue_count());
+    builder.build()
+}
+
+fn decode(function: &Function, spans: &[Span]) -> Option<Vec<Instruction>> {
+    let chunk = function.chunk();
+    let index_of = spans.iter()
+        .enumerate()
+        .map(|(i, span)| (span.start, i))
+        .collect::<HashMap<_, _>>();
+
+    spans.iter().map(|span| {
+        let target = match span.target {
+            Some(target) => Some(*index_of.get(&target)?),
+            None => None,
+        };
+
+        Some(Instruction {
+            bytes: chunk.as_ref()[span.start..span.end].to_vec(),
+            line: chunk.line(span.start),
+            target,
+            live: true,
+        })
+    }).collect()
+}
+
+struct Peephole {
+    instructions: Vec<Instruction>,
+    targeted: HashSet<usize>, // instructions some jump lands on, which can't be fused into the one before
+}
+
+impl Peephole {
+    // One sweep over the code, returning whether it changed anything.
+    fn round(&mut self) -> bool {
+        self.targeted = self.targets();
+
+        let mut changed = false;
+
+        for i in 0..self.instructions.len() {
+            if !self.instructions[i].live {
+                continue
+            }
+
+            changed |= self.thread(i);
+
+            let next = match self.next(i) {
+                Some(next) if !self.targeted.contains(&next) => next,
+                _ => {
+                    changed |= self.short_local(i);
+                    continue
+                },
+            };
+
+            let fused = match (self.op(i), self.op(next)) {
+                (LESS, NOT) => Some(GREATER_EQUAL),
+                (GREATER, NOT) => Some(LESS_EQUAL),
+                (EQUAL, NOT) => Some(NOT_EQUAL),
+                (IMMEDIATE, ADD) => Some(ADD_CONST),
+                _ => None,
+            };
+
+            if let Some(fused) = fused {
+                self.instructions[i].bytes[0] = fused;
+                self.instructions[next].live = false;
+                changed = true;
+                continue
+            }
+
+            if self.op(next) == POP {
+                changed |= self.fuse_pop(i, next)
+            }
+
+            changed |= self.short_local(i)
+        }
+
+        changed
+    }
+
+    // `JumpIfFalse` keeps the condition around for both sides to pop, which they do first thing.
+    // Pushing something that's popped right away does nothing at all.
+    fn fuse_pop(&mut self, i: usize, pop: usize) -> bool {
+        match self.op(i) {
+            JUMP_IF_FALSE => {
+                let after = self.instructions[i].target
+                    .and_then(|target| self.resolve(target))
+                    .filter(|&target| self.op(target) == POP)
+                    .and_then(|target| self.next(target));
+
+                match after {
+                    Some(after) => {
+                        self.instructions[i].bytes[0] = JUMP_IF_FALSE_POP;
+                        self.instructions[pop].live = false;
+                        self.retarget(i, after);
+                        true
+                    },
+                    None => false,
+                }
+            },
+
+            CONSTANT | CONSTANT_LONG | IMMEDIATE | NIL | TRUE | FALSE
+            | GET_LOCAL | GET_LOCAL_LONG | GET_LOCAL_0..=GET_LOCAL_3
+            | GET_UPVALUE | GET_UPVALUE_LONG => {
+                self.instructions[i].live = false;
+                self.instructions[pop].live = false;
+
+                // Anything jumping to the push now lands after the pop
+                if self.targeted.remove(&i) {
+                    if let Some(after) = self.next(pop) {
+                        self.targeted.insert(after);
+                    }
+                }
+
+                true
+            },
+
+            _ => false,
+        }
+    }
+
+    fn short_local(&mut self, i: usize) -> bool {
+        let inst = &mut self.instructions[i];
+
+        match inst.bytes[..] {
+            [GET_LOCAL, slot] if slot <= GET_LOCAL_3 - GET_LOCAL_0 => {
+                inst.bytes = vec![GET_LOCAL_0 + slot];
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Points a jump landing on an unconditional jump at wherever that one ends up.
+    fn thread(&mut self, i: usize) -> bool {
+        if !matches!(self.op(i), JUMP | JUMP_LONG | JUMP_IF_FALSE | JUMP_IF_FALSE_LONG | JUMP_IF_FALSE_POP) {
+            return false
+        }
+
+        let first = match self.instructions[i].target.and_then(|target| self.resolve(target)) {
+            Some(first) => first,
+            None => return false,
+        };
+
+        let mut seen = HashSet::new();
+        let mut last = first;
+
+        // Loops stay where they are, they're where the VM checks for interrupts
+        while matches!(self.op(last), JUMP | JUMP_LONG) {
+            if !seen.insert(last) {
+                return false // jumps going round in circles
+            }
+
+            last = match self.instructions[last].target.and_then(|target| self.resolve(target)) {
+                Some(target) => target,
+                None => return false,
+            };
+        }
+
+        if last == first {
+            return false
+        }
+
+        self.retarget(i, last);
+        true
+    }
+
+    fn retarget(&mut self, i: usize, target: usize) {
+        self.instructions[i].target = Some(target);
+        self.targeted.insert(target);
+    }
+
+    fn encode(&self, chunk: &Chunk) -> Option<Chunk> {
+        let mut starts = vec![0; self.instructions.len()];
+        let mut offset = 0;
+
+        for (i, inst) in self.instructions.iter().enumerate() {
+            starts[i] = offset;
+
+            if inst.live {
+                offset += inst.bytes.len()
+            }
+        }
+
+        let mut code = Vec::with_capacity(offset);
+        let mut lines: Vec<(usize, usize)> = Vec::new();
+
+        for inst in self.instructions.iter().filter(|inst| inst.live) {
+            let start = code.len();
+
+            if lines.last().map(|&(_, line)| line) != Some(inst.line) {
+                lines.push((start, inst.line))
+            }
+
+            let mut bytes = inst.bytes.clone();
+
+            if let Some(target) = inst.target {
+                let target = starts[self.resolve(target)?];
+                let end = start + bytes.len();
+
+                match bytes[0] {
+                    JUMP | JUMP_IF_FALSE | JUMP_IF_FALSE_POP | PUSH_HANDLER => {
+                        let target = u16::try_from(target).ok()?;
+                        bytes[1..3].copy_from_slice(&target.to_le_bytes())
+                    },
+                    JUMP_LONG | JUMP_IF_FALSE_LONG | PUSH_HANDLER_LONG => {
+                        let target = u32::try_from(target).ok()?;
+                        bytes[1..5].copy_from_slice(&target.to_le_bytes())
+                    },
+                    LOOP => {
+                        let sub = u16::try_from(end.checked_sub(target)?).ok()?;
+                        bytes[1..3].copy_from_slice(&sub.to_le_bytes())
+                    },
+                    LOOP_LONG => {
+                        let sub = u32::try_from(end.checked_sub(target)?).ok()?;
+                        bytes[1..5].copy_from_slice(&sub.to_le_bytes())
+                    },
+                    _ => return None,
+                }
+            }
+
+            code.extend_from_slice(&bytes)
+        }
+
+        Some(chunk.with_code(code, &lines))
+    }
+
+    fn targets(&self) -> HashSet<usize> {
+        self.instructions.iter()
+            .filter(|inst| inst.live)
+            .filter_map(|inst| inst.target)
+            .filter_map(|target| self.resolve(target))
+            .collect()
+    }
+
+    // Where a jump to `i` now lands, the first instruction still there from `i` on.
+    fn resolve(&self, i: usize) -> Option<usize> {
+        (i..self.instructions.len()).find(|&i| self.instructions[i].live)
+    }
+
+    fn next(&self, i: usize) -> Option<usize> {
+        self.resolve(i + 1)
+    }
+
+    fn op(&self, i: usize) -> u8 {
+        self.instructions[i].bytes[0]
+    }
+}
@@ -6,15 +6,28 @@ use std::cell::RefCell;
 #[derive(Clone, Debug)]
 pub struct IrBuilder {
     program: Vec<ExprNode>,
+    line: Option<usize>,
+}
+
+impl Default for IrBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IrBuilder {
     pub fn new() -> Self {
         IrBuilder {
             program: Vec::new(),
+            line: None,
         }
     }
 
+    // Every node emitted from here on without a line of its own gets this one
+    pub fn set_line(&mut self, line: usize) {
+        self.line = Some(line)
+    }
+
 
     pub fn bind(&mut self, binding: Binding, rhs: ExprNode) {
         let bind = Expr::Bind(binding, rhs);
@@ -40,6 +53,52 @@ impl IrBuilder {
         )
     }
 
+    pub fn throw(&mut self, value: ExprNode) {
+        self.emit(
+            Expr::Throw(value).node(TypeInfo::nil())
+        )
+    }
+
+    // The thrown value is bound to `catch`'s binding while its handler runs.
+    pub fn try_(&mut self, body: ExprNode, catch: Option<(Binding, ExprNode)>, finally: Option<ExprNode>) -> ExprNode {
+        let (catch_binding, handler) = match catch {
+            Some((binding, handler)) => (Some(binding), Some(handler)),
+            None => (None, None),
+        };
+
+        Expr::Try {
+            body,
+            catch_binding,
+            handler,
+            finally,
+        }.node(TypeInfo::nil())
+    }
+
+    pub fn coroutine(&self, function: ExprNode) -> ExprNode {
+        Expr::Coroutine(function).node(TypeInfo::nil())
+    }
+
+    pub fn yield_(&self, value: ExprNode) -> ExprNode {
+        Expr::Yield(value).node(TypeInfo::nil())
+    }
+
+    // Same as calling it.
+    pub fn resume(&self, coroutine: ExprNode, args: Vec<ExprNode>) -> ExprNode {
+        self.call(coroutine, args, None)
+    }
+
+    pub fn coroutine_status(&self, coroutine: ExprNode) -> ExprNode {
+        Expr::CoroutineStatus(coroutine).node(TypeInfo::nil())
+    }
+
+    pub fn block(&mut self, mut body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+        let mut body_builder = self.nested();
+
+        body_build(&mut body_builder);
+
+        Expr::Block(body_builder.build()).node(TypeInfo::nil())
+    }
+
     pub fn break_(&mut self) {
         self.emit(
             Expr::Break.node(TypeInfo::nil())
@@ -100,7 +159,7 @@ impl IrBuilder {
 
     pub fn int(&self, n: i32) -> ExprNode {
         let info = TypeInfo::new(Type::Int);
-        let lit = Literal::Number(n as f64);
+        let lit = Literal::Int(n);
 
         Expr::Literal(lit).node(info)
     }
@@ -126,10 +185,72 @@ impl IrBuilder {
         Expr::Literal(lit).node(info)
     }
 
+    pub fn nil(&self) -> ExprNode {
+        Expr::Literal(Literal::Nil).node(TypeInfo::nil())
+    }
+
+
+
+    pub fn function(&mut self, var: Binding, params: &[&str], body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+        let ir_func = self.function_body(var, params, false, body_build);
+
+        Expr::Function(
+            ir_func
+        ).node(
+            TypeInfo::nil()
+        )
+    }
+
+    // Methods see their receiver as the local `self`, at the same depth as their parameters.
+    pub fn method(&mut self, var: Binding, params: &[&str], body_build: impl FnMut(&mut IrBuilder)) -> IrFunction {
+        self.function_body(var, params, true, body_build)
+    }
 
+    pub fn class(&mut self, var: Binding, methods: Vec<IrFunction>) -> ExprNode {
+        Expr::Class(
+            IrClass {
+                var,
+                superclass: None,
+                methods,
+            }
+        ).node(
+            TypeInfo::nil()
+        )
+    }
 
-    pub fn function(&mut self, var: Binding, params: &[&str], mut body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
-        let mut body_builder = IrBuilder::new();
+    // Methods not defined here are inherited from `superclass`, which methods can reach through `super_`.
+    pub fn subclass(&mut self, var: Binding, superclass: ExprNode, methods: Vec<IrFunction>) -> ExprNode {
+        Expr::Class(
+            IrClass {
+                var,
+                superclass: Some(superclass),
+                methods,
+            }
+        ).node(
+            TypeInfo::nil()
+        )
+    }
+
+    pub fn super_(&self, name: &str) -> ExprNode {
+        Expr::Super(name.to_owned()).node(TypeInfo::nil())
+    }
+
+    pub fn get_property(&self, object: ExprNode, name: &str) -> ExprNode {
+        Expr::GetProperty(object, name.to_owned()).node(TypeInfo::nil())
+    }
+
+    pub fn set_property(&mut self, object: ExprNode, name: &str, value: ExprNode) {
+        let property = self.get_property(object, name);
+
+        self.mutate(property, value)
+    }
+
+    pub fn invoke(&self, object: ExprNode, name: &str, args: Vec<ExprNode>) -> ExprNode {
+        Expr::Invoke(object, name.to_owned(), args).node(TypeInfo::nil())
+    }
+
+    fn function_body(&mut self, var: Binding, params: &[&str], method: bool, mut body_build: impl FnMut(&mut IrBuilder)) -> IrFunction {
+        let mut body_builder = self.nested();
 
         body_build(&mut body_builder);
 
@@ -138,20 +259,14 @@ impl IrBuilder {
         let func_body = IrFunctionBody {
             params: params.iter().cloned().map(|x: &str|
                 Binding::local(x, var.depth.unwrap_or(0) + 1, var.function_depth + 1)).collect::<Vec<Binding>>(),
-            method: false,
+            method,
             inner: body
         };
 
-        let ir_func = IrFunction {
+        IrFunction {
             var,
             body: Rc::new(RefCell::new(func_body))
-        };
-
-        Expr::Function(
-            ir_func
-        ).node(
-            TypeInfo::nil()
-        )
+        }
     }
 
     pub fn ternary(&mut self, cond: ExprNode, then_body: ExprNode, else_body: Option<ExprNode>) -> ExprNode {
@@ -163,14 +278,14 @@ impl IrBuilder {
     }
 
     pub fn if_(&mut self, cond: ExprNode, then_build: fn(&mut IrBuilder), else_build: Option<fn(&mut IrBuilder)>) -> ExprNode {
-        let mut then_builder = IrBuilder::new();
+        let mut then_builder = self.nested();
 
         then_build(&mut then_builder);
 
         let then_body = Expr::Block(then_builder.build()).node(TypeInfo::nil());
 
         let else_body = if let Some(else_build) = else_build {
-            let mut else_builder = IrBuilder::new();
+            let mut else_builder = self.nested();
 
             else_build(&mut else_builder);
 
@@ -187,7 +302,7 @@ impl IrBuilder {
     }
 
     pub fn while_(&mut self, cond: ExprNode, then_build: fn(&mut IrBuilder)) -> ExprNode {
-        let mut then_builder = IrBuilder::new();
+        let mut then_builder = self.nested();
 
         then_build(&mut then_builder);
 
@@ -206,6 +321,19 @@ impl IrBuilder {
     }
 
     pub fn emit(&mut self, atom: ExprNode) {
+        let atom = match (atom.line(), self.line) {
+            (None, Some(line)) => atom.with_line(line),
+            _ => atom,
+        };
+
         self.program.push(atom)
     }
+
+    // Nested builders start out on the line of their parent
+    fn nested(&self) -> IrBuilder {
+        IrBuilder {
+            program: Vec::new(),
+            line: self.line,
+        }
+    }
 }
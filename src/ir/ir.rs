@@ -10,9 +10,10 @@ use std::{
 pub type LocalId = usize;
 pub type DataId  = usize;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Literal {
     Number(f64),
+    Int(i32),
     String(String),
     Boolean(bool),
     Nil,
@@ -48,7 +49,7 @@ impl Binding {
         Binding {
             name: name.to_string(),
             depth: Some(depth),
-            function_depth: function_depth
+            function_depth
         }
     }
 
@@ -78,7 +79,7 @@ impl Binding {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum BinaryOp {
     Add,
     Sub,
@@ -95,28 +96,42 @@ pub enum BinaryOp {
     And,
     Or,
     Pow,
+    // Ints only
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum UnaryOp {
     Neg,
     Not,
+    BitNot,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct IrFunctionBody {
     pub params: Vec<Binding>,
     pub method: bool,
     pub inner: Vec<ExprNode>, // the actual function body
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct IrFunction {
     pub var: Binding,
     pub body: Rc<RefCell<IrFunctionBody>>, // A Literal/Constant
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrClass {
+    pub var: Binding,
+    pub superclass: Option<ExprNode>,
+    pub methods: Vec<IrFunction>, // with `method` set, `self` is the first local
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Call {
     pub callee: Node<Expr>,
     pub args: Vec<Node<Expr>>,
@@ -126,16 +141,28 @@ pub struct Call {
 pub struct Node<T> {
     inner: Box<T>,
     type_info: TypeInfo,
+    line: Option<usize>,
 }
 
 impl<T> Node<T> {
     pub fn new(inner: T, type_info: TypeInfo) -> Self {
         Node {
             inner: Box::new(inner),
-            type_info
+            type_info,
+            line: None,
         }
     }
 
+    // Attach the source line this node came from, used for diagnostics and line tables
+    pub fn with_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
     pub fn inner(&self) -> &T {
         &self.inner
     }
@@ -149,6 +176,13 @@ impl<T> Node<T> {
     }
 }
 
+// Nodes are equal when their expressions are, wherever they came from
+impl<T: PartialEq> PartialEq for Node<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
 impl<T: fmt::Debug> fmt::Debug for Node<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:#?}", self.inner)
@@ -160,7 +194,7 @@ pub type ExprNode = Node<Expr>;
 // NOTE: LocalId removed for now, as it wasn't used in the compiler
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Data(DataId),
 
@@ -176,6 +210,24 @@ pub enum Expr {
     Call(Call),
     Function(IrFunction),
     AnonFunction(IrFunction), // variable here will be unique id
+    Class(IrClass),
+    GetProperty(ExprNode, String),
+    Invoke(ExprNode, String, Vec<ExprNode>), // `a.b(c)` without creating a bound method
+    Super(String), // `super.b`, only valid inside methods of a subclass
+
+    // `finally` runs when the body or handler completes or throws, but not when they `return`
+    Try {
+        body: ExprNode,
+        catch_binding: Option<Binding>,
+        handler: Option<ExprNode>,
+        finally: Option<ExprNode>,
+    },
+    Throw(ExprNode),
+
+    // Coroutines are resumed by calling them, the first call passes arguments to `Coroutine`'s function
+    Coroutine(ExprNode),
+    Yield(ExprNode), // evaluates to the value passed to the next resume, or nil
+    CoroutineStatus(ExprNode), // "suspended", "running" or "dead"
     Unary(UnaryOp, ExprNode),
     Return(Option<ExprNode>),
 
@@ -225,4 +277,8 @@ impl Program {
     pub fn insert(&mut self, id: DataId, atom: ExprNode) {
         self.data.insert(id, atom);
     }
+
+    pub fn entry(&self) -> Option<DataId> {
+        self.entry
+    }
 }
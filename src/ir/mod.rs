@@ -1,6 +1,8 @@
 pub mod types;
+#[allow(clippy::module_inception)]
 pub mod ir;
 pub mod builder;
+pub mod opt;
 
 
 pub use self::types::*;
@@ -0,0 +1,57 @@
+use super::*;
+
+// Drops the side of an `If` its literal condition never takes, and loops that never run.
+// Branches declaring locals are kept, as code after them may still refer to those.
+pub struct DeadBranches;
+
+impl Pass for DeadBranches {
+    fn name(&self) -> &'static str {
+        "dead-branches"
+    }
+
+    fn run(&mut self, program: &mut Vec<ExprNode>) -> bool {
+        rewrite(program, &mut prune, &mut |_| false)
+    }
+}
+
+fn prune(expr: &mut ExprNode) -> bool {
+    use self::Expr::*;
+
+    let pruned = match expr.inner() {
+        If(ref cond, ref then, ref els) => match as_literal(cond) {
+            Some(cond) => {
+                let (taken, dropped) = if truthy(cond) {
+                    (Some(then), els.as_ref())
+                } else {
+                    (els.as_ref(), Some(then))
+                };
+
+                if dropped.is_some_and(declares_local) {
+                    return false
+                }
+
+                Some(taken.cloned().unwrap_or_else(empty))
+            },
+            None => None,
+        },
+
+        While(ref cond, ref body) => match as_literal(cond) {
+            Some(cond) if !truthy(cond) && !declares_local(body) => Some(empty()),
+            _ => None,
+        },
+
+        _ => None,
+    };
+
+    match pruned {
+        Some(pruned) => {
+            replace(expr, pruned);
+            true
+        },
+        None => false,
+    }
+}
+
+fn empty() -> ExprNode {
+    Expr::Block(Vec::new()).node(TypeInfo::nil())
+}
@@ -0,0 +1,164 @@
+use super::*;
+use crate::vm::{ Arith, Bitwise, Number };
+
+use std::cmp::Ordering;
+
+// Evaluates operators whose operands are all literals, numbers following the same `Number` rules
+// as the VM. Anything the VM would reject, like adding a number to a boolean or dividing an int by
+// zero, is left alone to fail at runtime as before.
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant-folding"
+    }
+
+    fn run(&mut self, program: &mut Vec<ExprNode>) -> bool {
+        rewrite(program, &mut fold, &mut |_| false)
+    }
+}
+
+fn fold(expr: &mut ExprNode) -> bool {
+    use self::Expr::{ Binary, Unary, Neg, Not };
+
+    let folded = match expr.inner() {
+        Binary(ref lhs, ref op, ref rhs) => {
+            // Dividing as ints where the compiler would
+            let int = expr.type_info().is_int() || (lhs.type_info().is_int() && rhs.type_info().is_int());
+
+            match (as_literal(lhs), op, as_literal(rhs)) {
+                // Short circuiting leaves whichever operand decided the result
+                (Some(lhs), BinaryOp::And, _) => Some(if truthy(lhs) { rhs.clone() } else { literal(lhs.clone()) }),
+                (Some(lhs), BinaryOp::Or, _) => Some(if truthy(lhs) { literal(lhs.clone()) } else { rhs.clone() }),
+                (Some(lhs), op, Some(rhs)) => binary(lhs, op, rhs, int).map(literal),
+                _ => None,
+            }
+        },
+
+        Unary(UnaryOp::Neg, ref value) | Neg(ref value) => as_literal(value)
+            .and_then(number)
+            .map(|n| literal(from_number(-n))),
+
+        Unary(UnaryOp::BitNot, ref value) => match as_literal(value) {
+            Some(Literal::Int(n)) => Some(literal(Literal::Int(!n))),
+            _ => None,
+        },
+
+        Unary(UnaryOp::Not, ref value) | Not(ref value) => {
+            as_literal(value).map(|value| literal(Literal::Boolean(!truthy(value))))
+        },
+
+        _ => None,
+    };
+
+    match folded {
+        Some(folded) => {
+            replace(expr, folded);
+            true
+        },
+        None => false,
+    }
+}
+
+fn literal(literal: Literal) -> ExprNode {
+    let type_info = literal_type(&literal);
+    Expr::Literal(literal).node(type_info)
+}
+
+fn number(literal: &Literal) -> Option<Number> {
+    match *literal {
+        Literal::Number(n) => Some(Number::Float(n)),
+        Literal::Int(n) => Some(Number::Int(n)),
+        _ => None,
+    }
+}
+
+fn from_number(number: Number) -> Literal {
+    match number {
+        Number::Int(n) => Literal::Int(n),
+        Number::Float(n) => Literal::Number(n),
+    }
+}
+
+fn binary(lhs: &Literal, op: &BinaryOp, rhs: &Literal, int: bool) -> Option<Literal> {
+    use self::Literal::*;
+    use self::BinaryOp::*;
+
+    if let (Some(a), Some(b)) = (number(lhs), number(rhs)) {
+        let arith = match op {
+            Add => Some(Arith::Add),
+            Sub => Some(Arith::Sub),
+            Mul => Some(Arith::Mul),
+            Div if int => Some(Arith::IntDiv),
+            Div => Some(Arith::Div),
+            Rem => Some(Arith::Rem),
+            Pow => Some(Arith::Pow),
+            _ => None,
+        };
+
+        if let Some(arith) = arith {
+            return a.apply(arith, b).map(from_number)
+        }
+
+        let bitwise = match op {
+            BitAnd => Some(Bitwise::And),
+            BitOr => Some(Bitwise::Or),
+            BitXor => Some(Bitwise::Xor),
+            Shl => Some(Bitwise::Shl),
+            Shr => Some(Bitwise::Shr),
+            _ => None,
+        };
+
+        if let Some(bitwise) = bitwise {
+            return match (lhs, rhs) {
+                (Int(a), Int(b)) => Some(Int(bitwise.apply(*a, *b))),
+                _ => None,
+            }
+        }
+
+        // `>=` and `<=` are compiled as the negation of `<` and `>`, which NaN has to agree with
+        let ordering = a.compare(b);
+
+        match op {
+            Gt => return Some(Boolean(ordering == Some(Ordering::Greater))),
+            Lt => return Some(Boolean(ordering == Some(Ordering::Less))),
+            GtEqual => return Some(Boolean(ordering != Some(Ordering::Less))),
+            LtEqual => return Some(Boolean(ordering != Some(Ordering::Greater))),
+            _ => (),
+        }
+    }
+
+    let result = match (lhs, op, rhs) {
+        (String(a), Add, String(b)) => String(format!("{}{}", a, b)),
+        (String(a), Add, b) => String(format!("{}{}", a, number(b)?)),
+        (a, Add, String(b)) => String(format!("{}{}", number(a)?, b)),
+
+        (a, Equal, b) => Boolean(equal(a, b)),
+        (a, NEqual, b) => Boolean(!equal(a, b)),
+
+        (String(a), Gt, String(b)) => Boolean(a > b),
+        (String(a), Lt, String(b)) => Boolean(a < b),
+        (String(a), GtEqual, String(b)) => Boolean(a >= b),
+        (String(a), LtEqual, String(b)) => Boolean(a <= b),
+
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+// Values of different types are never equal, other than ints and floats
+fn equal(lhs: &Literal, rhs: &Literal) -> bool {
+    use self::Literal::*;
+
+    if let (Some(a), Some(b)) = (number(lhs), number(rhs)) {
+        return a.compare(b) == Some(Ordering::Equal)
+    }
+
+    match (lhs, rhs) {
+        (String(a), String(b)) => a == b,
+        (Boolean(a), Boolean(b)) => a == b,
+        (Nil, Nil) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,265 @@
+pub mod fold;
+pub mod propagate;
+pub mod branches;
+pub mod unreachable;
+
+pub use self::fold::*;
+pub use self::propagate::*;
+pub use self::branches::*;
+pub use self::unreachable::*;
+
+use super::*;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+// The full pipeline stops after this many rounds, even if passes still find something to do
+const MAX_ROUNDS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    #[default]
+    None,
+    Basic, // folding and dead code removal, one round
+    Full,  // ... and constant propagation, until nothing changes
+}
+
+// A pass rewrites a program in place, returning whether it changed anything.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&mut self, program: &mut Vec<ExprNode>) -> bool;
+}
+
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    rounds: usize,
+}
+
+impl PassManager {
+    pub fn empty() -> Self {
+        PassManager {
+            passes: Vec::new(),
+            rounds: 1,
+        }
+    }
+
+    pub fn new(level: OptLevel) -> Self {
+        let mut manager = PassManager::empty();
+
+        if level == OptLevel::Full {
+            manager.add(ConstantPropagation);
+            manager.rounds = MAX_ROUNDS;
+        }
+
+        if level >= OptLevel::Basic {
+            manager.add(ConstantFolding);
+            manager.add(DeadBranches);
+            manager.add(UnreachableCode);
+        }
+
+        manager
+    }
+
+    pub fn add(&mut self, pass: impl Pass + 'static) -> &mut Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn passes(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    // Runs every pass in order, starting over while any of them still makes changes.
+    pub fn run(&mut self, program: &mut Vec<ExprNode>) {
+        for _ in 0..self.rounds {
+            let mut changed = false;
+
+            for pass in self.passes.iter_mut() {
+                changed |= pass.run(program)
+            }
+
+            if !changed {
+                break
+            }
+        }
+    }
+}
+
+// Rewrites `program` bottom-up, function bodies included: `node` sees every expression after
+// everything below it, `list` sees every list of statements after the statements in it.
+pub fn rewrite(
+    program: &mut Vec<ExprNode>,
+    node: &mut dyn FnMut(&mut ExprNode) -> bool,
+    list: &mut dyn FnMut(&mut Vec<ExprNode>) -> bool,
+) -> bool {
+    let mut changed = false;
+
+    for statement in program.iter_mut() {
+        changed |= rewrite_node(statement, node, list)
+    }
+
+    changed | list(program)
+}
+
+fn rewrite_node(
+    expr: &mut ExprNode,
+    node: &mut dyn FnMut(&mut ExprNode) -> bool,
+    list: &mut dyn FnMut(&mut Vec<ExprNode>) -> bool,
+) -> bool {
+    let mut changed = false;
+
+    for body in bodies_mut(expr.inner_mut()) {
+        changed |= rewrite(body, node, list)
+    }
+
+    for child in children_mut(expr.inner_mut()) {
+        changed |= rewrite_node(child, node, list)
+    }
+
+    changed | node(expr)
+}
+
+// The lists of statements right below `expr`: blocks, and the bodies of functions and methods.
+pub fn bodies_mut(expr: &mut Expr) -> Vec<&mut Vec<ExprNode>> {
+    use self::Expr::*;
+
+    match expr {
+        Block(ref mut body) => vec![body],
+        Function(ref mut function) | AnonFunction(ref mut function) => vec![&mut function_body_mut(function).inner],
+        Class(ref mut class) => class.methods.iter_mut().map(|method| &mut function_body_mut(method).inner).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The expressions right below `expr`, leaving out the statements in `bodies_mut`.
+pub fn children_mut(expr: &mut Expr) -> Vec<&mut ExprNode> {
+    use self::Expr::*;
+
+    match expr {
+        Bind(_, ref mut value) | BindGlobal(_, ref mut value) => vec![value],
+        Mutate(ref mut lhs, ref mut rhs) | Binary(ref mut lhs, _, ref mut rhs) => vec![lhs, rhs],
+        Call(ref mut call) => {
+            let mut children = vec![&mut call.callee];
+            children.extend(call.args.iter_mut());
+            children
+        },
+        Class(ref mut class) => class.superclass.iter_mut().collect(),
+        GetProperty(ref mut object, _) => vec![object],
+        Invoke(ref mut object, _, ref mut args) => {
+            let mut children = vec![object];
+            children.extend(args.iter_mut());
+            children
+        },
+        Try { ref mut body, ref mut handler, ref mut finally, .. } => {
+            let mut children = vec![body];
+            children.extend(handler.iter_mut());
+            children.extend(finally.iter_mut());
+            children
+        },
+        Throw(ref mut value)
+        | Coroutine(ref mut value)
+        | Yield(ref mut value)
+        | CoroutineStatus(ref mut value)
+        | Unary(_, ref mut value)
+        | Not(ref mut value)
+        | Neg(ref mut value) => vec![value],
+        Return(ref mut value) => value.iter_mut().collect(),
+        If(ref mut cond, ref mut then, ref mut els) => {
+            let mut children = vec![cond, then];
+            children.extend(els.iter_mut());
+            children
+        },
+        While(ref mut cond, ref mut body) => vec![cond, body],
+        List(ref mut content) => content.iter_mut().collect(),
+        Dict(ref mut keys, ref mut values) => keys.iter_mut().chain(values.iter_mut()).collect(),
+        SetElement(ref mut list, ref mut index, ref mut value) => vec![list, index, value],
+        Data(_) | Literal(_) | Var(_) | Function(_) | AnonFunction(_) | Super(_) | Block(_) | Break | Pop => Vec::new(),
+    }
+}
+
+// Read-only `children_mut`
+pub fn children(expr: &ExprNode) -> impl Iterator<Item = &ExprNode> {
+    use self::Expr::*;
+
+    let children: Vec<&ExprNode> = match expr.inner() {
+        Bind(_, ref value) | BindGlobal(_, ref value) => vec![value],
+        Mutate(ref lhs, ref rhs) | Binary(ref lhs, _, ref rhs) => vec![lhs, rhs],
+        Call(ref call) => Some(&call.callee).into_iter().chain(call.args.iter()).collect(),
+        Class(ref class) => class.superclass.iter().collect(),
+        GetProperty(ref object, _) => vec![object],
+        Invoke(ref object, _, ref args) => Some(object).into_iter().chain(args.iter()).collect(),
+        Try { ref body, ref handler, ref finally, .. } => Some(body).into_iter().chain(handler).chain(finally).collect(),
+        Throw(ref value)
+        | Coroutine(ref value)
+        | Yield(ref value)
+        | CoroutineStatus(ref value)
+        | Unary(_, ref value)
+        | Not(ref value)
+        | Neg(ref value) => vec![value],
+        Return(ref value) => value.iter().collect(),
+        If(ref cond, ref then, ref els) => vec![cond, then].into_iter().chain(els).collect(),
+        While(ref cond, ref body) => vec![cond, body],
+        List(ref content) => content.iter().collect(),
+        Dict(ref keys, ref values) => keys.iter().chain(values.iter()).collect(),
+        SetElement(ref list, ref index, ref value) => vec![list, index, value],
+        _ => Vec::new(),
+    };
+
+    children.into_iter()
+}
+
+// Function bodies can be shared between clones of the IR, so they're copied before being rewritten.
+fn function_body_mut(function: &mut IrFunction) -> &mut IrFunctionBody {
+    let body = function.body.borrow().clone();
+    function.body = Rc::new(RefCell::new(body));
+
+    Rc::get_mut(&mut function.body)
+        .expect("just copied")
+        .get_mut()
+}
+
+// Whether running `expr` gives the function a new local. The compiler doesn't scope blocks,
+// so dropping one of these would leave later uses of the local unresolved.
+pub fn declares_local(expr: &ExprNode) -> bool {
+    use self::Expr::*;
+
+    match expr.inner() {
+        Bind(ref binding, _) => binding.depth.is_some(),
+        Function(ref function) => function.var.depth.is_some(),
+        Class(ref class) => class.var.depth.is_some() || class.superclass.is_some(),
+        Block(ref body) => body.iter().any(declares_local),
+        If(_, ref then, ref els) => declares_local(then) || els.as_ref().is_some_and(declares_local),
+        While(_, ref body) => declares_local(body),
+        _ => false,
+    }
+}
+
+// Swaps `expr` out, the replacement keeps its line if it has one or takes over the old one.
+pub(crate) fn replace(expr: &mut ExprNode, with: ExprNode) {
+    *expr = match (with.line(), expr.line()) {
+        (None, Some(line)) => with.with_line(line),
+        _ => with,
+    }
+}
+
+pub(crate) fn literal_type(literal: &Literal) -> TypeInfo {
+    match literal {
+        Literal::Number(_) => TypeInfo::new(Type::Float),
+        Literal::Int(_) => TypeInfo::new(Type::Int),
+        Literal::String(_) => TypeInfo::new(Type::String),
+        Literal::Boolean(_) => TypeInfo::new(Type::Bool),
+        Literal::Nil => TypeInfo::nil(),
+    }
+}
+
+// Same as the VM's idea of truth
+pub(crate) fn truthy(literal: &Literal) -> bool {
+    !matches!(literal, Literal::Boolean(false) | Literal::Nil)
+}
+
+pub(crate) fn as_literal(expr: &ExprNode) -> Option<&Literal> {
+    match expr.inner() {
+        Expr::Literal(ref literal) => Some(literal),
+        _ => None,
+    }
+}
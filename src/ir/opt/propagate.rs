@@ -0,0 +1,90 @@
+use super::*;
+
+// Replaces reads of a local bound to a literal with the literal itself, when nothing after
+// the binding assigns to the local or declares another with the same name. The binding
+// stays, locals are numbered by the order they're declared in.
+pub struct ConstantPropagation;
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "constant-propagation"
+    }
+
+    fn run(&mut self, program: &mut Vec<ExprNode>) -> bool {
+        rewrite(program, &mut |_| false, &mut |list| propagate(list))
+    }
+}
+
+fn propagate(list: &mut [ExprNode]) -> bool {
+    let mut changed = false;
+
+    for i in 0..list.len() {
+        let (name, value) = match list[i].inner() {
+            Expr::Bind(ref binding, ref value) if binding.depth.is_some() => match as_literal(value) {
+                Some(value) => (binding.name().to_owned(), value.clone()),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let rest = &mut list[i + 1..];
+
+        if rest.iter().any(|statement| rebinds(statement, &name)) {
+            continue
+        }
+
+        for statement in rest.iter_mut() {
+            changed |= substitute(statement, &name, &value)
+        }
+    }
+
+    changed
+}
+
+fn substitute(expr: &mut ExprNode, name: &str, value: &Literal) -> bool {
+    let mut changed = false;
+
+    for body in bodies_mut(expr.inner_mut()) {
+        for statement in body.iter_mut() {
+            changed |= substitute(statement, name, value)
+        }
+    }
+
+    for child in children_mut(expr.inner_mut()) {
+        changed |= substitute(child, name, value)
+    }
+
+    match expr.inner() {
+        Expr::Var(ref binding) if binding.depth.is_some() && binding.name() == name => {
+            replace(expr, Expr::Literal(value.clone()).node(literal_type(value)));
+            true
+        },
+        _ => changed,
+    }
+}
+
+// Whether `expr` assigns to `name`, or declares something called `name` that later reads could mean instead.
+fn rebinds(expr: &ExprNode, name: &str) -> bool {
+    use self::Expr::*;
+
+    let local = |binding: &Binding| binding.depth.is_some() && binding.name() == name;
+    let function = |function: &IrFunction| {
+        let body = function.body.borrow();
+
+        local(&function.var)
+            || body.params.iter().any(&local)
+            || body.inner.iter().any(|statement| rebinds(statement, name))
+    };
+
+    let here = match expr.inner() {
+        Bind(ref binding, _) => local(binding),
+        Mutate(ref lhs, _) => matches!(lhs.inner(), Var(ref binding) if local(binding)),
+        Function(ref f) | AnonFunction(ref f) => function(f),
+        Class(ref class) => local(&class.var) || name == "super" || class.methods.iter().any(function),
+        Try { catch_binding: Some(ref binding), .. } => local(binding),
+        Block(ref body) => body.iter().any(|statement| rebinds(statement, name)),
+        _ => false,
+    };
+
+    here || children(expr).any(|child| rebinds(child, name))
+}
@@ -0,0 +1,36 @@
+use super::*;
+
+// Removes statements following a `return`, `break` or `throw` in the same list, which can never run.
+// Ones declaring locals stay, so the locals after them keep resolving.
+pub struct UnreachableCode;
+
+impl Pass for UnreachableCode {
+    fn name(&self) -> &'static str {
+        "unreachable-code"
+    }
+
+    fn run(&mut self, program: &mut Vec<ExprNode>) -> bool {
+        rewrite(program, &mut |_| false, &mut prune)
+    }
+}
+
+fn prune(list: &mut Vec<ExprNode>) -> bool {
+    let end = list.iter().position(|statement| {
+        matches!(statement.inner(), Expr::Return(_) | Expr::Break | Expr::Throw(_))
+    });
+
+    let end = match end {
+        Some(end) => end + 1,
+        None => return false,
+    };
+
+    let len = list.len();
+    let mut index = 0;
+
+    list.retain(|statement| {
+        index += 1;
+        index <= end || declares_local(statement)
+    });
+
+    list.len() != len
+}
@@ -24,4 +24,13 @@ impl TypeInfo {
             kind: None,
         }
     }
+
+    pub fn kind(&self) -> Option<&Type> {
+        self.kind.as_ref()
+    }
+
+    // Expressions typed `Int` divide as ints, anything else divides as before
+    pub fn is_int(&self) -> bool {
+        matches!(self.kind, Some(Type::Int))
+    }
 }
\ No newline at end of file
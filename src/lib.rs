@@ -1,7 +1,9 @@
 // #![feature(vec_drain_as_slice)]
 
+#[cfg(feature = "profiling")]
 extern crate flame;
-#[macro_use] extern crate flamer;
+#[cfg(feature = "profiling")]
+extern crate flamer;
 extern crate im_rc;
 
 pub mod vm;
@@ -12,6 +14,8 @@ pub mod compiler;
 mod tests {
     use super::vm::*;
     use super::ir::*;
+    use super::compiler::CompileError;
+    use super::compiler::peephole;
 
     #[test]
     fn globals() {
@@ -22,7 +26,7 @@ mod tests {
 
         let mut vm = VM::new();
 
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -38,7 +42,7 @@ mod tests {
 
         let mut vm = VM::new();
 
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -55,7 +59,7 @@ mod tests {
         builder.bind(Binding::global("sum"), sum);
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -97,7 +101,7 @@ mod tests {
         let built = builder.build();
 
         let mut vm = VM::new();
-        vm.exec(&built, true);
+        vm.exec(&built, true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -116,15 +120,15 @@ mod tests {
 
         builder.emit(call);
 
-        fn print(heap: &mut Heap<Object>, args: &[Value]) -> Value {
-            println!("{}", args[1].with_heap(heap));
-            Value::nil()
+        fn print(context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+            println!("{}", args[0].with_heap(context.heap()));
+            Ok(Value::nil())
         }
 
         let mut vm = VM::new();
 
         vm.add_native("print", print, 1);
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
     }
 
     #[test]
@@ -154,7 +158,7 @@ mod tests {
         builder.bind(Binding::global("element"), right); // expect 777.0
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -219,14 +223,14 @@ mod tests {
 
         builder.emit(call); // :D
 
-        fn print_native(heap: &mut Heap<Object>, args: &[Value]) -> Value {
-            println!("{}", args[1].with_heap(heap));
-            Value::nil()
+        fn print_native(context: &mut NativeContext, args: &[Value]) -> Result<Value, RuntimeError> {
+            println!("{}", args[0].with_heap(context.heap()));
+            Ok(Value::nil())
         }
 
         let mut vm = VM::new();
         vm.add_native("print", print_native, 1);
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
     }
 
     #[test]
@@ -250,8 +254,2183 @@ mod tests {
         builder.bind(Binding::global("test"), get_fruit);
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!(" sad sad {:#?}", vm.globals)
     }
+
+    #[test]
+    fn runtime_errors() {
+        let mut builder = IrBuilder::new();
+
+        let callee = builder.var(Binding::global("missing"));
+        let call = builder.call(callee, vec![], None);
+
+        builder.emit(call);
+
+        let mut vm = VM::new();
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedGlobal);
+        assert_eq!(err.trace[0].function, "<zub>");
+
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![builder.number(1.0)]);
+        let index = builder.int(3);
+
+        builder.bind(Binding::global("element"), builder.binary(list, BinaryOp::Index, index));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::IndexOutOfBounds);
+
+        let mut builder = IrBuilder::new();
+
+        let dict = builder.empty_dict();
+        let key = builder.string("nope");
+
+        builder.bind(Binding::global("element"), builder.binary(dict, BinaryOp::Index, key));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::MissingKey);
+
+        // The VM is left in a usable state after an error
+        let mut builder = IrBuilder::new();
+
+        let value = builder.number(42.0);
+        builder.bind(Binding::global("answer"), value);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.globals["answer"], Value::float(42.0));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let mut builder = IrBuilder::new();
+
+        builder.set_line(3);
+
+        let a = builder.var(Binding::local("a", 1, 0));
+        builder.bind(Binding::global("x"), a);
+
+        builder.set_line(7);
+
+        let callee = builder.var(Binding::global("f"));
+        let args = (0..256).map(|i| builder.number(i as f64)).collect();
+        let call = builder.call(callee, args, None);
+
+        builder.emit(call);
+
+        let mut vm = VM::new();
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let errors = err.compile_errors().unwrap();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], CompileError::UnresolvedLocal { name: "a".into(), line: 3 });
+        assert_eq!(errors[0].name(), Some("a"));
+        assert_eq!(errors[1], CompileError::TooManyArguments { count: 256, line: 7 });
+
+        // Nothing ran
+        assert!(!vm.globals.contains_key("x"));
+    }
+
+    #[test]
+    fn type_errors() {
+        let mut vm = VM::new();
+
+        let mut builder = IrBuilder::new();
+
+        let lhs = builder.string("ten");
+        let rhs = builder.number(1.0);
+        builder.bind(Binding::global("bad"), builder.binary(lhs, BinaryOp::Sub, rhs));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.message, "can't apply `-` to string and number");
+        assert!(vm.stack.is_empty());
+
+        let mut builder = IrBuilder::new();
+
+        let lhs = builder.bool(true);
+        let rhs = builder.list(vec![]);
+        builder.bind(Binding::global("bad"), builder.binary(lhs, BinaryOp::Add, rhs));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().message, "can't apply `+` to bool and list");
+
+        let mut builder = IrBuilder::new();
+
+        let lhs = builder.string("a");
+        let rhs = builder.number(1.0);
+        builder.bind(Binding::global("bad"), builder.binary(lhs, BinaryOp::Lt, rhs));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().message, "can't apply `<` to string and number");
+
+        let mut builder = IrBuilder::new();
+
+        let negated = IrBuilder::unary(UnaryOp::Neg, builder.string("a")).node(TypeInfo::nil());
+        builder.bind(Binding::global("bad"), negated);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().message, "can't negate a value of type string");
+
+        // Equality is defined between anything, ordering between strings
+        let mut builder = IrBuilder::new();
+
+        let lhs = builder.string("a");
+        let rhs = builder.string("b");
+        builder.bind(Binding::global("ordered"), builder.binary(lhs, BinaryOp::Lt, rhs));
+
+        let lhs = builder.list(vec![builder.string("a")]);
+        let index = builder.int(0);
+        let element = builder.binary(lhs, BinaryOp::Index, index);
+        let rhs = builder.string("a");
+        builder.bind(Binding::global("same"), builder.binary(element, BinaryOp::Equal, rhs));
+
+        let lhs = builder.number(1.0);
+        let rhs = builder.string("1");
+        builder.bind(Binding::global("mixed"), builder.binary(lhs, BinaryOp::Equal, rhs));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["ordered"], Value::truelit());
+        assert_eq!(vm.globals["same"], Value::truelit());
+        assert_eq!(vm.globals["mixed"], Value::falselit());
+    }
+
+    #[test]
+    fn natives() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut vm = VM::new();
+
+        // Variadic
+        vm.add_native("sum", |_: &mut NativeContext, args: &[Value]| {
+            let mut total = 0.0;
+
+            for arg in args {
+                match arg.decode() {
+                    Variant::Float(n) => total += n,
+                    _ => return Err(RuntimeError::new(RuntimeErrorKind::Type, "`sum` takes numbers")),
+                }
+            }
+
+            Ok(total.into())
+        }, Arity::AtLeast(0));
+
+        // Calls back into zub
+        vm.add_native("map", |context: &mut NativeContext, args: &[Value]| {
+            let content = args[0].as_object()
+                .and_then(|o| context.heap().get(o))
+                .and_then(Object::as_list)
+                .map(|list| list.content.clone());
+
+            let content = match content {
+                Some(content) => content,
+                None => return context.error(RuntimeErrorKind::Type, "`map` takes a list"),
+            };
+
+            let mut mapped = Vec::with_capacity(content.len());
+
+            for element in content {
+                mapped.push(context.call(args[1], &[element])?)
+            }
+
+            Ok(context.allocate(Object::List(List::new(mapped))))
+        }, 2);
+
+        // Captures Rust state and reads globals
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+
+        vm.add_native("scaled", move |context: &mut NativeContext, args: &[Value]| {
+            counter.set(counter.get() + 1);
+
+            let scale = context.get_global("scale").unwrap_or_else(|| Value::float(1.0));
+
+            Ok((args[0].as_float() * scale.as_float()).into())
+        }, 1);
+
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("scale"), builder.number(10.0));
+
+        let double = builder.function(Binding::local("double", 0, 0), &["x"], |builder| {
+            let x = builder.var(Binding::local("x", 1, 1));
+            let two = builder.number(2.0);
+
+            builder.ret(Some(builder.binary(x, BinaryOp::Mul, two)))
+        });
+
+        builder.emit(double);
+
+        let sum = builder.var(Binding::global("sum"));
+        let args = vec![builder.number(1.0), builder.number(2.0), builder.number(3.0)];
+        builder.bind(Binding::global("total"), builder.call(sum.clone(), args, None));
+        builder.bind(Binding::global("nothing"), builder.call(sum, vec![], None));
+
+        let map = builder.var(Binding::global("map"));
+        let list = builder.list(vec![builder.number(1.0), builder.number(2.0)]);
+        let double = builder.var(Binding::local("double", 0, 0));
+        builder.bind(Binding::global("doubled"), builder.call(map.clone(), vec![list, double], None));
+
+        let list = builder.list(vec![builder.number(4.0)]);
+        let scaled = builder.var(Binding::global("scaled"));
+        builder.bind(Binding::global("scaled_list"), builder.call(map, vec![list, scaled], None));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["total"], Value::float(6.0));
+        assert_eq!(vm.globals["nothing"], Value::float(0.0));
+        let content = |vm: &VM, name: &str| {
+            let list = vm.globals[name].as_object().unwrap();
+            vm.heap.get(list).unwrap().as_list().unwrap().content.clone()
+        };
+
+        assert_eq!(content(&vm, "doubled"), vec![Value::float(2.0), Value::float(4.0)]);
+        assert_eq!(content(&vm, "scaled_list"), vec![Value::float(40.0)]);
+        assert_eq!(calls.get(), 1);
+
+        // Errors returned by natives get the zub call stack attached
+        let mut builder = IrBuilder::new();
+
+        let sum = builder.var(Binding::global("sum"));
+        let args = vec![builder.string("one")];
+        builder.emit(builder.call(sum, args, None));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.trace[0].function, "<zub>");
+
+        // Arity is checked before the native runs
+        let mut builder = IrBuilder::new();
+
+        let map = builder.var(Binding::global("map"));
+        builder.emit(builder.call(map, vec![], None));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().message, "`map` expects 2 argument(s), got 0");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn call_from_rust() {
+        let mut builder = IrBuilder::new();
+
+        let add = builder.function(Binding::global("add"), &["a", "b"], |builder| {
+            let a = builder.var(Binding::local("a", 1, 1));
+            let b = builder.var(Binding::local("b", 1, 1));
+
+            builder.ret(Some(builder.binary(a, BinaryOp::Add, b)))
+        });
+
+        builder.emit(add);
+
+        // Calls back out to Rust, which calls into zub again
+        let twice = builder.function(Binding::global("twice"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let host = builder.var(Binding::global("host_add"));
+
+            builder.ret(Some(builder.call(host, vec![n.clone(), n], None)))
+        });
+
+        builder.emit(twice);
+
+        let mut vm = VM::new();
+
+        vm.add_native("host_add", |context: &mut NativeContext, args: &[Value]| {
+            context.call_global("add", args)
+        }, 2);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let sum = vm.call_global("add", &[Value::float(1.0), Value::float(2.0)]).unwrap();
+        assert_eq!(sum, Value::float(3.0));
+
+        let twice = vm.globals["twice"];
+        assert_eq!(vm.call_value(twice, &[Value::float(21.0)]).unwrap(), Value::float(42.0));
+
+        // Failed calls leave the VM usable
+        let err = vm.call_global("add", &[Value::float(1.0)]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Arity);
+
+        let err = vm.call_global("add", &[Value::nil(), Value::float(1.0)]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.trace[0].function, "add");
+
+        let err = vm.call_global("missing", &[]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedGlobal);
+
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+
+        assert_eq!(vm.call_global("add", &[Value::float(2.0), Value::float(2.0)]).unwrap(), Value::float(4.0));
+    }
+
+    #[test]
+    fn conversions() {
+        use std::collections::HashMap;
+
+        let mut vm = VM::new();
+
+        let value = "zub".into_value(&mut vm.heap);
+        assert_eq!(String::from_value(value, &vm.heap).unwrap(), "zub");
+
+        let value = vec![Some(1u8), None, Some(3)].into_value(&mut vm.heap);
+        assert_eq!(Vec::<Option<u8>>::from_value(value, &vm.heap).unwrap(), vec![Some(1), None, Some(3)]);
+
+        let value = (1.5, true, "three".to_string()).into_value(&mut vm.heap);
+        assert_eq!(<(f64, bool, String)>::from_value(value, &vm.heap).unwrap(), (1.5, true, "three".to_string()));
+
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), vec![1i32, 2]);
+        map.insert("b".to_string(), vec![]);
+
+        let value = map.clone().into_value(&mut vm.heap);
+        assert_eq!(HashMap::<String, Vec<i32>>::from_value(value, &vm.heap).unwrap(), map);
+
+        // Mismatches are reported, not panicked on
+        let err = i32::from_value(Value::float(1.5), &vm.heap).unwrap_err();
+        assert_eq!(err.to_string(), "expected i32, found number");
+
+        let err = u8::from_value(Value::float(300.0), &vm.heap).unwrap_err();
+        assert_eq!(err.expected, "u8");
+
+        let value = "nope".into_value(&mut vm.heap);
+        let err = Vec::<f64>::from_value(value, &vm.heap).unwrap_err();
+        assert_eq!(err, ConversionError { expected: "list", found: "string" });
+    }
+
+    #[test]
+    fn typed_natives() {
+        let mut vm = VM::new();
+
+        vm.add_typed_native("repeat", |s: String, n: usize| s.repeat(n));
+        vm.add_typed_native("lengths", |words: Vec<String>| -> Vec<usize> {
+            words.iter().map(String::len).collect()
+        });
+        vm.add_typed_native("checked_div", |a: f64, b: f64| {
+            if b == 0.0 {
+                Err(RuntimeError::new(RuntimeErrorKind::Native, "division by zero"))
+            } else {
+                Ok(a / b)
+            }
+        });
+        vm.add_typed_native("answer", || 42);
+
+        let mut builder = IrBuilder::new();
+
+        let repeat = builder.var(Binding::global("repeat"));
+        let args = vec![builder.string("ab"), builder.number(3.0)];
+        builder.bind(Binding::global("repeated"), builder.call(repeat, args, None));
+
+        let lengths = builder.var(Binding::global("lengths"));
+        let words = builder.list(vec![builder.string("zub"), builder.string("vm!")]);
+        builder.bind(Binding::global("lengths_of"), builder.call(lengths, vec![words], None));
+
+        let answer = builder.var(Binding::global("answer"));
+        builder.bind(Binding::global("the_answer"), builder.call(answer, vec![], None));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(String::from_value(vm.globals["repeated"], &vm.heap).unwrap(), "ababab");
+        assert_eq!(Vec::<usize>::from_value(vm.globals["lengths_of"], &vm.heap).unwrap(), vec![3, 3]);
+        assert_eq!(vm.globals["the_answer"], Value::int(42));
+
+        // Arity comes from the signature
+        let err = vm.call_global("repeat", &[Value::nil()]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Arity);
+
+        let err = vm.call_global("repeat", &[Value::float(1.0), Value::float(1.0)]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.message, "argument 1 of `repeat`: expected string, found number");
+
+        let err = vm.call_global("checked_div", &[Value::float(1.0), Value::float(0.0)]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Native);
+
+        assert_eq!(vm.call_global("checked_div", &[Value::float(1.0), Value::float(4.0)]).unwrap(), Value::float(0.25));
+    }
+
+    #[test]
+    fn classes() {
+        let mut builder = IrBuilder::new();
+
+        let init = builder.method(Binding::local("init", 0, 0), &["x", "y"], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+            let x = builder.var(Binding::local("x", 1, 1));
+            let y = builder.var(Binding::local("y", 1, 1));
+
+            builder.set_property(this.clone(), "x", x);
+            builder.set_property(this, "y", y);
+        });
+
+        let sum = builder.method(Binding::local("sum", 0, 0), &[], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+            let x = builder.get_property(this.clone(), "x");
+            let y = builder.get_property(this, "y");
+
+            builder.ret(Some(builder.binary(x, BinaryOp::Add, y)))
+        });
+
+        let point = builder.class(Binding::global("Point"), vec![init, sum]);
+        builder.emit(point);
+
+        let class = builder.var(Binding::global("Point"));
+        let args = vec![builder.number(1.0), builder.number(2.0)];
+        builder.bind(Binding::global("p"), builder.call(class, args, None));
+
+        let p = builder.var(Binding::global("p"));
+        builder.bind(Binding::global("invoked"), builder.invoke(p.clone(), "sum", vec![]));
+
+        // Reading a method binds it to its instance
+        let bound = builder.get_property(p.clone(), "sum");
+        builder.bind(Binding::global("bound"), bound);
+
+        let bound = builder.var(Binding::global("bound"));
+        builder.set_property(p.clone(), "x", builder.number(10.0));
+        builder.bind(Binding::global("called"), builder.call(bound, vec![], None));
+
+        builder.bind(Binding::global("y"), builder.get_property(p, "y"));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["invoked"], Value::float(3.0));
+        assert_eq!(vm.globals["called"], Value::float(12.0));
+        assert_eq!(vm.globals["y"], Value::float(2.0));
+        assert_eq!(format!("{}", vm.globals["p"].with_heap(&vm.heap)), "<Point instance>");
+
+        // Classes are callable from Rust too
+        let p = vm.call_global("Point", &[Value::float(3.0), Value::float(4.0)]).unwrap();
+        let handle = p.as_object().unwrap();
+        let fields = &vm.heap.get(handle).unwrap().as_instance().unwrap().fields;
+        assert_eq!(fields["x"], Value::float(3.0));
+
+        let err = vm.call_global("Point", &[]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Arity);
+
+        let mut builder = IrBuilder::new();
+
+        let p = builder.var(Binding::global("p"));
+        builder.emit(builder.invoke(p, "missing", vec![]));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedProperty);
+        assert_eq!(err.message, "`Point` instance has no property `missing`");
+
+        let mut builder = IrBuilder::new();
+
+        let n = builder.number(1.0);
+        builder.emit(builder.get_property(n, "x"));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::Type);
+    }
+
+    #[test]
+    fn inheritance() {
+        let mut builder = IrBuilder::new();
+
+        let init = builder.method(Binding::local("init", 0, 0), &["name"], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+            let name = builder.var(Binding::local("name", 1, 1));
+
+            builder.set_property(this, "name", name);
+        });
+
+        let speak = builder.method(Binding::local("speak", 0, 0), &[], |builder| {
+            builder.ret(Some(builder.string("...")))
+        });
+
+        let describe = builder.method(Binding::local("describe", 0, 0), &[], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+            let name = builder.get_property(this.clone(), "name");
+            let says = builder.string(" says ");
+            let sound = builder.invoke(this, "speak", vec![]);
+
+            let text = builder.binary(name, BinaryOp::Add, says);
+            builder.ret(Some(builder.binary(text, BinaryOp::Add, sound)))
+        });
+
+        let animal = builder.class(Binding::global("Animal"), vec![init, speak, describe]);
+        builder.emit(animal);
+
+        let speak = builder.method(Binding::local("speak", 0, 0), &[], |builder| {
+            builder.ret(Some(builder.string("woof")))
+        });
+
+        let describe = builder.method(Binding::local("describe", 0, 0), &[], |builder| {
+            let inherited = builder.super_("describe");
+            let description = builder.call(inherited, vec![], None);
+            let bang = builder.string("!");
+
+            builder.ret(Some(builder.binary(description, BinaryOp::Add, bang)))
+        });
+
+        let superclass = builder.var(Binding::global("Animal"));
+        let dog = builder.subclass(Binding::global("Dog"), superclass, vec![speak, describe]);
+        builder.emit(dog);
+
+        // Two levels deep, with `init` delegating upwards and `super` read as a bound method
+        let init = builder.method(Binding::local("init", 0, 0), &["name"], |builder| {
+            let inherited = builder.super_("init");
+            let name = builder.var(Binding::local("name", 1, 1));
+            let suffix = builder.string(" jr.");
+
+            builder.emit(builder.call(inherited, vec![builder.binary(name, BinaryOp::Add, suffix)], None));
+        });
+
+        let parent_sound = builder.method(Binding::local("parent_sound", 0, 0), &[], |builder| {
+            let bound = builder.super_("speak");
+            builder.ret(Some(builder.call(bound, vec![], None)))
+        });
+
+        let superclass = builder.var(Binding::global("Dog"));
+        let puppy = builder.subclass(Binding::global("Puppy"), superclass, vec![init, parent_sound]);
+        builder.emit(puppy);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let mut builder = IrBuilder::new();
+
+        for (global, class, name) in &[("a", "Animal", "Rex"), ("d", "Dog", "Rex"), ("p", "Puppy", "Rex")] {
+            let class = builder.var(Binding::global(class));
+            let instance = builder.call(class, vec![builder.string(name)], None);
+
+            builder.bind(Binding::global(global), builder.invoke(instance, "describe", vec![]));
+        }
+
+        let puppy = builder.var(Binding::global("Puppy"));
+        let instance = builder.call(puppy, vec![builder.string("Fido")], None);
+        builder.bind(Binding::global("parent"), builder.invoke(instance, "parent_sound", vec![]));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let string = |vm: &VM, name: &str| String::from_value(vm.globals[name], &vm.heap).unwrap();
+
+        assert_eq!(string(&vm, "a"), "Rex says ...");
+        assert_eq!(string(&vm, "d"), "Rex says woof!");
+        assert_eq!(string(&vm, "p"), "Rex jr. says woof!");
+        assert_eq!(string(&vm, "parent"), "woof");
+        assert!(vm.stack.is_empty());
+
+        let mut builder = IrBuilder::new();
+
+        let not_a_class = builder.number(1.0);
+        let broken = builder.subclass(Binding::global("Broken"), not_a_class, vec![]);
+        builder.emit(broken);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().message, "can't inherit from value of type number");
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut vm = VM::new();
+
+        vm.add_native("explode", |context: &mut NativeContext, _: &[Value]| {
+            context.error(RuntimeErrorKind::Native, "kaboom")
+        }, 0);
+
+        let mut builder = IrBuilder::new();
+
+        let fail = builder.function(Binding::global("fail"), &["x"], |builder| {
+            let x = builder.var(Binding::local("x", 1, 1));
+            builder.throw(x);
+            builder.ret(Some(builder.string("unreachable")))
+        });
+        builder.emit(fail);
+
+        // Thrown values reach the handler as they are
+        let body = builder.block(|builder| {
+            builder.throw(builder.string("boom"));
+            builder.bind(Binding::global("skipped"), builder.bool(true));
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("caught"), builder.var(Binding::local("e", 0, 0)));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        // ... and from deeper call frames
+        let body = builder.block(|builder| {
+            let fail = builder.var(Binding::global("fail"));
+            builder.emit(builder.call(fail, vec![builder.number(42.0)], None));
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("across"), builder.var(Binding::local("e", 0, 0)));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        // Errors raised by the VM and by natives are caught as dicts
+        for (global, cause) in &[("type_error", "vm"), ("native_error", "native")] {
+            let body = builder.block(|builder| {
+                let value = if *cause == "vm" {
+                    builder.binary(builder.number(1.0), BinaryOp::Sub, builder.string("a"))
+                } else {
+                    builder.call(builder.var(Binding::global("explode")), vec![], None)
+                };
+
+                builder.bind(Binding::global("skipped"), value);
+            });
+            let handler = builder.block(|builder| {
+                let e = builder.var(Binding::local("e", 0, 0));
+                builder.bind(Binding::global(global), builder.binary(e, BinaryOp::Index, builder.string("message")));
+            });
+            let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+            builder.emit(try_);
+        }
+
+        // `finally` runs on the way out, whether or not something was thrown
+        let body = builder.block(|builder| {
+            builder.bind(Binding::global("normal"), builder.number(1.0));
+        });
+        let finally = builder.block(|builder| {
+            builder.bind(Binding::global("finally_normal"), builder.bool(true));
+        });
+        let try_ = builder.try_(body, None, Some(finally));
+        builder.emit(try_);
+
+        let body = builder.block(|builder| {
+            let body = builder.block(|builder| {
+                builder.throw(builder.string("again"));
+            });
+            let finally = builder.block(|builder| {
+                builder.bind(Binding::global("finally_thrown"), builder.bool(true));
+            });
+            let try_ = builder.try_(body, None, Some(finally));
+            builder.emit(try_);
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("rethrown"), builder.var(Binding::local("e", 0, 0)));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let string = |vm: &VM, name: &str| String::from_value(vm.globals[name], &vm.heap).unwrap();
+
+        assert_eq!(string(&vm, "caught"), "boom");
+        assert_eq!(vm.globals["across"], Value::float(42.0));
+        assert_eq!(string(&vm, "type_error"), "can't apply `-` to number and string");
+        assert_eq!(string(&vm, "native_error"), "kaboom");
+        assert_eq!(vm.globals["finally_normal"], Value::truelit());
+        assert_eq!(vm.globals["finally_thrown"], Value::truelit());
+        assert_eq!(string(&vm, "rethrown"), "again");
+        assert!(!vm.globals.contains_key("skipped"));
+
+        // Breaking out of a `try` drops its handler, so this throw isn't caught by it
+        let mut builder = IrBuilder::new();
+
+        let cond = builder.bool(true);
+        let loop_ = builder.while_(cond, |builder| {
+            let body = builder.block(|builder| builder.break_());
+            let handler = builder.block(|builder| {
+                builder.bind(Binding::global("stale"), builder.bool(true));
+            });
+            let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+            builder.emit(try_);
+        });
+        builder.emit(loop_);
+        builder.throw(builder.number(7.0));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        let err = err.runtime_error().unwrap();
+
+        assert_eq!(err.kind, RuntimeErrorKind::Thrown);
+        assert_eq!(err.value, Some(Value::float(7.0)));
+        assert!(!vm.globals.contains_key("stale"));
+
+        // `return` and `break` run `finally` on the way out too, innermost first
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("order"), builder.string(""));
+
+        let guarded = builder.function(Binding::global("guarded"), &[], |builder| {
+            let body = builder.block(|builder| {
+                let body = builder.block(|builder| builder.ret(Some(builder.number(1.0))));
+                let finally = builder.block(|builder| {
+                    let order = builder.var(Binding::global("order"));
+                    builder.mutate(order.clone(), builder.binary(order, BinaryOp::Add, builder.string("inner ")));
+                    builder.emit(Expr::Pop.node(TypeInfo::nil()));
+                });
+                let try_ = builder.try_(body, None, Some(finally));
+                builder.emit(try_);
+            });
+            let finally = builder.block(|builder| {
+                // Declaring locals doesn't disturb the value being returned
+                builder.bind(Binding::local("last", 1, 1), builder.string("outer"));
+
+                let order = builder.var(Binding::global("order"));
+                let last = builder.var(Binding::local("last", 1, 1));
+                builder.mutate(order.clone(), builder.binary(order, BinaryOp::Add, last));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+            });
+            let try_ = builder.try_(body, None, Some(finally));
+            builder.emit(try_);
+
+            builder.ret(Some(builder.number(2.0)))
+        });
+        builder.emit(guarded);
+
+        let recovering = builder.function(Binding::global("recovering"), &[], |builder| {
+            let body = builder.block(|builder| builder.throw(builder.string("oops")));
+            let handler = builder.block(|builder| builder.ret(Some(builder.var(Binding::local("e", 1, 1)))));
+            let finally = builder.block(|builder| {
+                builder.bind(Binding::global("recovered"), builder.bool(true));
+            });
+            let try_ = builder.try_(body, Some((Binding::local("e", 1, 1), handler)), Some(finally));
+            builder.emit(try_);
+
+            builder.ret(None)
+        });
+        builder.emit(recovering);
+
+        builder.bind(Binding::global("returned"), builder.call(builder.var(Binding::global("guarded")), vec![], None));
+        builder.bind(Binding::global("caught"), builder.call(builder.var(Binding::global("recovering")), vec![], None));
+
+        let cond = builder.bool(true);
+        let loop_ = builder.while_(cond, |builder| {
+            let body = builder.block(|builder| builder.break_());
+            let handler = builder.block(|builder| {
+                builder.bind(Binding::global("stale"), builder.bool(true));
+            });
+            let finally = builder.block(|builder| {
+                builder.bind(Binding::global("broke"), builder.bool(true));
+            });
+            let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), Some(finally));
+            builder.emit(try_);
+        });
+        builder.emit(loop_);
+        builder.throw(builder.number(8.0));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().value, Some(Value::float(8.0)));
+        assert_eq!(vm.globals["returned"], Value::float(1.0));
+        assert_eq!(string(&vm, "order"), "inner outer");
+        assert_eq!(string(&vm, "caught"), "oops");
+        assert_eq!(vm.globals["recovered"], Value::truelit());
+        assert_eq!(vm.globals["broke"], Value::truelit());
+        assert!(!vm.globals.contains_key("stale"));
+    }
+
+    #[test]
+    fn coroutines() {
+        let mut builder = IrBuilder::new();
+
+        // Counts down, yielding as it goes
+        let countdown = builder.function(Binding::global("countdown"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let cond = builder.binary(n, BinaryOp::Gt, builder.number(0.0));
+
+            let loop_ = builder.while_(cond, |builder| {
+                let n = builder.var(Binding::local("n", 1, 1));
+                builder.emit(builder.yield_(n.clone()));
+
+                let next = builder.binary(n.clone(), BinaryOp::Sub, builder.number(1.0));
+                builder.mutate(n, next);
+            });
+            builder.emit(loop_);
+
+            builder.ret(Some(builder.string("liftoff")))
+        });
+        builder.emit(countdown);
+
+        let function = builder.var(Binding::global("countdown"));
+        builder.bind(Binding::global("co"), builder.coroutine(function));
+
+        let co = builder.var(Binding::global("co"));
+        builder.bind(Binding::global("first"), builder.resume(co.clone(), vec![builder.number(2.0)]));
+        builder.bind(Binding::global("second"), builder.resume(co.clone(), vec![]));
+        builder.bind(Binding::global("last"), builder.resume(co.clone(), vec![]));
+        builder.bind(Binding::global("status"), builder.coroutine_status(co));
+
+        // Resuming hands a value back to the paused `yield`
+        let sum = builder.function(Binding::global("sum"), &[], |builder| {
+            builder.bind(Binding::local("total", 1, 1), builder.number(0.0));
+
+            let cond = builder.bool(true);
+            let loop_ = builder.while_(cond, |builder| {
+                let total = builder.var(Binding::local("total", 1, 1));
+                let resumed = builder.yield_(total.clone());
+
+                builder.mutate(total.clone(), builder.binary(total, BinaryOp::Add, resumed));
+            });
+            builder.emit(loop_);
+        });
+        builder.emit(sum);
+
+        let function = builder.var(Binding::global("sum"));
+        builder.bind(Binding::global("summer"), builder.coroutine(function));
+
+        let summer = builder.var(Binding::global("summer"));
+        builder.emit(builder.resume(summer.clone(), vec![]));
+        builder.emit(builder.resume(summer.clone(), vec![builder.number(5.0)]));
+        builder.bind(Binding::global("total"), builder.resume(summer, vec![builder.number(10.0)]));
+
+        // Closures made inside a coroutine keep seeing its locals while it's suspended
+        let maker = builder.function(Binding::global("maker"), &[], |builder| {
+            builder.bind(Binding::local("x", 1, 1), builder.number(1.0));
+
+            let get = builder.function(Binding::local("get", 1, 1), &[], |builder| {
+                builder.ret(Some(builder.var(Binding::local("x", 2, 1))))
+            });
+            builder.emit(get);
+
+            builder.emit(builder.yield_(builder.var(Binding::local("get", 1, 1))));
+            builder.mutate(builder.var(Binding::local("x", 1, 1)), builder.number(2.0));
+            builder.emit(builder.yield_(builder.nil()));
+        });
+        builder.emit(maker);
+
+        let function = builder.var(Binding::global("maker"));
+        builder.bind(Binding::global("made"), builder.coroutine(function));
+
+        let made = builder.var(Binding::global("made"));
+        builder.bind(Binding::global("get"), builder.resume(made.clone(), vec![]));
+        builder.bind(Binding::global("before"), builder.call(builder.var(Binding::global("get")), vec![], None));
+        builder.emit(builder.resume(made, vec![]));
+        builder.bind(Binding::global("after"), builder.call(builder.var(Binding::global("get")), vec![], None));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let string = |vm: &VM, name: &str| String::from_value(vm.globals[name], &vm.heap).unwrap();
+
+        assert_eq!(vm.globals["first"], Value::float(2.0));
+        assert_eq!(vm.globals["second"], Value::float(1.0));
+        assert_eq!(string(&vm, "last"), "liftoff");
+        assert_eq!(string(&vm, "status"), "dead");
+        assert_eq!(vm.status_of(vm.globals["co"]), Some(CoroutineStatus::Dead));
+        assert_eq!(vm.globals["total"], Value::float(15.0));
+        assert_eq!(vm.status_of(vm.globals["summer"]), Some(CoroutineStatus::Suspended));
+        assert_eq!(vm.globals["before"], Value::float(1.0));
+        assert_eq!(vm.globals["after"], Value::float(2.0));
+
+        // Coroutines can be resumed from Rust too
+        let summer = vm.globals["summer"];
+        assert_eq!(vm.call_value(summer, &[Value::float(1.0)]).unwrap(), Value::float(16.0));
+
+        let co = vm.globals["co"];
+        let err = vm.call_value(co, &[]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::Coroutine);
+        assert_eq!(err.message, "can't resume a dead coroutine");
+
+        // Errors a coroutine doesn't handle kill it, and reach whoever resumed it
+        let mut builder = IrBuilder::new();
+
+        let broken = builder.function(Binding::global("broken"), &[], |builder| {
+            builder.emit(builder.yield_(builder.nil()));
+            builder.throw(builder.string("oops"));
+        });
+        builder.emit(broken);
+
+        let function = builder.var(Binding::global("broken"));
+        builder.bind(Binding::global("broken_co"), builder.coroutine(function));
+
+        let body = builder.block(|builder| {
+            let co = builder.var(Binding::global("broken_co"));
+            builder.emit(builder.resume(co.clone(), vec![]));
+            builder.emit(builder.resume(co, vec![]));
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("caught"), builder.var(Binding::local("e", 0, 0)));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(string(&vm, "caught"), "oops");
+        assert_eq!(vm.status_of(vm.globals["broken_co"]), Some(CoroutineStatus::Dead));
+
+        let mut builder = IrBuilder::new();
+        builder.emit(builder.yield_(builder.nil()));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().message, "can't yield outside of a coroutine");
+    }
+
+    #[test]
+    fn bytecode() {
+        let mut builder = IrBuilder::new();
+
+        let adder = builder.function(Binding::global("adder"), &["x"], |builder| {
+            let add = builder.function(Binding::local("add", 1, 1), &["y"], |builder| {
+                let x = builder.var(Binding::local("x", 2, 1));
+                let y = builder.var(Binding::local("y", 2, 2));
+
+                builder.ret(Some(builder.binary(x, BinaryOp::Add, y)))
+            });
+            builder.emit(add);
+
+            builder.ret(Some(builder.var(Binding::local("add", 1, 1))))
+        });
+        builder.emit(adder);
+
+        let adder = builder.var(Binding::global("adder"));
+        let add_ten = builder.call(adder, vec![builder.number(10.0)], None);
+        builder.bind(Binding::global("sum"), builder.call(add_ten, vec![builder.number(5.0)], None));
+
+        let greeting = builder.binary(builder.string("hello, "), BinaryOp::Add, builder.string("world"));
+        builder.bind(Binding::global("greeting"), greeting);
+
+        let mut bytes = Vec::new();
+
+        let mut compiling = VM::new();
+        let function = compiling.compile(&builder.build()).unwrap();
+        function.write_to(&mut bytes).unwrap();
+
+        let mut vm = VM::new();
+        let function = vm.load_bytecode(&mut &bytes[..]).unwrap();
+        vm.exec_function(function, false).unwrap();
+
+        assert_eq!(vm.globals["sum"], Value::float(15.0));
+        assert_eq!(String::from_value(vm.globals["greeting"], &vm.heap).unwrap(), "hello, world");
+
+        // Broken input is rejected without taking the VM down
+        let error = |bytes: &[u8]| VM::new().load_bytecode(&mut &bytes[..]).unwrap_err().to_string();
+
+        assert_eq!(error(b"nope, not bytecode"), "not zub bytecode");
+        assert_eq!(error(b"ZUBC\x07\x00"), "unsupported bytecode version 7, expected 6 or older");
+        assert_eq!(error(&[&bytes[..], &[0]].concat()), "malformed bytecode: trailing bytes after function");
+
+        for len in 0..bytes.len() {
+            assert!(VM::new().load_bytecode(&mut &bytes[..len]).is_err());
+        }
+
+        let mut huge = bytes[..6].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(error(&huge), "malformed bytecode: unexpected end of input");
+    }
+
+    #[test]
+    fn verifier() {
+        let heap = Heap::default();
+
+        let function = |build: &dyn Fn(&mut Chunk)| {
+            let mut function = FunctionBuilder::new("broken", 0);
+            build(function.chunk_mut());
+            function.build()
+        };
+
+        let errors = |build: &dyn Fn(&mut Chunk)| {
+            Verifier::new(&function(build), &heap).verify()
+                .into_iter()
+                .filter(Diagnostic::is_error)
+                .map(|d| d.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(errors(&|c| { c.write(Op::Nil, 1); c.write(Op::Return, 1) }).is_empty());
+
+        assert_eq!(errors(&|c| c.write_byte(0xee)), vec!["unknown opcode 0xee"]);
+
+        assert_eq!(
+            errors(&|c| { c.write(Op::Jump, 1); c.write_byte(1); c.write_byte(0); c.write(Op::Nil, 1); c.write(Op::Return, 1) }),
+            vec!["jump to 1, which isn't the start of an instruction"]
+        );
+
+        assert_eq!(
+            errors(&|c| { c.write(Op::Constant(3), 1); c.write(Op::Return, 1) }),
+            vec!["constant 3 is out of range"]
+        );
+
+        assert_eq!(
+            errors(&|c| { c.write(Op::GetLocal, 1); c.write_byte(5); c.write(Op::Return, 1) }),
+            vec!["local slot 5 is out of range, the stack only holds 1"]
+        );
+
+        assert_eq!(
+            errors(&|c| { c.write(Op::Pop, 1); c.write(Op::Pop, 1); c.write(Op::Nil, 1); c.write(Op::Return, 1) }),
+            vec!["pops 1 value(s) from a stack of 0"]
+        );
+
+        assert_eq!(errors(&|c| c.write(Op::Nil, 1)), vec!["execution runs off the end of the chunk"]);
+        assert_eq!(errors(&|c| c.write(Op::Jump, 1)), vec!["instruction runs past the end of the chunk"]);
+
+        // Loaded bytecode has to pass
+        let mut bytes = Vec::new();
+
+        function(&|c| { c.write(Op::GetLocal, 1); c.write_byte(5); c.write(Op::Return, 1) })
+            .write_to(&mut bytes)
+            .unwrap();
+
+        match VM::new().load_bytecode(&mut &bytes[..]) {
+            Err(BytecodeError::Invalid(diagnostics)) => assert_eq!(diagnostics.len(), 1),
+            _ => panic!("expected the verifier to reject the function"),
+        }
+
+        // Immediates can't smuggle in object handles
+        let forged = |c: &mut Chunk| {
+            c.write(Op::Immediate, 1);
+            c.write_u64(0xfffc000000001000);
+            c.write(Op::AddConst, 1);
+            c.write_u64(0xfffc000000001000);
+            c.write(Op::Return, 1)
+        };
+
+        assert_eq!(
+            errors(&forged),
+            vec!["immediate 0xfffc000000001000 is an object handle", "immediate 0xfffc000000001000 is an object handle"]
+        );
+
+        let mut bytes = Vec::new();
+        function(&forged).write_to(&mut bytes).unwrap();
+
+        match VM::new().load_bytecode(&mut &bytes[..]) {
+            Err(BytecodeError::Invalid(diagnostics)) => assert_eq!(diagnostics.len(), 2),
+            _ => panic!("expected the verifier to reject the forged handles"),
+        }
+
+        // What's on the stack isn't checked, so ops getting the wrong kind of value fail when they run
+        let run = |build: &dyn Fn(&mut Chunk, u16)| {
+            let mut compiling = VM::new();
+            let mut function = FunctionBuilder::new("broken", 0);
+            let name = function.chunk_mut().string_constant(&mut compiling.heap, "Broken").unwrap();
+
+            build(function.chunk_mut(), name);
+
+            let mut bytes = Vec::new();
+            function.build().write_to(&mut bytes).unwrap();
+
+            let mut vm = VM::new();
+            let function = vm.load_bytecode(&mut &bytes[..]).unwrap();
+            let err = vm.exec_function(function, false).unwrap_err();
+
+            (err.kind, err.message)
+        };
+
+        assert_eq!(
+            run(&|c, name| { c.write(Op::Nil, 1); c.write(Op::Class, 1); c.write_byte(name as u8); c.write_byte(1); c.write(Op::Return, 1) }),
+            (RuntimeErrorKind::Type, "class methods must be functions, got nil".into())
+        );
+
+        assert_eq!(
+            run(&|c, name| {
+                c.write(Op::Class, 1); c.write_byte(name as u8); c.write_byte(0);
+                c.write(Op::Nil, 1); c.write(Op::Inherit, 1); c.write(Op::Return, 1)
+            }),
+            (RuntimeErrorKind::InvalidOp, "can't inherit into value of type nil".into())
+        );
+
+        assert_eq!(
+            run(&|c, name| { c.write(Op::Nil, 1); c.write(Op::Nil, 1); c.write(Op::GetSuper, 1); c.write_byte(name as u8); c.write(Op::Return, 1) }),
+            (RuntimeErrorKind::InvalidOp, "can't look up `super` method on value of type nil".into())
+        );
+    }
+
+    #[test]
+    fn peephole() {
+        let heap = Heap::default();
+
+        let number = |c: &mut Chunk, n: f64, line| { c.write(Op::Immediate, line); c.write_u64(Value::float(n).to_raw()) };
+        let jump = |c: &mut Chunk, op, target: u16, line| { c.write(op, line); c.write_u16(target) };
+
+        let mut function = FunctionBuilder::new("f", 1);
+        let c = function.chunk_mut();
+
+        number(c, 1.0, 1);
+        c.write(Op::Pop, 1);
+        c.write(Op::GetLocal, 2);
+        c.write_byte(1);
+        number(c, 2.0, 2);
+        c.write(Op::Less, 2);
+        c.write(Op::Not, 2);
+        jump(c, Op::JumpIfFalse, 40, 2);
+        c.write(Op::Pop, 3);
+        c.write(Op::GetLocal, 3);
+        c.write_byte(1);
+        number(c, 1.0, 3);
+        c.write(Op::Add, 3);
+        c.write(Op::Return, 3);
+        c.write(Op::Pop, 4);
+        jump(c, Op::Jump, 44, 4);
+        c.write(Op::False, 5);
+        c.write(Op::Return, 5);
+
+        let function = peephole::optimize(function.build(), &heap);
+
+        let mut expected = FunctionBuilder::new("f", 1);
+        let c = expected.chunk_mut();
+
+        c.write(Op::GetLocal1, 2);
+        number(c, 2.0, 2);
+        c.write(Op::GreaterEqual, 2);
+        jump(c, Op::JumpIfFalsePop, 29, 2);
+        c.write(Op::GetLocal1, 3);
+        c.write(Op::AddConst, 3);
+        c.write_u64(Value::float(1.0).to_raw());
+        c.write(Op::Return, 3);
+        c.write(Op::Pop, 4);
+        jump(c, Op::Jump, 29, 4);
+        c.write(Op::False, 5);
+        c.write(Op::Return, 5);
+
+        let chunk = function.chunk();
+
+        assert_eq!(chunk.as_ref(), expected.chunk.as_ref());
+        assert_eq!([0, 13, 14, 25, 29].iter().map(|&offset| chunk.line(offset)).collect::<Vec<_>>(), vec![2, 2, 3, 4, 5]);
+        assert!(Verifier::new(&function, &heap).verify().is_empty());
+
+        // Counting the numbers from 5 to 10 but 7, through every fused comparison
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("i"), builder.number(0.0));
+        builder.bind(Binding::global("count"), builder.number(0.0));
+
+        let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::LtEqual, builder.number(10.0));
+        let loop_ = builder.while_(cond, |builder| {
+            let i = builder.var(Binding::global("i"));
+            let wanted = builder.binary(
+                builder.binary(i.clone(), BinaryOp::GtEqual, builder.number(5.0)),
+                BinaryOp::And,
+                builder.binary(i.clone(), BinaryOp::NEqual, builder.number(7.0)),
+            );
+
+            let count = builder.if_(wanted, |builder| {
+                let count = builder.var(Binding::global("count"));
+                builder.mutate(count.clone(), builder.binary(count, BinaryOp::Add, builder.number(1.0)))
+            }, None);
+            builder.emit(count);
+
+            builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+        });
+        builder.emit(loop_);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["count"], Value::float(5.0));
+
+        // A loop body ending in an `if` still jumps back through a Loop, where interrupts are checked
+        let mut builder = IrBuilder::new();
+
+        let loop_ = builder.while_(builder.bool(true), |builder| {
+            let then = builder.if_(builder.bool(false), |builder| builder.emit(builder.number(1.0)), None);
+            builder.emit(then)
+        });
+        builder.emit(loop_);
+
+        let function = vm.compile(&builder.build()).unwrap();
+        let spans = Verifier::new(&function, &vm.heap).spans().unwrap();
+        let code = function.chunk().as_ref();
+
+        let mut back = spans.iter().filter(|span| span.target.is_some_and(|target| target <= span.start)).peekable();
+
+        assert!(back.peek().is_some());
+        assert!(back.all(|span| matches!(code[span.start], 0x20 | 0x4b)));
+    }
+
+    #[test]
+    fn dispatch() {
+        // Summing in locals stays in the fast path, the string at the end leaves it
+        let mut builder = IrBuilder::new();
+
+        let sum = builder.function(Binding::global("sum"), &[], |builder| {
+            builder.bind(Binding::local("i", 1, 1), builder.number(0.0));
+            builder.bind(Binding::local("total", 1, 1), builder.number(0.0));
+
+            let cond = builder.binary(builder.var(Binding::local("i", 1, 1)), BinaryOp::Lt, builder.number(10000.0));
+            let body = builder.while_(cond, |builder| {
+                let i = builder.var(Binding::local("i", 1, 1));
+                let total = builder.var(Binding::local("total", 1, 1));
+
+                builder.mutate(total.clone(), builder.binary(total, BinaryOp::Add, i.clone()));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()))
+            });
+            builder.emit(body);
+
+            let total = builder.var(Binding::local("total", 1, 1));
+            builder.ret(Some(builder.binary(builder.string("total: "), BinaryOp::Add, total)))
+        });
+        builder.emit(sum);
+
+        builder.bind(Binding::global("result"), builder.call(builder.var(Binding::global("sum")), vec![], None));
+
+        let nan = builder.binary(builder.number(0.0), BinaryOp::Div, builder.number(0.0));
+        builder.bind(Binding::global("nan"), nan);
+        builder.bind(Binding::global("at_least"), builder.binary(builder.var(Binding::global("nan")), BinaryOp::GtEqual, builder.number(1.0)));
+        builder.bind(Binding::global("less"), builder.binary(builder.var(Binding::global("nan")), BinaryOp::Lt, builder.number(1.0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(String::from_value(vm.globals["result"], &vm.heap).unwrap(), "total: 49995000");
+        assert_eq!(vm.globals["at_least"], Value::truelit());
+        assert_eq!(vm.globals["less"], Value::falselit());
+
+        // Values left behind every time around still run into the end of the stack
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("i"), builder.number(0.0));
+
+        let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(5000.0));
+        let body = builder.while_(cond, |builder| {
+            let i = builder.var(Binding::global("i"));
+            builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+        });
+        builder.emit(body);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::StackOverflow);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn profiling() {
+        let mut builder = IrBuilder::new();
+
+        let square = builder.function(Binding::global("square"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            builder.ret(Some(builder.binary(n.clone(), BinaryOp::Mul, n)))
+        });
+        builder.emit(square);
+
+        let call = builder.call(builder.var(Binding::global("square")), vec![builder.number(4.0)], None);
+        builder.bind(Binding::global("result"), call);
+
+        let path = std::env::temp_dir().join(format!("zub-profile-{}.folded", std::process::id()));
+
+        let mut vm = VM::new();
+        vm.profile = Some(ProfileConfig::new(&path, ProfileFormat::Folded));
+        vm.exec(&builder.build(), false).unwrap();
+        vm.write_profile().unwrap();
+
+        let folded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let stacks = folded.lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect::<Vec<_>>();
+
+        // Calls nest under their caller, with the VM methods they ran into below them
+        assert!(stacks.contains(&"<zub>"));
+        assert!(stacks.contains(&"<zub>;square"));
+        assert!(stacks.iter().any(|stack| stack.starts_with("<zub>;square;")));
+    }
+
+    #[test]
+    fn profiler() {
+        let mut builder = IrBuilder::new();
+
+        builder.set_line(1);
+
+        let countdown = builder.function(Binding::global("countdown"), &["n"], |builder| {
+            builder.set_line(2);
+
+            let n = builder.var(Binding::local("n", 1, 1));
+            let next = builder.call(builder.var(Binding::global("countdown")), vec![builder.binary(n.clone(), BinaryOp::Sub, builder.number(1.0))], None);
+            let result = builder.ternary(builder.binary(n, BinaryOp::Gt, builder.number(0.0)), next, Some(builder.number(0.0)));
+
+            builder.ret(Some(result))
+        });
+        builder.emit(countdown);
+
+        builder.set_line(4);
+        builder.bind(Binding::global("done"), builder.call(builder.var(Binding::global("countdown")), vec![builder.number(5.0)], None));
+
+        let mut vm = VM::new();
+        vm.profiler = Some(Profiler::new());
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["done"], Value::float(0.0));
+
+        let report = vm.profiler.take().unwrap().report();
+
+        let script = report.function("<zub>").unwrap();
+        let countdown = report.function("countdown").unwrap();
+
+        assert_eq!(countdown.calls, 6);
+        assert_eq!(script.inclusive, report.total);
+        assert_eq!(countdown.inclusive, countdown.exclusive);
+        assert_eq!(script.exclusive + countdown.exclusive, report.total);
+
+        assert_eq!((report.lines[0].function.as_str(), report.lines[0].line), ("countdown", 2));
+        assert_eq!(report.lines.iter().map(|line| line.instructions).sum::<u64>(), report.total);
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+
+        assert!(folded.lines().any(|line| line.starts_with("<zub>;countdown;countdown;countdown;countdown;countdown;countdown ")));
+        assert!(report.to_string().starts_with("function"));
+
+        // Nothing is counted once it's taken off again
+        vm.exec(&builder.build(), false).unwrap();
+        assert!(vm.profiler.is_none());
+    }
+
+    #[test]
+    fn incremental_gc() {
+        use std::collections::HashMap;
+
+        // An object that's already been traced and then has something stored in it has to be traced again
+        let mut heap = Heap::default();
+
+        let list = heap.insert_temp(Object::List(List::new(Vec::new())));
+        let late = heap.insert_temp(Object::String("late".to_string()));
+        let orphan = heap.insert_temp(Object::String("orphan".to_string()));
+
+        assert!(!heap.step(1, vec![list]));
+        assert_eq!(heap.phase(), Phase::Mark);
+
+        if let Some(Object::List(list)) = heap.get_mut(list) {
+            list.content.push(late.into())
+        }
+
+        while !heap.step(1, vec![list]) {}
+
+        assert!(heap.contains(list) && heap.contains(late));
+        assert!(!heap.contains(orphan));
+        assert_eq!((heap.stats().collections, heap.stats().freed), (1, 1));
+
+        // Strings kept in a closed upvalue and a dict, with one object traced or swept per allocation
+        let mut builder = IrBuilder::new();
+
+        let make = builder.function(Binding::global("make"), &[], |builder| {
+            builder.bind(Binding::local("text", 1, 1), builder.string(""));
+
+            let append = builder.function(Binding::local("append", 1, 1), &["s"], |builder| {
+                let text = builder.var(Binding::local("text", 2, 1));
+                let s = builder.var(Binding::local("s", 2, 2));
+
+                builder.mutate(text.clone(), builder.binary(text.clone(), BinaryOp::Add, s));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+                builder.ret(Some(text))
+            });
+            builder.emit(append);
+
+            builder.ret(Some(builder.var(Binding::local("append", 1, 1))))
+        });
+        builder.emit(make);
+
+        builder.bind(Binding::global("append"), builder.call(builder.var(Binding::global("make")), vec![], None));
+        builder.bind(Binding::global("seen"), builder.dict(vec![builder.string("last")], vec![builder.nil()]));
+        builder.bind(Binding::global("i"), builder.number(0.0));
+
+        let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(2000.0));
+        let body = builder.while_(cond, |builder| {
+            let i = builder.var(Binding::global("i"));
+            let text = builder.call(builder.var(Binding::global("append")), vec![builder.string("x")], None);
+
+            let set = builder.set_element(builder.var(Binding::global("seen")), builder.string("last"), text);
+            builder.emit(set);
+
+            builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)));
+            builder.emit(Expr::Pop.node(TypeInfo::nil()))
+        });
+        builder.emit(body);
+
+        builder.bind(Binding::global("text"), builder.call(builder.var(Binding::global("append")), vec![builder.string("!")], None));
+
+        let mut vm = VM::new_with(GcConfig { threshold: 16 * 1024, step: 1, ..GcConfig::default() });
+        vm.exec(&builder.build(), false).unwrap();
+
+        let text = String::from_value(vm.globals["text"], &vm.heap).unwrap();
+        assert_eq!(text, format!("{}!", "x".repeat(2000)));
+
+        let seen = HashMap::<String, String>::from_value(vm.globals["seen"], &vm.heap).unwrap();
+        assert_eq!(seen["last"].len(), 2000);
+
+        let stats = vm.heap.stats();
+        assert!(stats.collections > 0 && stats.freed > 1000);
+
+        // Only the end of marking goes over budget, tracing the closure and dict again
+        assert!(stats.longest_step < 8);
+    }
+
+    #[test]
+    fn gc_config() {
+        let mut vm = VM::new_with(GcConfig { threshold: 64 * 1024, growth: 1.5, step: 0, ..GcConfig::default() });
+
+        // Strings are counted with what they hold
+        let before = vm.heap.bytes();
+        vm.globals.insert("big".to_string(), "x".repeat(100_000).into_value(&mut vm.heap));
+        vm.globals.insert("kept".to_string(), vec!["a", "b"].into_value(&mut vm.heap));
+        assert!(vm.heap.bytes() >= before + 100_000);
+
+        vm.globals.remove("big");
+        vm.collect();
+
+        let stats = vm.heap.stats();
+        assert_eq!((stats.collections, stats.freed), (1, 1));
+        assert!(stats.freed_bytes >= 100_000);
+        assert!(stats.live_bytes < 64 * 1024);
+        assert_eq!(stats.live_bytes, vm.heap.bytes());
+        assert!(stats.pause > std::time::Duration::from_secs(0) && stats.longest_pause <= stats.pause);
+
+        assert_eq!(Vec::<String>::from_value(vm.globals["kept"], &vm.heap).unwrap(), vec!["a", "b"]);
+
+        // Allocating past the threshold collects without being asked to
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("text"), builder.string(""));
+        builder.bind(Binding::global("i"), builder.number(0.0));
+
+        let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(500.0));
+        let body = builder.while_(cond, |builder| {
+            let text = builder.var(Binding::global("text"));
+            let i = builder.var(Binding::global("i"));
+
+            builder.mutate(text.clone(), builder.binary(text, BinaryOp::Add, builder.string("0123456789")));
+            builder.emit(Expr::Pop.node(TypeInfo::nil()));
+            builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)));
+            builder.emit(Expr::Pop.node(TypeInfo::nil()))
+        });
+        builder.emit(body);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.heap.stats().collections > 1);
+        assert_eq!(String::from_value(vm.globals["text"], &vm.heap).unwrap().len(), 5000);
+    }
+
+    #[test]
+    fn gc_roots() {
+        let stress = GcConfig { stress: true, ..GcConfig::default() };
+
+        // Constants of a function compiled ahead of time outlive collections until it runs
+        let mut builder = IrBuilder::new();
+
+        let greet = builder.function(Binding::global("greet"), &["name"], |builder| {
+            let name = builder.var(Binding::local("name", 1, 1));
+            builder.ret(Some(builder.binary(builder.string("hello, "), BinaryOp::Add, name)))
+        });
+        builder.emit(greet);
+
+        let call = builder.call(builder.var(Binding::global("greet")), vec![builder.string("world")], None);
+        builder.bind(Binding::global("greeting"), call);
+
+        let mut vm = VM::new_with(stress);
+        let function = vm.compile(&builder.build()).unwrap();
+
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("other"), builder.list(vec![builder.string("a"), builder.string("b")]));
+
+        vm.exec(&builder.build(), false).unwrap();
+        vm.collect();
+
+        vm.exec_function(function, false).unwrap();
+        assert_eq!(String::from_value(vm.globals["greeting"], &vm.heap).unwrap(), "hello, world");
+
+        // Natives allocating more than once keep what they made so far rooted
+        vm.add_native("pair", |context: &mut NativeContext, args: &[Value]| {
+            let first = context.into_value(format!("{}!", context.from_value::<String>(args[0])?));
+            let first = context.heap_mut().root(first);
+
+            let second = context.allocate(Object::String("?".to_string()));
+            assert!(context.heap().contains(first.value().as_object().unwrap()));
+
+            Ok(context.allocate(Object::List(List::new(vec![first.value(), second]))))
+        }, 1);
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("pair")), vec![builder.string("hey")], None);
+        builder.bind(Binding::global("pair"), call);
+
+        vm.exec(&builder.build(), false).unwrap();
+        vm.collect();
+
+        assert_eq!(Vec::<String>::from_value(vm.globals["pair"], &vm.heap).unwrap(), vec!["hey!", "?"]);
+
+        // Dropping the guard lets go again
+        let lonely = "lonely".into_value(&mut vm.heap);
+        let root = vm.heap.root(lonely);
+
+        vm.collect();
+        assert!(vm.heap.contains(root.value().as_object().unwrap()));
+
+        drop(root);
+        vm.collect();
+        assert!(!vm.heap.contains(lonely.as_object().unwrap()));
+    }
+
+    #[test]
+    fn limits() {
+        // Counts to 1000, inside a `try` that mustn't get to see running out
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("i"), builder.number(0.0));
+
+        let body = builder.block(|builder| {
+            let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(1000.0));
+            let count = builder.while_(cond, |builder| {
+                let i = builder.var(Binding::global("i"));
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()))
+            });
+            builder.emit(count);
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("caught"), builder.bool(true));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        let count = builder.build();
+
+        let mut vm = VM::new();
+        vm.limits.fuel = Some(100);
+
+        let err = vm.exec(&count, false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::OutOfFuel);
+        assert!(vm.is_suspended());
+
+        let mut stops = 1;
+
+        loop {
+            vm.refuel(100);
+
+            match vm.resume() {
+                Ok(_) => break,
+                Err(err) => assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel),
+            }
+
+            stops += 1;
+        }
+
+        assert!(stops > 10);
+        assert_eq!(vm.globals["i"], Value::float(1000.0));
+        assert!(!vm.globals.contains_key("caught"));
+        assert!(!vm.is_suspended());
+        assert_eq!(vm.resume().unwrap_err().kind, RuntimeErrorKind::InvalidOp);
+
+        // Running out under a native can't be picked up again, the native's gone
+        let mut builder = IrBuilder::new();
+
+        let spin = builder.function(Binding::global("spin"), &[], |builder| {
+            let body = builder.while_(builder.bool(true), |_| {});
+            builder.emit(body);
+        });
+        builder.emit(spin);
+        builder.emit(builder.call(builder.var(Binding::global("run")), vec![builder.var(Binding::global("spin"))], None));
+
+        vm.add_native("run", |context: &mut NativeContext, args: &[Value]| context.call(args[0], &[]), 1);
+        vm.limits.fuel = Some(1000);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::OutOfFuel);
+        assert!(!vm.is_suspended());
+        assert!(vm.frames.is_empty());
+
+        // Going over the heap, then carrying on with room to spare
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("text"), builder.string(""));
+        builder.bind(Binding::global("i"), builder.number(0.0));
+
+        let cond = builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.number(1000.0));
+        let body = builder.while_(cond, |builder| {
+            let text = builder.var(Binding::global("text"));
+            let i = builder.var(Binding::global("i"));
+
+            builder.mutate(text.clone(), builder.binary(text, BinaryOp::Add, builder.string("0123456789")));
+            builder.emit(Expr::Pop.node(TypeInfo::nil()));
+            builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)));
+            builder.emit(Expr::Pop.node(TypeInfo::nil()))
+        });
+        builder.emit(body);
+
+        let mut vm = VM::new();
+        vm.limits.heap_bytes = Some(vm.heap.bytes() + 4096);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::HeapLimit);
+
+        vm.limits.heap_bytes = None;
+        vm.resume().unwrap();
+
+        assert_eq!(String::from_value(vm.globals["text"], &vm.heap).unwrap().len(), 10000);
+
+        // ... and strings and lists too long, calls too deep and stacks too full are plain errors
+        vm.limits.length = Some(100);
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::LengthLimit);
+        assert!(!vm.is_suspended());
+
+        vm.limits.length = Some(2);
+
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("list"), builder.list(vec![builder.number(1.0), builder.number(2.0), builder.number(3.0)]));
+
+        let err = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::LengthLimit);
+
+        let mut builder = IrBuilder::new();
+
+        let down = builder.function(Binding::global("down"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let down = builder.var(Binding::global("down"));
+
+            let next = builder.call(down, vec![builder.binary(n.clone(), BinaryOp::Sub, builder.number(1.0))], None);
+            let result = builder.ternary(builder.binary(n, BinaryOp::Lt, builder.number(1.0)), builder.number(0.0), Some(next));
+
+            builder.ret(Some(result))
+        });
+        builder.emit(down);
+        builder.bind(Binding::global("bottom"), builder.call(builder.var(Binding::global("down")), vec![builder.number(50.0)], None));
+
+        let down = builder.build();
+
+        vm.limits.frames = Some(20);
+
+        let err = vm.exec(&down, false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::CallDepth);
+
+        vm.limits.frames = None;
+        vm.limits.stack = 64;
+
+        let err = vm.exec(&down, false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::StackOverflow);
+
+        vm.limits.stack = 256;
+        vm.exec(&down, false).unwrap();
+
+        assert_eq!(vm.globals["bottom"], Value::float(0.0));
+    }
+
+    #[test]
+    fn interrupts() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // Spins forever in a `try`, which doesn't get to catch being stopped
+        let mut builder = IrBuilder::new();
+
+        let body = builder.block(|builder| {
+            let spin = builder.while_(builder.bool(true), |_| {});
+            builder.emit(spin);
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("caught"), builder.bool(true));
+        });
+        let try_ = builder.try_(body, Some((Binding::local("e", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        let spin = builder.build();
+
+        let mut vm = VM::new();
+        let handle = vm.interrupt_handle();
+
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.interrupt()
+        });
+
+        let err = vm.exec(&spin, false).unwrap_err();
+        stopper.join().unwrap();
+
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::Interrupted);
+        assert!(!vm.globals.contains_key("caught"));
+        assert!(!vm.interrupt_handle().is_interrupted());
+
+        // So does a loop whose body always takes the first arm of an if/else, jumping back from its end
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("on"), builder.bool(true));
+
+        let loop_ = builder.while_(builder.bool(true), |builder| {
+            let on = builder.var(Binding::global("on"));
+            let branch = builder.if_(on, |builder| {
+                let on = builder.var(Binding::global("on"));
+                builder.mutate(on, builder.bool(true));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+            }, Some(|builder| {
+                let on = builder.var(Binding::global("on"));
+                builder.mutate(on, builder.bool(false));
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+            }));
+            builder.emit(branch);
+        });
+        builder.emit(loop_);
+
+        let branching = builder.build();
+        let handle = vm.interrupt_handle();
+
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.interrupt()
+        });
+
+        let err = vm.exec(&branching, false).unwrap_err();
+        stopper.join().unwrap();
+
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::Interrupted);
+        assert!(!vm.interrupt_handle().is_interrupted());
+
+        // Interrupting ahead of time stops the next run at its first call, and only that one
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("ran"), builder.bool(true));
+
+        let ran = builder.build();
+
+        vm.interrupt_handle().interrupt();
+
+        let err = vm.exec(&ran, false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::Interrupted);
+        assert!(!vm.globals.contains_key("ran"));
+
+        vm.exec(&ran, false).unwrap();
+        assert_eq!(vm.globals["ran"], Value::truelit());
+
+        // The periodic callback runs alongside fuel, and can stop the run itself
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+
+        vm.set_periodic(100, move || {
+            counted.set(counted.get() + 1);
+            counted.get() < 5
+        });
+        vm.limits.fuel = Some(250);
+
+        let err = vm.exec(&spin, false).unwrap_err();
+        assert_eq!(err.runtime_error().unwrap().kind, RuntimeErrorKind::OutOfFuel);
+        assert_eq!(calls.get(), 2);
+
+        vm.refuel(1000);
+
+        assert_eq!(vm.resume().unwrap_err().kind, RuntimeErrorKind::Interrupted);
+        assert_eq!(calls.get(), 5);
+        assert_eq!(vm.limits.fuel, Some(750));
+        assert!(!vm.is_suspended());
+
+        // ... with the profiler counting too
+        calls.set(0);
+        vm.limits.fuel = None;
+        vm.profiler = Some(Profiler::new());
+
+        assert_eq!(vm.exec(&spin, false).unwrap_err().runtime_error().unwrap().kind, RuntimeErrorKind::Interrupted);
+        assert_eq!(calls.get(), 5);
+
+        vm.clear_periodic();
+    }
+
+    #[test]
+    fn ints() {
+        use super::ir::opt::OptLevel;
+
+        let program = || {
+            let mut builder = IrBuilder::new();
+
+            // Ints stay ints until they can't
+            builder.bind(Binding::global("sum"), builder.binary(builder.int(20), BinaryOp::Add, builder.int(22)));
+            builder.bind(Binding::global("big"), builder.binary(builder.int(i32::MAX), BinaryOp::Add, builder.int(1)));
+            builder.bind(Binding::global("mixed"), builder.binary(builder.int(1), BinaryOp::Add, builder.number(0.5)));
+
+            // `/` without types gives an int only when it divides evenly
+            builder.bind(Binding::global("seven"), builder.int(7));
+            builder.bind(Binding::global("eight"), builder.int(8));
+
+            let seven = builder.var(Binding::global("seven"));
+            let eight = builder.var(Binding::global("eight"));
+            builder.bind(Binding::global("half"), builder.binary(seven, BinaryOp::Div, builder.int(2)));
+            builder.bind(Binding::global("even"), builder.binary(eight, BinaryOp::Div, builder.int(2)));
+
+            // Both sides typed `Type::Int`, so it rounds towards zero
+            builder.bind(Binding::global("trunc"), builder.binary(builder.int(-7), BinaryOp::Div, builder.int(2)));
+
+            let bits = builder.binary(builder.int(12), BinaryOp::BitAnd, builder.int(10));
+            let bits = builder.binary(bits, BinaryOp::BitOr, builder.int(1));
+            let bits = builder.binary(bits, BinaryOp::BitXor, builder.int(3));
+            builder.bind(Binding::global("bits"), bits);
+
+            let shifted = builder.binary(builder.int(1), BinaryOp::Shl, builder.int(4));
+            builder.bind(Binding::global("shl"), shifted);
+            builder.bind(Binding::global("shr"), builder.binary(builder.int(-16), BinaryOp::Shr, builder.int(2)));
+
+            let not = IrBuilder::unary(UnaryOp::BitNot, builder.int(0)).node(TypeInfo::nil());
+            builder.bind(Binding::global("not"), not);
+
+            let same = builder.binary(builder.int(1), BinaryOp::Equal, builder.number(1.0));
+            builder.bind(Binding::global("same"), same);
+            builder.bind(Binding::global("less"), builder.binary(builder.int(1), BinaryOp::Lt, builder.number(1.5)));
+
+            let list = builder.list(vec![builder.string("a"), builder.string("b")]);
+            builder.bind(Binding::global("element"), builder.binary(list, BinaryOp::Index, builder.int(1)));
+
+            // 1 and 1.0 are the same key
+            let dict = builder.dict(vec![builder.int(1)], vec![builder.string("one")]);
+            builder.bind(Binding::global("key"), builder.binary(dict, BinaryOp::Index, builder.number(1.0)));
+
+            // Counting stays exact
+            builder.bind(Binding::global("i"), builder.int(0));
+
+            let counting = builder.while_(
+                builder.binary(builder.var(Binding::global("i")), BinaryOp::Lt, builder.int(10)),
+                |builder| {
+                    let i = builder.var(Binding::global("i"));
+                    builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.int(1)));
+                    builder.emit(Expr::Pop.node(TypeInfo::nil()));
+                },
+            );
+            builder.emit(counting);
+
+            builder.build()
+        };
+
+        let check = |vm: &VM| {
+            assert_eq!(vm.globals["sum"], Value::int(42));
+            assert_eq!(vm.globals["big"], Value::float(2147483648.0));
+            assert_eq!(vm.globals["mixed"], Value::float(1.5));
+            assert_eq!(vm.globals["half"], Value::float(3.5));
+            assert_eq!(vm.globals["even"], Value::int(4));
+            assert_eq!(vm.globals["trunc"], Value::int(-3));
+            assert_eq!(vm.globals["bits"], Value::int(10));
+            assert_eq!(vm.globals["shl"], Value::int(16));
+            assert_eq!(vm.globals["shr"], Value::int(-4));
+            assert_eq!(vm.globals["not"], Value::int(-1));
+            assert_eq!(vm.globals["same"], Value::truelit());
+            assert_eq!(vm.globals["less"], Value::truelit());
+            assert_eq!(String::from_value(vm.globals["element"], &vm.heap).unwrap(), "b");
+            assert_eq!(String::from_value(vm.globals["key"], &vm.heap).unwrap(), "one");
+            assert_eq!(vm.globals["i"], Value::int(10));
+        };
+
+        // Folding gives what the VM would
+        for level in [OptLevel::None, OptLevel::Basic, OptLevel::Full].iter() {
+            let mut vm = VM::new();
+            vm.opt_level = *level;
+            vm.exec(&program(), false).unwrap();
+
+            check(&vm);
+        }
+
+        // Ints survive bytecode
+        let mut bytes = Vec::new();
+        VM::new().compile(&program()).unwrap().write_to(&mut bytes).unwrap();
+
+        let mut vm = VM::new();
+        let function = vm.load_bytecode(&mut &bytes[..]).unwrap();
+        vm.exec_function(function, false).unwrap();
+
+        check(&vm);
+
+        // To and from Rust
+        let mut vm = VM::new();
+
+        assert_eq!(5u8.into_value(&mut vm.heap), Value::int(5));
+        assert_eq!(u64::MAX.into_value(&mut vm.heap), Value::float(u64::MAX as f64));
+        assert_eq!(i64::from_value(Value::int(-5), &vm.heap).unwrap(), -5);
+        assert_eq!(f64::from_value(Value::int(2), &vm.heap).unwrap(), 2.0);
+        assert!(u8::from_value(Value::int(-1), &vm.heap).is_err());
+
+        // Bitwise ops only take ints, and ints can't be divided by zero
+        let error = |lhs: ExprNode, op: BinaryOp, rhs: ExprNode| {
+            let mut builder = IrBuilder::new();
+            builder.bind(Binding::global("bad"), builder.binary(lhs, op, rhs));
+
+            VM::new().exec(&builder.build(), false).unwrap_err().runtime_error().unwrap().clone()
+        };
+
+        let builder = IrBuilder::new();
+
+        let err = error(builder.int(1), BinaryOp::BitAnd, builder.number(1.0));
+        assert_eq!(err.kind, RuntimeErrorKind::Type);
+        assert_eq!(err.message, "can't apply `&` to int and number");
+
+        let err = error(builder.number(1.0), BinaryOp::BitXor, builder.int(1));
+        assert_eq!(err.message, "can't apply `^` to number and int");
+
+        let err = error(builder.int(1), BinaryOp::Div, builder.int(0));
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+        assert_eq!(err.message, "can't divide int 1 by zero");
+
+        // Floats still divide by zero as they always did
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("inf"), builder.binary(builder.number(1.0), BinaryOp::Div, builder.int(0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["inf"], Value::float(f64::INFINITY));
+    }
+
+    #[test]
+    fn wide_ops() {
+        let mut builder = IrBuilder::new();
+
+        // More constants than a byte can index
+        for i in 0..300 {
+            builder.bind(Binding::global(&format!("g{}", i)), builder.string(&format!("s{}", i)));
+        }
+
+        builder.bind(Binding::global("last"), builder.var(Binding::global("g299")));
+
+        // More arguments than `Call` has ops for
+        let params = (0..12).map(|i| format!("p{}", i)).collect::<Vec<_>>();
+        let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let many = builder.function(Binding::global("many"), &params, |builder| {
+            let first = builder.var(Binding::local("p0", 1, 1));
+            let last = builder.var(Binding::local("p11", 1, 1));
+
+            builder.ret(Some(builder.binary(first, BinaryOp::Add, last)))
+        });
+        builder.emit(many);
+
+        let args = (1..=12).map(|i| builder.number(i as f64)).collect();
+        builder.bind(Binding::global("args"), builder.call(builder.var(Binding::global("many")), args, None));
+
+        // More locals than a byte can index, captured or not
+        let deep = builder.function(Binding::global("deep"), &[], |builder| {
+            for i in 0..300 {
+                builder.bind(Binding::local(&format!("l{}", i), 1, 1), builder.number(i as f64));
+            }
+
+            let get = builder.function(Binding::local("get", 1, 1), &[], |builder| {
+                builder.ret(Some(builder.var(Binding::local("l299", 2, 1))))
+            });
+            builder.emit(get);
+
+            let got = builder.call(builder.var(Binding::local("get", 1, 1)), vec![], None);
+            let local = builder.var(Binding::local("l298", 1, 1));
+
+            builder.ret(Some(builder.binary(got, BinaryOp::Add, local)))
+        });
+        builder.emit(deep);
+
+        builder.bind(Binding::global("locals"), builder.call(builder.var(Binding::global("deep")), vec![], None));
+
+        // More elements than a byte can count
+        let list = builder.list((0..300).map(|i| builder.int(i)).collect());
+        builder.bind(Binding::global("list"), list);
+
+        let keys = (0..300).map(|i| builder.int(i)).collect();
+        let values = (0..300).map(|i| builder.int(i * 2)).collect();
+        builder.bind(Binding::global("dict"), builder.dict(keys, values));
+
+        // Runs the same straight away and after a trip through the verifier
+        let mut compiling = VM::new();
+        let function = compiling.compile(&builder.build()).unwrap();
+
+        let mut bytes = Vec::new();
+        function.write_to(&mut bytes).unwrap();
+
+        compiling.exec_function(function, false).unwrap();
+
+        let mut vm = VM::new();
+        let function = vm.load_bytecode(&mut &bytes[..]).unwrap();
+        vm.exec_function(function, false).unwrap();
+
+        for vm in [&compiling, &vm].iter() {
+            assert_eq!(String::from_value(vm.globals["last"], &vm.heap).unwrap(), "s299");
+            assert_eq!(vm.globals["args"], Value::float(13.0));
+            assert_eq!(vm.globals["locals"], Value::float(597.0));
+
+            let list = Vec::<i32>::from_value(vm.globals["list"], &vm.heap).unwrap();
+            assert_eq!(list, (0..300).collect::<Vec<_>>());
+
+            let dict = vm.heap.get(vm.globals["dict"].as_object().unwrap()).unwrap().as_dict().unwrap();
+            assert_eq!(dict.content.len(), 300);
+
+            assert!(vm.stack.is_empty());
+        }
+
+        // ... but not more than a u16 can
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("huge"), builder.list(vec![builder.nil(); 70_000]));
+
+        assert_eq!(
+            VM::new().compile(&builder.build()).unwrap_err(),
+            vec![CompileError::TooManyElements { count: 70_000, line: 0 }]
+        );
+    }
+
+    #[test]
+    fn long_jumps() {
+        // Every binding is 11 bytes of code, enough of them put the end of a block past 64 KiB
+        fn filler(builder: &mut IrBuilder) {
+            for i in 0..7000 {
+                builder.bind(Binding::global("filler"), builder.number(i as f64))
+            }
+        }
+
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("n"), builder.number(0.0));
+
+        let body = builder.block(|builder| {
+            filler(builder);
+            builder.throw(builder.string("far"))
+        });
+        let handler = builder.block(|builder| {
+            builder.bind(Binding::global("caught"), builder.var(Binding::local("err", 0, 0)))
+        });
+        let try_ = builder.try_(body, Some((Binding::local("err", 0, 0), handler)), None);
+        builder.emit(try_);
+
+        let count = builder.function(Binding::global("count"), &[], |builder| {
+            let n = builder.var(Binding::global("n"));
+            let cond = builder.binary(n, BinaryOp::Lt, builder.number(3.0));
+
+            let body = builder.while_(cond, |builder| {
+                filler(builder);
+
+                let n = builder.var(Binding::global("n"));
+                let next = builder.binary(n.clone(), BinaryOp::Add, builder.number(1.0));
+                builder.mutate(n, next)
+            });
+            builder.emit(body)
+        });
+        builder.emit(count);
+        builder.emit(builder.call(builder.var(Binding::global("count")), vec![], None));
+
+        let cond = builder.binary(builder.var(Binding::global("n")), BinaryOp::Equal, builder.number(3.0));
+        let check = builder.if_(
+            cond,
+            |builder| {
+                filler(builder);
+                builder.bind(Binding::global("counted"), builder.bool(true))
+            },
+            Some(|builder| builder.bind(Binding::global("counted"), builder.bool(false)))
+        );
+        builder.emit(check);
+
+
+        let mut compiling = VM::new();
+        let function = compiling.compile(&builder.build()).unwrap();
+
+        let mut bytes = Vec::new();
+        function.write_to(&mut bytes).unwrap();
+
+        compiling.exec_function(function, false).unwrap();
+
+        let mut vm = VM::new();
+        let function = vm.load_bytecode(&mut &bytes[..]).unwrap();
+        vm.exec_function(function, false).unwrap();
+
+        for vm in [&compiling, &vm].iter() {
+            assert_eq!(vm.globals["n"], Value::float(3.0));
+            assert_eq!(vm.globals["counted"], Value::truelit());
+            assert_eq!(String::from_value(vm.globals["caught"], &vm.heap).unwrap(), "far");
+        }
+    }
+
+    #[test]
+    fn optimizer() {
+        use super::ir::opt::*;
+
+        let mut builder = IrBuilder::new();
+
+        let run = |pass: &mut dyn Pass, mut program: Vec<ExprNode>| {
+            pass.run(&mut program);
+            program
+        };
+
+        // Folding, leaving type errors for the VM
+        let product = builder.binary(builder.number(2.0), BinaryOp::Mul, builder.number(3.0));
+        let sum = builder.binary(builder.number(1.0), BinaryOp::Add, product);
+        let label = builder.binary(builder.string("n = "), BinaryOp::Add, builder.number(7.0));
+        let either = builder.binary(builder.nil(), BinaryOp::Or, builder.var(Binding::global("x")));
+        let broken = builder.binary(builder.bool(true), BinaryOp::Add, builder.number(1.0));
+
+        assert_eq!(
+            run(&mut ConstantFolding, vec![sum, label, either, broken.clone()]),
+            vec![builder.number(7.0), builder.string("n = 7"), builder.var(Binding::global("x")), broken]
+        );
+
+        // Dead branches, unless they declare locals
+        let pick = builder.ternary(builder.bool(false), builder.number(1.0), Some(builder.number(2.0)));
+        let never = builder.while_(builder.nil(), |builder| builder.break_());
+        let declaring = builder.block(|builder| builder.bind(Binding::local("x", 1, 1), builder.nil()));
+        let declaring = builder.ternary(builder.bool(false), declaring, None);
+
+        assert_eq!(
+            run(&mut DeadBranches, vec![pick, never, declaring.clone()]),
+            vec![builder.number(2.0), builder.block(|_| {}), declaring]
+        );
+
+        // Unreachable code
+        let mut body = IrBuilder::new();
+        body.ret(Some(body.number(1.0)));
+        body.bind(Binding::global("y"), body.number(2.0));
+        body.bind(Binding::local("z", 1, 1), body.number(3.0));
+
+        let mut expected = IrBuilder::new();
+        expected.ret(Some(expected.number(1.0)));
+        expected.bind(Binding::local("z", 1, 1), expected.number(3.0));
+
+        assert_eq!(run(&mut UnreachableCode, body.build()), expected.build());
+
+        // Propagation feeding folding, but not through locals that change
+        let function = |builder: &mut IrBuilder| {
+            builder.function(Binding::global("f"), &[], |builder| {
+                builder.bind(Binding::local("x", 1, 1), builder.number(2.0));
+                builder.bind(Binding::local("y", 1, 1), builder.number(5.0));
+                builder.mutate(builder.var(Binding::local("y", 1, 1)), builder.number(6.0));
+
+                let x = builder.var(Binding::local("x", 1, 1));
+                let y = builder.var(Binding::local("y", 1, 1));
+
+                builder.ret(Some(builder.binary(x, BinaryOp::Mul, y)))
+            })
+        };
+
+        let mut program = vec![function(&mut builder)];
+        PassManager::new(OptLevel::Full).run(&mut program);
+
+        // `y` changes, so only `x` goes
+        let mut expected = IrBuilder::new();
+        let expected = expected.function(Binding::global("f"), &[], |builder| {
+            builder.bind(Binding::local("x", 1, 1), builder.number(2.0));
+            builder.bind(Binding::local("y", 1, 1), builder.number(5.0));
+            builder.mutate(builder.var(Binding::local("y", 1, 1)), builder.number(6.0));
+
+            let product = builder.binary(builder.number(2.0), BinaryOp::Mul, builder.var(Binding::local("y", 1, 1)));
+            builder.ret(Some(product))
+        });
+
+        assert_eq!(program, vec![expected]);
+
+        // Optimized programs still do the same thing
+        let mut builder = IrBuilder::new();
+
+        let f = function(&mut builder);
+        builder.emit(f);
+
+        let result = builder.call(builder.var(Binding::global("f")), vec![], None);
+        builder.bind(Binding::global("result"), result);
+
+        let pick = builder.ternary(builder.bool(true), builder.string("yes"), Some(builder.string("no")));
+        builder.bind(Binding::global("picked"), pick);
+
+        for level in [OptLevel::None, OptLevel::Basic, OptLevel::Full].iter() {
+            let mut vm = VM::new();
+            vm.opt_level = *level;
+            vm.exec(&builder.build(), false).unwrap();
+
+            assert_eq!(vm.globals["result"], Value::float(12.0));
+            assert_eq!(String::from_value(vm.globals["picked"], &vm.heap).unwrap(), "yes");
+        }
+    }
 }
@@ -0,0 +1,330 @@
+use super::*;
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+
+// Layout, all integers little-endian:
+//
+//   magic "ZUBC", version u16, function
+//
+//   function:  name, arity u8, upvalue count u32, chunk
+//   chunk:     name, code (u32 length + bytes), lines (u32 count + u32 start, u32 line each),
+//              constants (u32 count + a tag byte each, followed by its payload)
+//   string:    u32 length + UTF-8
+//
+// Upvalue descriptors live in the code itself, right after each `Closure` op.
+// Version 2 added the long index ops, 3 the long jumps, 4 the fused ops, 5 ints and their ops, and 6
+// the long list and dict ops.
+// Older files are still read, as they're a subset.
+const MAGIC: &[u8; 4] = b"ZUBC";
+const VERSION: u16 = 6;
+
+const TAG_NIL: u8 = 0;
+const TAG_TRUE: u8 = 1;
+const TAG_FALSE: u8 = 2;
+const TAG_FLOAT: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+const TAG_INT: u8 = 6;
+
+// Keeps hostile input from recursing through nested functions until the stack runs out.
+const MAX_NESTING: usize = 256;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Malformed(String),
+    UnsupportedConstant(&'static str), // only nil, bools, numbers, strings and functions can be saved
+    Invalid(Vec<Diagnostic>), // well-formed, but rejected by the `Verifier`
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BytecodeError::*;
+
+        match self {
+            Io(err) => write!(f, "{}", err),
+            BadMagic => write!(f, "not zub bytecode"),
+            UnsupportedVersion(version) => write!(f, "unsupported bytecode version {}, expected {} or older", version, VERSION),
+            Malformed(what) => write!(f, "malformed bytecode: {}", what),
+            UnsupportedConstant(kind) => write!(f, "can't save constant of type {}", kind),
+            Invalid(diagnostics) => {
+                write!(f, "invalid bytecode")?;
+
+                for diagnostic in diagnostics.iter().filter(|d| d.is_error()) {
+                    write!(f, "\n  {}", diagnostic)?
+                }
+
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl From<io::Error> for BytecodeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => BytecodeError::Malformed("unexpected end of input".into()),
+            _ => BytecodeError::Io(err),
+        }
+    }
+}
+
+fn malformed<T>(what: impl Into<String>) -> Result<T, BytecodeError> {
+    Err(BytecodeError::Malformed(what.into()))
+}
+
+impl Function {
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), BytecodeError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        write_function(writer, self)
+    }
+}
+
+fn write_function(writer: &mut impl Write, function: &Function) -> Result<(), BytecodeError> {
+    let chunk = function.chunk();
+
+    write_str(writer, function.name())?;
+    writer.write_all(&[function.arity()])?;
+    write_len(writer, function.upvalue_count())?;
+
+    write_str(writer, chunk.name())?;
+
+    write_len(writer, chunk.len())?;
+    writer.write_all(chunk.as_ref())?;
+
+    let lines = chunk.line_starts().collect::<Vec<_>>();
+
+    write_len(writer, lines.len())?;
+
+    for (start, line) in lines {
+        write_len(writer, start)?;
+        write_len(writer, line)?;
+    }
+
+    let constants = chunk.constants().collect::<Vec<_>>();
+
+    write_len(writer, constants.len())?;
+
+    for constant in constants {
+        match constant.decode() {
+            Variant::Nil => writer.write_all(&[TAG_NIL])?,
+            Variant::True => writer.write_all(&[TAG_TRUE])?,
+            Variant::False => writer.write_all(&[TAG_FALSE])?,
+            Variant::Float(n) => {
+                writer.write_all(&[TAG_FLOAT])?;
+                writer.write_all(&n.to_bits().to_le_bytes())?
+            },
+            Variant::Int(n) => {
+                writer.write_all(&[TAG_INT])?;
+                writer.write_all(&n.to_le_bytes())?
+            },
+            Variant::Obj(handle) => match unsafe { handle.get_unchecked() } {
+                Object::String(ref string) => {
+                    writer.write_all(&[TAG_STRING])?;
+                    write_str(writer, string)?
+                },
+                Object::Function(ref function) => {
+                    writer.write_all(&[TAG_FUNCTION])?;
+                    write_function(writer, function)?
+                },
+                object => return Err(BytecodeError::UnsupportedConstant(object.type_name())),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn write_len(writer: &mut impl Write, len: usize) -> Result<(), BytecodeError> {
+    if len > u32::MAX as usize {
+        return Err(BytecodeError::Malformed(format!("length {} doesn't fit the format", len)))
+    }
+
+    writer.write_all(&(len as u32).to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_str(writer: &mut impl Write, string: &str) -> Result<(), BytecodeError> {
+    write_len(writer, string.len())?;
+    writer.write_all(string.as_bytes())?;
+
+    Ok(())
+}
+
+impl VM {
+    // Reads a function saved with `Function::write_to`, putting its constants on this VM's heap.
+    // It has to pass the `Verifier` before it's handed back, run it with `exec_function`.
+    pub fn load_bytecode(&mut self, reader: &mut impl Read) -> Result<Function, BytecodeError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(BytecodeError::BadMagic)
+        }
+
+        let version = read_u16(reader)?;
+
+        if version == 0 || version > VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version))
+        }
+
+        let function = read_function(reader, &mut self.heap, 0)?;
+
+        // Anything after the function means we're reading something else
+        if reader.read(&mut [0])? != 0 {
+            return malformed("trailing bytes after function")
+        }
+
+        let diagnostics = Verifier::new(&function, &self.heap).verify();
+
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(BytecodeError::Invalid(diagnostics))
+        }
+
+        Ok(function)
+    }
+}
+
+fn read_function(reader: &mut impl Read, heap: &mut Heap<Object>, depth: usize) -> Result<Function, BytecodeError> {
+    if depth > MAX_NESTING {
+        return malformed(format!("functions nested more than {} deep", MAX_NESTING))
+    }
+
+    let name = read_string(reader)?;
+    let arity = read_u8(reader)?;
+    let upvalue_count = read_len(reader)?;
+
+    if upvalue_count > u16::MAX as usize + 1 {
+        return malformed(format!("`{}` has {} upvalues", name, upvalue_count))
+    }
+
+    let chunk_name = read_string(reader)?;
+    let code = read_bytes(reader)?;
+
+    let line_count = read_len(reader)?;
+    let mut lines = Vec::new();
+
+    for _ in 0..line_count {
+        let start = read_len(reader)?;
+        let line = read_len(reader)?;
+
+        // Line lookups binary search from the start of the code
+        let in_order = match lines.last() {
+            Some(&(last, _)) => start > last,
+            None => start == 0,
+        };
+
+        if !in_order || start > code.len() {
+            return malformed(format!("bad line table in `{}`", name))
+        }
+
+        lines.push((start, line))
+    }
+
+    if lines.is_empty() && !code.is_empty() {
+        return malformed(format!("`{}` has code but no lines", name))
+    }
+
+    let constant_count = read_len(reader)?;
+
+    if constant_count > u16::MAX as usize + 1 {
+        return malformed(format!("`{}` has {} constants", name, constant_count))
+    }
+
+    let mut constants = Vec::with_capacity(constant_count);
+    let mut pinned = Vec::new();
+
+    for _ in 0..constant_count {
+        let constant = match read_u8(reader)? {
+            TAG_NIL => Value::nil(),
+            TAG_TRUE => Value::truelit(),
+            TAG_FALSE => Value::falselit(),
+            TAG_FLOAT => {
+                let mut bytes = [0; 8];
+                reader.read_exact(&mut bytes)?;
+
+                Value::float(f64::from_bits(u64::from_le_bytes(bytes)))
+            },
+            TAG_INT => {
+                let mut bytes = [0; 4];
+                reader.read_exact(&mut bytes)?;
+
+                Value::int(i32::from_le_bytes(bytes))
+            },
+            TAG_STRING => {
+                let string = read_string(reader)?;
+                pin(heap.insert(Object::String(string)), &mut pinned)
+            },
+            TAG_FUNCTION => {
+                let function = read_function(reader, heap, depth + 1)?;
+                pin(heap.insert(Object::Function(function)), &mut pinned)
+            },
+            tag => return malformed(format!("unknown constant tag {}", tag)),
+        };
+
+        constants.push(constant)
+    }
+
+    let mut builder = FunctionBuilder::new(&name, arity);
+
+    builder.chunk = Chunk::from_parts(chunk_name, code, constants, pinned, &lines);
+    builder.set_upvalue_count(upvalue_count);
+
+    Ok(builder.build())
+}
+
+// Object constants stay alive with the chunk, nothing on the heap points to them yet
+fn pin(constant: Rooted<Object>, pinned: &mut Vec<Rooted<Object>>) -> Value {
+    let value = constant.handle().into();
+
+    pinned.push(constant);
+    value
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8, BytecodeError> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16, BytecodeError> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_len(reader: &mut impl Read) -> Result<usize, BytecodeError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
+// Lengths aren't trusted to size buffers up front, a bogus one just runs out of input.
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, BytecodeError> {
+    let len = read_len(reader)?;
+    let mut bytes = Vec::new();
+
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() != len {
+        return malformed("unexpected end of input")
+    }
+
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String, BytecodeError> {
+    String::from_utf8(read_bytes(reader)?)
+        .or_else(|_| malformed("string isn't valid UTF-8"))
+}
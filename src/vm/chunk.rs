@@ -1,12 +1,17 @@
 use super::*;
 use gc::trace::{ Trace, Tracer };
 
+use std::mem;
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
     name: String,
     constants: Vec<Value>,
     lines: Vec<Line>,
+    // The objects among the constants, which nothing on the heap may point to yet while the
+    // function is being compiled, or held on to by whoever compiled it
+    pinned: Vec<Rooted<Object>>,
 }
 
 impl Trace<Object> for Chunk {
@@ -26,6 +31,7 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             name,
+            pinned: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new()
         }
@@ -44,28 +50,37 @@ impl Chunk {
         self.code[idx] = byte;
     }
 
+    pub fn write_u16(&mut self, val: u16) {
+        self.code.extend_from_slice(&val.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.code.extend_from_slice(&val.to_le_bytes())
+    }
+
     pub fn write_u64(&mut self, val: u64) {
-        (0..8).for_each(|i| self.write_byte(((val >> i * 8) & 0xFF) as u8))
+        (0..8).for_each(|i| self.write_byte(((val >> (i * 8)) & 0xFF) as u8))
     }
 
+    // Returns `None` once the chunk has run out of constant slots. Indexes past 255 need the long ops.
     #[inline]
-    pub fn add_constant(&mut self, constant: Value) -> u8 {
+    pub fn add_constant(&mut self, constant: Value) -> Option<u16> {
         for (i, c) in self.constants.iter().enumerate() {
             if *c == constant {
-                return i as u8;
+                return Some(i as u16);
             }
         }
 
-        if self.constants.len() == 1028 {
-            panic!("A chunk cannot have more than 1028 constants");
+        if self.constants.len() > u16::MAX as usize {
+            return None
         }
 
         self.constants.push(constant);
-        self.constants.len() as u8 - 1
+        Some((self.constants.len() - 1) as u16)
     }
 
     #[inline]
-    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> u8 {
+    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> Option<u16> {
         for (i, c) in self.constants().enumerate() {
             let obj = c
                 .as_object()
@@ -74,23 +89,70 @@ impl Chunk {
 
             if let Some(s) = obj {
                 if s == string {
-                    return i as u8
+                    return Some(i as u16)
                 }
             }
         }
 
-        let handle = heap.insert(Object::String(string.to_owned())).into_handle();
-        self.add_constant(handle.into())
+        let rooted = heap.insert(Object::String(string.to_owned()));
+        let idx = self.add_constant(rooted.handle().into())?;
+
+        self.pin(rooted);
+        Some(idx)
+    }
+
+    // Keeps a constant alive for as long as the chunk is around.
+    pub fn pin(&mut self, constant: Rooted<Object>) {
+        self.pinned.push(constant)
+    }
+
+    // Puts a chunk back together from its parts, as read back from bytecode.
+    pub(crate) fn from_parts(
+        name: String,
+        code: Vec<u8>,
+        constants: Vec<Value>,
+        pinned: Vec<Rooted<Object>>,
+        lines: &[(usize, usize)],
+    ) -> Self {
+        Chunk {
+            code,
+            name,
+            constants,
+            lines: lines.iter().map(|&(start, line)| Line { start, line }).collect(),
+            pinned,
+        }
+    }
+
+    // The same chunk with its code swapped out, the constants stay where they are.
+    pub(crate) fn with_code(&self, code: Vec<u8>, lines: &[(usize, usize)]) -> Self {
+        Chunk {
+            code,
+            lines: lines.iter().map(|&(start, line)| Line { start, line }).collect(),
+            ..self.clone()
+        }
     }
 
-    pub fn constants(&self) -> Constants {
+    // The offset each line starts at, along with the line.
+    pub(crate) fn line_starts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.lines.iter().map(|l| (l.start, l.line))
+    }
+
+    pub fn constants(&self) -> Constants<'_> {
         Constants::new(self.constants.iter())
     }
 
+    pub(crate) fn constant_values(&self) -> &[Value] {
+        &self.constants
+    }
+
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
     fn add_line(&mut self, line: usize) {
         match self.lines.last().cloned() {
             Some(last) if last.line >= line => return,
@@ -99,7 +161,7 @@ impl Chunk {
 
         self.lines.push(Line {
             start: self.code.len(),
-            line: line,
+            line,
         });
     }
 
@@ -109,8 +171,8 @@ impl Chunk {
     }
 
     #[inline]
-    pub fn get_constant(&self, idx: u8) -> Option<&Value> {
-        self.constants.get(idx as usize)
+    pub fn get_constant(&self, idx: usize) -> Option<&Value> {
+        self.constants.get(idx)
     }
 
     pub fn line(&self, offset: usize) -> usize {
@@ -129,37 +191,40 @@ impl Chunk {
 
     #[inline]
     pub fn read_u16(&self, idx: usize) -> u16 {
-        let mut t = 0u16;
-        let size = ::std::mem::size_of::<u16>();
-        
-        unsafe {
-            ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
-                &mut t as *mut u16 as *mut u8,
-                size);
-        }
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(&self.code[idx..idx + 2]);
 
-        t.to_le()
+        u16::from_le_bytes(bytes)
+    }
+
+    #[inline]
+    pub fn read_u32(&self, idx: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.code[idx..idx + 4]);
+
+        u32::from_le_bytes(bytes)
     }
 
     #[inline]
     pub fn read_u64(&self, idx: usize) -> u64 {
-        let mut t = 0u64;
-        let size = ::std::mem::size_of::<u64>();
-        
-        unsafe {
-            ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
-                &mut t as *mut u64 as *mut u8,
-                size);
-        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.code[idx..idx + 8]);
 
-        t.to_le()
+        u64::from_le_bytes(bytes)
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    // Bytes held on to for the code, constants and line table, not counting the chunk itself
+    pub fn heap_size(&self) -> usize {
+        self.code.capacity()
+            + self.name.capacity()
+            + self.constants.capacity() * mem::size_of::<Value>()
+            + self.lines.capacity() * mem::size_of::<Line>()
+            + self.pinned.capacity() * mem::size_of::<Rooted<Object>>()
+    }
 }
 
 pub struct Constants<'c> {
@@ -177,7 +242,7 @@ impl<'c> Iterator for Constants<'c> {
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|v| *v)
+        self.iter.next().copied()
     }
 }
 
@@ -192,6 +257,7 @@ impl AsRef<[u8]> for Chunk {
 pub enum Op {
     Return,
     Constant(u8),
+    ConstantLong(u16),
     Nil,
     True,
     False,
@@ -233,6 +299,69 @@ pub enum Op {
     SetElement,
 
     Index,
+
+    Class,
+    GetProperty,
+    SetProperty,
+    Invoke,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+
+    PushHandler,
+    PopHandler,
+    Throw,
+
+    Coroutine,
+    Yield,
+    CoroutineStatus,
+
+    // Taking a u16 index where their short forms take a byte
+    GetGlobalLong,
+    SetGlobalLong,
+    DefineGlobalLong,
+    GetLocalLong,
+    SetLocalLong,
+    GetUpValueLong,
+    SetUpValueLong,
+    ClosureLong, // upvalue indexes are u16 too
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    InvokeLong,
+    GetSuperLong,
+    SuperInvokeLong,
+
+    CallN, // arity as an operand, for calls with more than 8 arguments
+
+    // Same as their short forms, with u32 operands for functions past 64 KiB
+    JumpLong,
+    JumpIfFalseLong,
+    LoopLong,
+    PushHandlerLong,
+
+    // Fused by the peephole pass out of what the compiler emits
+    GreaterEqual, // Less; Not
+    LessEqual,    // Greater; Not
+    NotEqual,     // Equal; Not
+    JumpIfFalsePop, // pops the condition either way
+    AddConst,     // Immediate; Add
+    GetLocal0,
+    GetLocal1,
+    GetLocal2,
+    GetLocal3,
+
+    IntDiv, // `/` rounding towards zero, for expressions typed `Type::Int`
+    // Ints only
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    BitNot,
+
+    ListLong, // element counts past 255, as u16
+    DictLong,
 }
 
 impl Op {
@@ -277,6 +406,61 @@ impl Op {
             SetElement => buf.push(0x29),
             Index => buf.push(0x30),
             Pow => buf.push(0x31),
+
+            Class => buf.push(0x2a),
+            GetProperty => buf.push(0x2b),
+            SetProperty => buf.push(0x2c),
+            Invoke => buf.push(0x2d),
+            Inherit => buf.push(0x2e),
+            GetSuper => buf.push(0x2f),
+            SuperInvoke => buf.push(0x32),
+
+            PushHandler => buf.push(0x33),
+            PopHandler => buf.push(0x34),
+            Throw => buf.push(0x35),
+
+            Coroutine => buf.push(0x36),
+            Yield => buf.push(0x37),
+            CoroutineStatus => buf.push(0x38),
+
+            ConstantLong(idx) => { buf.push(0x39); buf.extend_from_slice(&idx.to_le_bytes()); }
+            GetGlobalLong => buf.push(0x3a),
+            SetGlobalLong => buf.push(0x3b),
+            DefineGlobalLong => buf.push(0x3c),
+            GetLocalLong => buf.push(0x3d),
+            SetLocalLong => buf.push(0x3e),
+            GetUpValueLong => buf.push(0x3f),
+            SetUpValueLong => buf.push(0x40),
+            ClosureLong => buf.push(0x41),
+            ClassLong => buf.push(0x42),
+            GetPropertyLong => buf.push(0x43),
+            SetPropertyLong => buf.push(0x44),
+            InvokeLong => buf.push(0x45),
+            GetSuperLong => buf.push(0x46),
+            SuperInvokeLong => buf.push(0x47),
+            CallN => buf.push(0x48),
+            JumpLong => buf.push(0x49),
+            JumpIfFalseLong => buf.push(0x4a),
+            LoopLong => buf.push(0x4b),
+            PushHandlerLong => buf.push(0x4c),
+            GreaterEqual => buf.push(0x4d),
+            LessEqual => buf.push(0x4e),
+            NotEqual => buf.push(0x4f),
+            JumpIfFalsePop => buf.push(0x50),
+            AddConst => buf.push(0x51),
+            GetLocal0 => buf.push(0x52),
+            GetLocal1 => buf.push(0x53),
+            GetLocal2 => buf.push(0x54),
+            GetLocal3 => buf.push(0x55),
+            IntDiv => buf.push(0x56),
+            BitAnd => buf.push(0x57),
+            BitOr => buf.push(0x58),
+            BitXor => buf.push(0x59),
+            Shl => buf.push(0x5a),
+            Shr => buf.push(0x5b),
+            BitNot => buf.push(0x5c),
+            ListLong => buf.push(0x5d),
+            DictLong => buf.push(0x5e),
         }
     }
 }
@@ -285,7 +469,7 @@ macro_rules! decode_op {
     ($op:expr, $this:ident) => {
         match $op {
             0x00 => $this.ret(),
-            0x01 => { let idx = $this.read_byte(); $this.constant(idx); }
+            0x01 => { let idx = $this.read_byte() as usize; $this.constant(idx) }
             0x02 => $this.print(),
             0x03 => $this.add(),
             0x04 => $this.sub(),
@@ -296,13 +480,13 @@ macro_rules! decode_op {
             0x09 => $this.eq(),
             0x0a => $this.gt(),
             0x0b => $this.lt(),
-            0x0c => $this.jmp(),
-            0x0d => $this.jze(),
-            0x0e => { $this.pop(); },
-            0x0f => $this.get_global(),
-            0x10 => $this.set_global(),
-            0x11 => $this.get_local(),
-            0x12 => $this.set_local(),
+            0x0c => { let ip = $this.read_u16() as usize; $this.jmp(ip) }
+            0x0d => { let ip = $this.read_u16() as usize; $this.jze(ip) }
+            0x0e => $this.op_pop(),
+            0x0f => { let idx = $this.read_byte() as usize; $this.get_global(idx) }
+            0x10 => { let idx = $this.read_byte() as usize; $this.set_global(idx) }
+            0x11 => { let slot = $this.read_byte() as usize; $this.get_local(slot) }
+            0x12 => { let slot = $this.read_byte() as usize; $this.set_local(slot) }
             0x13 => $this.immediate(),
             0x14 => $this.imm_nil(),
             0x15 => $this.imm_true(),
@@ -310,21 +494,67 @@ macro_rules! decode_op {
             a @ 0x17..=0x1f => {
                 $this.call(a - 0x17)
             },
-            0x20 => $this.op_loop(),
+            0x20 => { let sub = $this.read_u16() as usize; $this.op_loop(sub) }
             0x21 => $this.close_upvalue(),
-            0x22 => $this.get_upvalue(),
-            0x23 => $this.set_upvalue(),
-            0x24 => $this.closure(),
-            0x25 => $this.define_global(),
-            0x26 => $this.list(),
+            0x22 => { let idx = $this.read_byte() as usize; $this.get_upvalue(idx) }
+            0x23 => { let idx = $this.read_byte() as usize; $this.set_upvalue(idx) }
+            0x24 => { let idx = $this.read_byte() as usize; $this.closure(idx, false) }
+            0x25 => { let idx = $this.read_byte() as usize; $this.define_global(idx) }
+            0x26 => { let count = $this.read_byte() as usize; $this.list(count) }
             0x27 => $this.rem(),
-            0x28 => $this.dict(),
+            0x28 => { let count = $this.read_byte() as usize; $this.dict(count) }
             0x29 => $this.set_element(),
+            0x2a => { let idx = $this.read_byte() as usize; $this.class(idx) }
+            0x2b => { let idx = $this.read_byte() as usize; $this.get_property(idx) }
+            0x2c => { let idx = $this.read_byte() as usize; $this.set_property(idx) }
+            0x2d => { let arity = $this.read_byte(); let idx = $this.read_byte() as usize; $this.invoke(arity, idx) }
+            0x2e => $this.inherit(),
+            0x2f => { let idx = $this.read_byte() as usize; $this.get_super(idx) }
             0x30 => $this.index(),
             0x31 => $this.pow(),
-            _ => {
-                panic!("Unknown op {}", $op);
-            }
+            0x32 => { let arity = $this.read_byte(); let idx = $this.read_byte() as usize; $this.super_invoke(arity, idx) }
+            0x33 => { let ip = $this.read_u16() as usize; $this.push_handler(ip) }
+            0x34 => $this.pop_handler(),
+            0x35 => $this.throw(),
+            0x36 => $this.coroutine(),
+            0x37 => $this.op_yield(),
+            0x38 => $this.coroutine_status(),
+            0x39 => { let idx = $this.read_u16() as usize; $this.constant(idx) }
+            0x3a => { let idx = $this.read_u16() as usize; $this.get_global(idx) }
+            0x3b => { let idx = $this.read_u16() as usize; $this.set_global(idx) }
+            0x3c => { let idx = $this.read_u16() as usize; $this.define_global(idx) }
+            0x3d => { let slot = $this.read_u16() as usize; $this.get_local(slot) }
+            0x3e => { let slot = $this.read_u16() as usize; $this.set_local(slot) }
+            0x3f => { let idx = $this.read_u16() as usize; $this.get_upvalue(idx) }
+            0x40 => { let idx = $this.read_u16() as usize; $this.set_upvalue(idx) }
+            0x41 => { let idx = $this.read_u16() as usize; $this.closure(idx, true) }
+            0x42 => { let idx = $this.read_u16() as usize; $this.class(idx) }
+            0x43 => { let idx = $this.read_u16() as usize; $this.get_property(idx) }
+            0x44 => { let idx = $this.read_u16() as usize; $this.set_property(idx) }
+            0x45 => { let arity = $this.read_byte(); let idx = $this.read_u16() as usize; $this.invoke(arity, idx) }
+            0x46 => { let idx = $this.read_u16() as usize; $this.get_super(idx) }
+            0x47 => { let arity = $this.read_byte(); let idx = $this.read_u16() as usize; $this.super_invoke(arity, idx) }
+            0x48 => { let arity = $this.read_byte(); $this.call(arity) }
+            0x49 => { let ip = $this.read_u32() as usize; $this.jmp(ip) }
+            0x4a => { let ip = $this.read_u32() as usize; $this.jze(ip) }
+            0x4b => { let sub = $this.read_u32() as usize; $this.op_loop(sub) }
+            0x4c => { let ip = $this.read_u32() as usize; $this.push_handler(ip) }
+            0x4d => $this.ge(),
+            0x4e => $this.le(),
+            0x4f => $this.neq(),
+            0x50 => { let ip = $this.read_u16() as usize; $this.jze_pop(ip) }
+            0x51 => $this.add_const(),
+            a @ 0x52..=0x55 => $this.get_local((a - 0x52) as usize),
+            0x56 => $this.int_div(),
+            0x57 => $this.bit_and(),
+            0x58 => $this.bit_or(),
+            0x59 => $this.bit_xor(),
+            0x5a => $this.shl(),
+            0x5b => $this.shr(),
+            0x5c => $this.bit_not(),
+            0x5d => { let count = $this.read_u16() as usize; $this.list(count) }
+            0x5e => { let count = $this.read_u16() as usize; $this.dict(count) }
+            op => $this.unknown_op(op),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,9 @@
 use super::*;
-use gc::trace::{ Trace, Tracer };
 use colored::Colorize;
 
 pub struct Disassembler<'c> {
     offset: usize,
+    start: usize, // of the instruction being disassembled
     line: usize,
     chunk: &'c Chunk,
     heap: &'c Heap<Object>,
@@ -13,6 +13,7 @@ impl<'c> Disassembler<'c> {
     pub fn new(chunk: &'c Chunk, heap: &'c Heap<Object>) -> Self {
         Disassembler {
             offset: 0,
+            start: 0,
             line: 0,
             chunk,
             heap,
@@ -39,6 +40,7 @@ impl<'c> Disassembler<'c> {
         } else {
             self.line = line;
         }
+        self.start = self.offset;
         let inst = self.read_byte();
         println!();
         let off = format!("{:04} | ", self.offset);
@@ -47,7 +49,7 @@ impl<'c> Disassembler<'c> {
         decode_op!(inst, self);
     }
 
-    fn constant(&mut self, idx: u8) {
+    fn constant(&mut self, idx: usize) {
         let val = self.chunk.get_constant(idx);
         eprint!("CONSTANT\t{}\t{:?}", idx, val);
     }
@@ -61,22 +63,30 @@ impl<'c> Disassembler<'c> {
     fn pow(&self) { eprint!("POW"); }
     fn div(&self) { eprint!("DIV"); }
     fn neg(&self) { eprint!("NEG"); }
+    fn int_div(&self) { eprint!("INT_DIV"); }
+    fn bit_and(&self) { eprint!("BIT_AND"); }
+    fn bit_or(&self) { eprint!("BIT_OR"); }
+    fn bit_xor(&self) { eprint!("BIT_XOR"); }
+    fn shl(&self) { eprint!("SHL"); }
+    fn shr(&self) { eprint!("SHR"); }
+    fn bit_not(&self) { eprint!("BIT_NOT"); }
     fn not(&self) { eprint!("NOT"); }
     fn eq(&self) { eprint!("EQ"); }
     fn gt(&self) { eprint!("GT"); }
     fn lt(&self) { eprint!("LT"); }
-    fn pop(&self) { eprint!("POP"); }
+    fn ge(&self) { eprint!("GE"); }
+    fn le(&self) { eprint!("LE"); }
+    fn neq(&self) { eprint!("NEQ"); }
+    fn op_pop(&self) { eprint!("POP"); }
 
-    fn list(&mut self) {
-        eprint!("LIST");
-        self.read_byte();
+    fn list(&mut self, count: usize) {
+        eprint!("LIST\t{}", count);
     }
 
     fn index(&mut self) {}
 
-    fn dict(&mut self) {
-        eprint!("DICT");
-        self.read_byte();
+    fn dict(&mut self, count: usize) {
+        eprint!("DICT\t{}", count);
     }
 
     fn set_element(&mut self) {
@@ -84,46 +94,75 @@ impl<'c> Disassembler<'c> {
     }
 
 
-    fn jmp(&mut self) {
-        let offset = self.offset - 1;
-        let ip = self.read_u16();
-        eprint!("JUMP\t{} -> {}", offset, ip);
+    fn jmp(&mut self, ip: usize) {
+        eprint!("JUMP\t{} -> {}", self.start, ip);
     }
 
-    fn jze(&mut self) {
-        let offset = self.offset - 1;
-        let ip = self.read_u16();
-        eprint!("JUMP_IF_FALSE\t{} -> {}", offset, ip);
+    fn jze(&mut self, ip: usize) {
+        eprint!("JUMP_IF_FALSE\t{} -> {}", self.start, ip);
     }
 
-    fn op_loop(&mut self) {
-        let sub = self.read_u16() as usize;
+    fn jze_pop(&mut self, ip: usize) {
+        eprint!("JUMP_IF_FALSE_POP\t{} -> {}", self.start, ip);
+    }
+
+    fn push_handler(&mut self, ip: usize) {
+        eprint!("PUSH_HANDLER\t{} -> {}", self.start, ip);
+    }
+
+    fn pop_handler(&self) {
+        eprint!("POP_HANDLER");
+    }
+
+    fn throw(&self) {
+        eprint!("THROW");
+    }
+
+    fn coroutine(&self) {
+        eprint!("COROUTINE");
+    }
+
+    fn op_yield(&self) {
+        eprint!("YIELD");
+    }
+
+    fn coroutine_status(&self) {
+        eprint!("COROUTINE_STATUS");
+    }
+
+    fn op_loop(&mut self, sub: usize) {
         eprint!("LOOP\t{} -> {}", self.offset, self.offset - sub);
     }
 
-    fn get_global(&mut self) {
-        let val = self.read_constant();
+    fn get_global(&mut self, idx: usize) {
+        let val = self.read_constant(idx);
         eprint!("GET_GLOBAL\t{}", val.with_heap(self.heap));
     }
 
-    fn set_global(&mut self) {
-        let val = self.read_constant();
+    fn set_global(&mut self, idx: usize) {
+        let val = self.read_constant(idx);
         eprint!("SET_GLOBAL\t{}", val.with_heap(self.heap));
     }
 
-    fn define_global(&mut self) {
-        let val = self.read_constant();
+    fn define_global(&mut self, idx: usize) {
+        let val = self.read_constant(idx);
         eprint!("DEFINE_GLOBAL\t{}", val.with_heap(self.heap));
     }
 
-    fn get_local(&mut self) {
-        let val = self.read_byte();
-        eprint!("GET_LOCAL\t{}", val);
+    fn get_local(&mut self, slot: usize) {
+        eprint!("GET_LOCAL\t{}", slot);
     }
 
-    fn set_local(&mut self) {
-        let val = self.read_byte();
-        eprint!("SET_LOCAL\t{}", val);
+    fn set_local(&mut self, slot: usize) {
+        eprint!("SET_LOCAL\t{}", slot);
+    }
+
+    fn add_const(&mut self) {
+        let raw = self.chunk.read_u64(self.offset);
+        self.offset += 8;
+
+        let val = unsafe { Value::from_raw(raw) };
+        eprint!("ADD_CONST\t{}", val.with_heap(self.heap));
     }
 
     fn immediate(&mut self) {
@@ -145,7 +184,7 @@ impl<'c> Disassembler<'c> {
             (b7 << 48) +
             (b8 << 56);
         let val = unsafe { Value::from_raw(raw) };
-        eprint!("FLOAT\t{}", val.with_heap(self.heap));
+        eprint!("IMMEDIATE\t{:?}", val);
     }
 
     fn imm_nil(&self) {
@@ -164,28 +203,29 @@ impl<'c> Disassembler<'c> {
         eprint!("CALL_{}", arity);
     }
 
-    fn invoke(&mut self, arity: u8) {
-        let idx = self.read_byte();
+    fn unknown_op(&self, op: u8) {
+        eprint!("UNKNOWN\t{:#04x}", op);
+    }
+
+    fn invoke(&mut self, arity: u8, idx: usize) {
         let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("INVOKE_{} {}", arity, val.with_heap(&self.heap));
+        eprint!("INVOKE_{} {}", arity, val.with_heap(self.heap));
     }
 
     fn close_upvalue(&self) {
         eprint!("CLOSE_UPVALUE");
     }
 
-    fn get_upvalue(&mut self) {
-        let index = self.read_byte();
+    fn get_upvalue(&mut self, index: usize) {
         eprint!("GET_UPVALUE\t{}", index);
     }
 
-    fn set_upvalue(&mut self) {
-        let index = self.read_byte();
+    fn set_upvalue(&mut self, index: usize) {
         eprint!("SET_UPVALE\t{}", index);
     }
 
-    fn closure(&mut self) {
-        let val = self.read_constant();
+    fn closure(&mut self, idx: usize, wide: bool) {
+        let val = self.read_constant(idx);
         let count = val
             .as_object()
             .and_then(|o| self.heap.get(o))
@@ -200,33 +240,45 @@ impl<'c> Disassembler<'c> {
             unsafe {
                 let closure = cl.get_unchecked().as_function().unwrap();
 
-                let dis = Disassembler::new(closure.chunk(), &self.heap);
+                let dis = Disassembler::new(closure.chunk(), self.heap);
                 dis.disassemble()
             }
         }
 
         for _ in 0..count {
             let _is_local = self.read_byte() > 0;
-            let _index = self.read_byte();
+            let _index = if wide { self.read_u16() as usize } else { self.read_byte() as usize };
         }
     }
 
-    fn class(&mut self, idx: u8) {
+    fn class(&mut self, idx: usize) {
         let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
         let methods = self.read_byte();
-        eprint!("CLASS\t{}\t{}\t({} method(s))", idx, val.with_heap(&self.heap), methods);
+        eprint!("CLASS\t{}\t{}\t({} method(s))", idx, val.with_heap(self.heap), methods);
     }
 
-    fn get_property(&mut self) {
-        let idx = self.read_byte();
+    fn get_property(&mut self, idx: usize) {
         let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("GET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        eprint!("GET_PROPERTY\t{}\t{}", idx, val.with_heap(self.heap));
     }
 
-    fn set_property(&mut self) {
-        let idx = self.read_byte();
+    fn set_property(&mut self, idx: usize) {
         let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("SET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        eprint!("SET_PROPERTY\t{}\t{}", idx, val.with_heap(self.heap));
+    }
+
+    fn inherit(&self) {
+        eprint!("INHERIT");
+    }
+
+    fn get_super(&mut self, idx: usize) {
+        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
+        eprint!("GET_SUPER\t{}\t{}", idx, val.with_heap(self.heap));
+    }
+
+    fn super_invoke(&mut self, arity: u8, idx: usize) {
+        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
+        eprint!("SUPER_INVOKE_{} {}", arity, val.with_heap(self.heap));
     }
 
     fn read_byte(&mut self) -> u8 {
@@ -241,8 +293,12 @@ impl<'c> Disassembler<'c> {
         lo + (hi << 8)
     }
 
-    fn read_constant(&mut self) -> Value {
-        let idx = self.read_byte();
+    fn read_u32(&mut self) -> u32 {
+        self.offset += 4;
+        self.chunk.read_u32(self.offset - 4)
+    }
+
+    fn read_constant(&self, idx: usize) -> Value {
         *self.chunk.get_constant(idx).expect("invalid constant segment index")
     }
 }
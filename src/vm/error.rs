@@ -0,0 +1,158 @@
+use std::fmt::{self, Display};
+
+use crate::compiler::CompileError;
+use super::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    Arity,
+    UndefinedGlobal,
+    Type,
+    DivisionByZero,
+    IndexOutOfBounds,
+    MissingKey,
+    UndefinedProperty,
+    StackOverflow,
+    InvalidOp,
+    Native,
+    Thrown,
+    Coroutine,
+    OutOfFuel,
+    HeapLimit,
+    CallDepth,
+    LengthLimit,
+    Interrupted,
+}
+
+impl RuntimeErrorKind {
+    // The host stopping a script, by a limit or an interrupt, goes straight past its handlers
+    pub fn is_catchable(self) -> bool {
+        !matches!(self, RuntimeErrorKind::OutOfFuel | RuntimeErrorKind::HeapLimit | RuntimeErrorKind::Interrupted)
+    }
+}
+
+impl Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RuntimeErrorKind::*;
+
+        let name = match self {
+            Arity => "arity mismatch",
+            UndefinedGlobal => "undefined global",
+            Type => "type error",
+            DivisionByZero => "division by zero",
+            IndexOutOfBounds => "index out of bounds",
+            MissingKey => "missing key",
+            UndefinedProperty => "undefined property",
+            StackOverflow => "stack overflow",
+            InvalidOp => "invalid op",
+            Native => "native error",
+            Thrown => "uncaught exception",
+            Coroutine => "coroutine error",
+            OutOfFuel => "out of fuel",
+            HeapLimit => "heap limit exceeded",
+            CallDepth => "call depth exceeded",
+            LengthLimit => "length limit exceeded",
+            Interrupted => "interrupted",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// One entry of the call stack at the point where an error was raised, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    pub trace: Vec<TraceFrame>,
+    // What a zub `throw` threw. It isn't rooted, so it can be collected once the VM runs again.
+    pub value: Option<Value>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, message: impl Into<String>) -> Self {
+        RuntimeError {
+            kind,
+            message: message.into(),
+            trace: Vec::new(),
+            value: None,
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[error]: {}: {}.", self.kind, self.message)?;
+
+        for frame in self.trace.iter() {
+            write!(f, "\n         at [line {}] in {}", frame.line, frame.function)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// Anything that can go wrong when handing a program to `VM::exec`.
+#[derive(Debug, Clone)]
+pub enum ExecError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+impl ExecError {
+    pub fn compile_errors(&self) -> Option<&[CompileError]> {
+        match self {
+            ExecError::Compile(errors) => Some(errors),
+            _ => None,
+        }
+    }
+
+    pub fn runtime_error(&self) -> Option<&RuntimeError> {
+        match self {
+            ExecError::Runtime(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Vec<CompileError>> for ExecError {
+    fn from(errors: Vec<CompileError>) -> Self {
+        ExecError::Compile(errors)
+    }
+}
+
+impl From<RuntimeError> for ExecError {
+    fn from(err: RuntimeError) -> Self {
+        ExecError::Runtime(err)
+    }
+}
+
+impl Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::Compile(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?
+                    }
+
+                    write!(f, "[error]: {}", err)?
+                }
+
+                Ok(())
+            },
+
+            ExecError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
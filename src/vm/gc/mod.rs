@@ -7,32 +7,102 @@ use std::{
     hash::{Hash, Hasher},
 };
 use hashbrown::{HashMap, HashSet};
+use std::time::{ Duration, Instant };
 use trace::*;
 
 type Generation = usize;
 
+// Where the collection running on a heap is at, see `Heap::step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Mark,  // tracing out from the roots, a little at a time
+    Sweep, // freeing whatever wasn't reached
+}
+
+// How much memory an object takes up, along with everything it owns outside the heap.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+// When and how much the VM collects, given to `VM::new_with`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    pub threshold: usize, // heap bytes before the first collection, and the least before any other
+    pub growth: f64,      // how far the heap may grow past what a collection left alive before the next one
+    pub step: usize,      // objects traced or swept per allocation while collecting, 0 collects in one go
+    pub stress: bool,     // collect everything on every allocation, to find objects that should've been rooted
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            threshold: 1024 * 1024,
+            growth: 2.0,
+            step: 256,
+            stress: false,
+        }
+    }
+}
+
+// What the collector has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize, // run to the end
+    pub steps: usize,
+    pub traced: usize,
+    pub freed: usize,
+    pub freed_bytes: usize,
+    pub live_bytes: usize, // as of the last sweep, plus everything allocated since
+    pub longest_step: usize, // most objects traced or swept in one step
+    pub pause: Duration,     // spent collecting, all steps together
+    pub longest_pause: Duration,
+}
+
+// Whether an object was reached by the collection numbered `sweep`, and whether what it points
+// to has been looked at yet. Objects that weren't reached are white, reached ones are gray until
+// they're traced, then black.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mark {
+    pub sweep: usize,
+    pub traced: bool,
+}
+
 #[derive(Clone)]
 pub struct Heap<T> {
     last_sweep: usize,
-    object_sweeps: HashMap<Handle<T>, usize>,
+    marks: HashMap<Handle<T>, Mark>,
     obj_counter: Generation,
-    objects: HashSet<Handle<T>>,
+    objects: HashMap<Handle<T>, usize>, // with their size when they were last measured
     rooted: HashMap<Handle<T>, Rc<()>>,
+    bytes: usize,
+
+    phase: Phase,
+    gray: Vec<Handle<T>>,
+    again: Vec<Handle<T>>, // changed after being traced, to be traced once more at the end of marking
+    unswept: Vec<Handle<T>>,
+    stats: GcStats,
 }
 
 impl<T> Default for Heap<T> {
     fn default() -> Self {
         Self {
             last_sweep: 0,
-            object_sweeps: HashMap::default(),
+            marks: HashMap::default(),
             obj_counter: 0,
-            objects: HashSet::default(),
+            objects: HashMap::default(),
             rooted: HashMap::default(),
+            bytes: 0,
+            phase: Phase::Idle,
+            gray: Vec::new(),
+            again: Vec::new(),
+            unswept: Vec::new(),
+            stats: GcStats::default(),
         }
     }
 }
 
-impl<T: Trace<T>> Heap<T> {
+impl<T: Trace<T> + HeapSize> Heap<T> {
     /// Create an empty heap.
     pub fn new() -> Self {
         Self::default()
@@ -46,11 +116,20 @@ impl<T: Trace<T>> Heap<T> {
     /// Adds a new object to this heap that will be cleared upon the next garbage collection, if
     /// not attached to the object tree.
     pub fn insert_temp(&mut self, object: T) -> Handle<T> {
+        let size = object.heap_size();
         let ptr = Box::into_raw(Box::new(object));
 
         let gen = self.new_generation();
         let handle = Handle { gen, ptr };
-        self.objects.insert(handle);
+        self.objects.insert(handle, size);
+        self.bytes += size;
+
+        // Born black while marking, so new objects don't keep marking from ever running out of
+        // work. They're likely to hold values just taken off the stack, which are marked now.
+        if self.phase == Phase::Mark {
+            self.marks.insert(handle, Mark { sweep: self.last_sweep, traced: true });
+            unsafe { (*ptr).trace(&mut self.tracer()) }
+        }
 
         handle
     }
@@ -89,10 +168,21 @@ impl<T: Trace<T>> Heap<T> {
         self.objects.len()
     }
 
+    /// Return true if the heap has no objects
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Roughly how many bytes the objects in this heap take up. Objects are measured when they're
+    /// added and again when they're swept, so anything that grew in between is counted late.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
     /// Return true if the heap contains the specified handle
     pub fn contains(&self, handle: impl AsRef<Handle<T>>) -> bool {
         let handle = handle.as_ref();
-        self.objects.contains(&handle)
+        self.objects.contains_key(handle)
     }
 
     /// Get a reference to a heap object if it exists on this heap.
@@ -108,6 +198,8 @@ impl<T: Trace<T>> Heap<T> {
     /// Get a reference to a heap object without checking whether it is still alive or that it
     /// belongs to this heap.
     ///
+    /// # Safety
+    ///
     /// If either invariant is not upheld, calling this function results in undefined
     /// behaviour.
     pub unsafe fn get_unchecked(&self, handle: impl AsRef<Handle<T>>) -> &T {
@@ -120,6 +212,7 @@ impl<T: Trace<T>> Heap<T> {
     pub fn get_mut(&mut self, handle: impl AsRef<Handle<T>>) -> Option<&mut T> {
         let handle = handle.as_ref();
         if self.contains(handle) {
+            self.barrier(*handle);
             Some(unsafe { &mut *handle.ptr })
         } else {
             None
@@ -130,59 +223,207 @@ impl<T: Trace<T>> Heap<T> {
     /// that it belongs to this heap.
     ///
     /// If either invariant is not upheld, calling this function results in undefined
-    /// behaviour. Provided they are upheld, this function provides zero-cost access.
+    /// behaviour. Provided they are upheld, this function only costs something while a
+    /// collection is marking.
     pub fn get_mut_unchecked(&mut self, handle: impl AsRef<Handle<T>>) -> &mut T {
         let handle = handle.as_ref();
         debug_assert!(self.contains(handle));
+        self.barrier(*handle);
         unsafe { &mut *handle.ptr }
     }
 
-    pub fn clean_excluding(&mut self, excluding: impl IntoIterator<Item=Handle<T>>) {
-        let new_sweep = self.last_sweep + 1;
+    // The write barrier: an object that's about to change has to be traced again, or whatever
+    // is stored in it after it was traced would look unreachable. That's left for the end, so
+    // objects changing all the time don't keep marking from running out of work.
+    #[inline]
+    fn barrier(&mut self, handle: Handle<T>) {
+        if self.phase != Phase::Mark {
+            return
+        }
+
+        if let Some(mark) = self.marks.get_mut(&handle) {
+            if mark.sweep == self.last_sweep && mark.traced {
+                mark.traced = false;
+                self.again.push(handle)
+            }
+        }
+    }
+
+    /// Keeps `handle` from being freed by the collection that's running, for when it's stored
+    /// somewhere `get_mut` doesn't see, like an upvalue shared between closures.
+    pub fn shade(&mut self, handle: Handle<T>) {
+        if self.phase == Phase::Mark {
+            self.tracer().mark(handle)
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            live_bytes: self.bytes,
+            ..self.stats
+        }
+    }
+
+    /// Does about `budget` objects worth of collecting, starting a collection if none is running,
+    /// and returns whether it's done. Marking ends with going over `roots` again, since those change
+    /// without going through the heap, and tracing everything changed since it was traced. That's
+    /// done in one go, whatever the budget.
+    pub fn step(&mut self, budget: usize, roots: impl IntoIterator<Item=Handle<T>>) -> bool {
+        let start = Instant::now();
+        let mut roots = Some(roots);
+
+        if self.phase == Phase::Idle {
+            self.last_sweep += 1;
+            self.phase = Phase::Mark;
+
+            self.mark_roots(roots.take().expect("roots are only taken once"));
+        }
+
+        let mut work = 0;
+
+        while work < budget {
+            match self.phase {
+                Phase::Mark => match self.gray.pop() {
+                    Some(handle) => {
+                        self.trace(handle);
+                        work += 1
+                    },
+
+                    None => {
+                        // Nothing's run since the roots were marked if they're gone already
+                        if let Some(roots) = roots.take() {
+                            self.mark_roots(roots);
+                            self.gray.append(&mut self.again);
+
+                            while let Some(handle) = self.gray.pop() {
+                                self.trace(handle);
+                                work += 1
+                            }
+                        }
+
+                        self.phase = Phase::Sweep;
+                        self.unswept = self.objects.keys().copied().collect();
+                    },
+                },
+
+                Phase::Sweep => match self.unswept.pop() {
+                    Some(handle) => {
+                        self.sweep(handle);
+                        work += 1
+                    },
+
+                    None => {
+                        self.phase = Phase::Idle;
+                        self.stats.collections += 1;
+                        break
+                    },
+                },
+
+                Phase::Idle => break,
+            }
+        }
+
+        let pause = start.elapsed();
+
+        self.stats.steps += 1;
+        self.stats.longest_step = self.stats.longest_step.max(work);
+        self.stats.pause += pause;
+        self.stats.longest_pause = self.stats.longest_pause.max(pause);
+
+        self.phase == Phase::Idle
+    }
+
+    fn mark_roots(&mut self, roots: impl IntoIterator<Item=Handle<T>>) {
         let mut tracer = Tracer {
-            new_sweep,
-            object_sweeps: &mut self.object_sweeps,
+            sweep: self.last_sweep,
+            marks: &mut self.marks,
             objects: &self.objects,
+            gray: &mut self.gray,
         };
 
-        // Mark
         self.rooted
             .retain(|handle, rc| {
                 if Rc::strong_count(rc) > 1 {
                     tracer.mark(*handle);
-                    unsafe { (&*handle.ptr).trace(&mut tracer); }
                     true
                 } else {
                     false
                 }
             });
-        let objects = &self.objects;
-        excluding
-            .into_iter()
-            .filter(|handle| objects.contains(&handle))
-            .for_each(|handle| {
-                tracer.mark(handle);
-                unsafe { (&*handle.ptr).trace(&mut tracer); }
-            });
 
-        // Sweep
-        let object_sweeps = &mut self.object_sweeps;
-        self.objects
-            .retain(|handle| {
-                if object_sweeps
-                    .get(handle)
-                    .map(|sweep| *sweep == new_sweep)
-                    .unwrap_or(false)
-                {
-                    true
-                } else {
-                    object_sweeps.remove(handle);
-                    drop(unsafe { Box::from_raw(handle.ptr) });
-                    false
-                }
-            });
+        for handle in roots {
+            tracer.mark(handle)
+        }
+    }
+
+    fn trace(&mut self, handle: Handle<T>) {
+        match self.marks.get_mut(&handle) {
+            Some(mark) if !mark.traced => mark.traced = true,
+            _ => return, // pushed twice, and traced already
+        }
+
+        self.stats.traced += 1;
 
-        self.last_sweep = new_sweep;
+        let object = unsafe { &*handle.ptr };
+        object.trace(&mut self.tracer())
+    }
+
+    // Frees `handle` if it wasn't reached, or measures it again if it was.
+    fn sweep(&mut self, handle: Handle<T>) {
+        let sweep = self.last_sweep;
+        let size = unsafe { (*handle.ptr).heap_size() };
+
+        let measured = self.objects.get_mut(&handle).expect("swept objects are on the heap");
+        self.bytes = self.bytes - *measured + size;
+
+        if self.marks.get(&handle).map(|mark| mark.sweep == sweep).unwrap_or(false) {
+            *measured = size;
+            return
+        }
+
+        self.objects.remove(&handle);
+        self.marks.remove(&handle);
+        self.rooted.remove(&handle);
+
+        self.bytes -= size;
+        self.stats.freed += 1;
+        self.stats.freed_bytes += size;
+
+        drop(unsafe { Box::from_raw(handle.ptr) });
+    }
+
+    fn tracer(&mut self) -> Tracer<'_, T> {
+        Tracer {
+            sweep: self.last_sweep,
+            marks: &mut self.marks,
+            objects: &self.objects,
+            gray: &mut self.gray,
+        }
+    }
+
+    /// Collects everything not reachable from `excluding` or a rooted handle in one go.
+    pub fn clean_excluding(&mut self, excluding: impl IntoIterator<Item=Handle<T>>) {
+        match self.phase {
+            // Nothing's been freed yet, so marking can start over from these roots
+            Phase::Mark => {
+                self.gray.clear();
+                self.again.clear();
+                self.phase = Phase::Idle;
+            },
+
+            // Everything still around was reachable when marking ended, the sweep only has to finish
+            Phase::Sweep => {
+                self.step(usize::MAX, std::iter::empty());
+            },
+
+            Phase::Idle => (),
+        }
+
+        self.step(usize::MAX, excluding);
     }
 
     /// Clean orphaned objects from the heap.
@@ -193,7 +434,7 @@ impl<T: Trace<T>> Heap<T> {
 
 impl<T> Drop for Heap<T> {
     fn drop(&mut self) {
-        for handle in &self.objects {
+        for handle in self.objects.keys() {
             drop(unsafe { Box::from_raw(handle.ptr) });
         }
     }
@@ -206,10 +447,19 @@ pub struct Handle<T> {
 }
 
 impl<T> Handle<T> {
+    /// # Safety
+    ///
+    /// The handle must point to a live object.
     pub unsafe fn get_unchecked(&self) -> &T {
         &*self.ptr
     }
 
+    /// # Safety
+    ///
+    /// The handle must point to a live object, and no other reference to it may be alive. This
+    /// skips the heap's write barrier, so no handles may be stored through it while a collection
+    /// is marking.
+    #[allow(clippy::mut_from_ref)]
     pub unsafe fn get_mut_unchecked(&self) -> &mut T {
         &mut *self.ptr
     }
@@ -218,7 +468,7 @@ impl<T> Handle<T> {
 impl<T> Copy for Handle<T> {}
 impl<T> Clone for Handle<T> {
     fn clone(&self) -> Self {
-        Self { gen: self.gen, ptr: self.ptr }
+        *self
     }
 }
 
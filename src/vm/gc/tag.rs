@@ -9,13 +9,18 @@ pub struct TaggedHandle<T> {
 pub enum Tag<T> {
     Tag(u8),
     Float(f64),
+    Int(i32),
     Handle(Handle<T>),
 }
 
 const QNAN: u64 = 0x7ffc000000000000;
 const SIGN: u64 = 1 << 63;
+const INT:  u64 = 1 << 48; // with the int in the low 32 bits
 
 impl<T> TaggedHandle<T> {
+    /// # Safety
+    ///
+    /// `raw` must be the raw representation of a valid tagged handle, as produced by `to_raw`.
     pub unsafe fn from_raw(raw: u64) -> Self {
         TaggedHandle {
             handle: Handle {
@@ -43,7 +48,16 @@ impl<T> TaggedHandle<T> {
         TaggedHandle {
             handle: Handle {
                 gen: 0,
-                ptr: unsafe { ::std::mem::transmute(float) },
+                ptr: float.to_bits() as *mut T,
+            },
+        }
+    }
+
+    pub fn from_int(int: i32) -> Self {
+        TaggedHandle {
+            handle: Handle {
+                gen: 0,
+                ptr: (QNAN | INT | (int as u32 as u64)) as *mut T,
             },
         }
     }
@@ -52,7 +66,7 @@ impl<T> TaggedHandle<T> {
         TaggedHandle {
             handle: Handle {
                 gen: 0,
-                ptr: unsafe { ::std::mem::transmute(QNAN | (tag as u64)) },
+                ptr: (QNAN | (tag as u64)) as *mut T,
             },
         }
     }
@@ -60,7 +74,7 @@ impl<T> TaggedHandle<T> {
     pub fn decode(self) -> Tag<T> {
         let u = self.handle.ptr as u64;
         if u & QNAN != QNAN {
-            return Tag::Float(unsafe { ::std::mem::transmute(u) });
+            return Tag::Float(f64::from_bits(u));
         }
         if (u & (QNAN | SIGN)) == (QNAN | SIGN) {
             let ptr = u & (!(QNAN | SIGN)); // only keep lower 51 bits
@@ -69,6 +83,9 @@ impl<T> TaggedHandle<T> {
                 ptr: ptr as *mut T,
             });
         }
+        if u & INT != 0 {
+            return Tag::Int(u as u32 as i32);
+        }
         let tag: u8 = (u & 7) as u8;
         Tag::Tag(tag)
     }
@@ -76,7 +93,7 @@ impl<T> TaggedHandle<T> {
 
 impl<T> Clone for TaggedHandle<T> {
     fn clone(&self) -> Self {
-        TaggedHandle { handle: self.handle }
+        *self
     }
 }
 impl<T> Copy for TaggedHandle<T> {}
@@ -5,20 +5,27 @@ pub trait Trace<T: Trace<T>> {
     fn trace(&self, tracer: &mut Tracer<T>);
 }
 
+// Marks what objects point to gray, for the heap to trace later.
 pub struct Tracer<'a, T: Trace<T>> {
-    pub(crate) new_sweep: usize,
-    pub(crate) object_sweeps: &'a mut HashMap<Handle<T>, usize>,
-    pub(crate) objects: &'a HashSet<Handle<T>>,
+    pub(crate) sweep: usize,
+    pub(crate) marks: &'a mut HashMap<Handle<T>, Mark>,
+    pub(crate) objects: &'a HashMap<Handle<T>, usize>,
+    pub(crate) gray: &'a mut Vec<Handle<T>>,
 }
 
 impl<'a, T: Trace<T>> Tracer<'a, T> {
     pub(crate) fn mark(&mut self, handle: Handle<T>) {
-        let sweep = self.object_sweeps
+        if !self.objects.contains_key(&handle) {
+            return
+        }
+
+        let mark = self.marks
             .entry(handle)
-            .or_insert(self.new_sweep - 1);
-        if *sweep != self.new_sweep && self.objects.contains(&handle) {
-            *sweep = self.new_sweep;
-            unsafe { (&*handle.ptr).trace(self); }
+            .or_insert(Mark { sweep: 0, traced: false });
+
+        if mark.sweep != self.sweep {
+            *mark = Mark { sweep: self.sweep, traced: false };
+            self.gray.push(handle);
         }
     }
 }
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+// Stops whatever a VM is running from anywhere else, such as another thread or a timer. Got from
+// `VM::interrupt_handle`, the run fails with `RuntimeErrorKind::Interrupted` the next time it loops
+// back or calls something. That uses up the interrupt, one made while nothing runs stops the next run.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        InterruptHandle::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    // Takes back an interrupt that hasn't stopped anything yet
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::Relaxed)
+    }
+}
+
+// Run every `every` instructions while set with `VM::set_periodic`, stopping the run like an
+// interrupt when it returns false.
+pub(crate) struct Periodic {
+    pub every: u64,
+    pub left: u64, // instructions until it's next run
+    pub callback: Box<dyn FnMut() -> bool>,
+}
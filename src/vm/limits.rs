@@ -0,0 +1,23 @@
+// How far a script may go before it's stopped, set on `vm.limits` to run code that isn't trusted.
+// Going past one is an error like any other, but scripts can't catch running out of fuel or heap,
+// and after either the host may give it more and `resume` where it left off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub fuel: Option<u64>,          // instructions left to run, counting down as they do
+    pub heap_bytes: Option<usize>,  // heap bytes still alive after a collection
+    pub frames: Option<usize>,      // calls deep, counting the function being run
+    pub stack: usize,               // values on the stack
+    pub length: Option<usize>,      // bytes in a string, or entries in a list or dict
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            heap_bytes: None,
+            frames: None,
+            stack: 4096,
+            length: None,
+        }
+    }
+}
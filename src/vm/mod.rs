@@ -1,16 +1,34 @@
 pub mod value;
 #[macro_use]
 pub mod chunk;
+#[allow(clippy::module_inception)]
 pub mod vm;
 pub mod gc;
 pub mod disassembler;
+pub mod error;
+pub mod native;
+pub mod bytecode;
+pub mod verifier;
+pub mod profiler;
+pub mod limits;
+pub mod interrupt;
+#[cfg(feature = "profiling")]
+pub mod profile;
 
 use super::compiler::*;
 use super::ir::*;
 
 pub use self::value::*;
-#[macro_use]
 pub use self::chunk::*;
 pub use self::vm::*;
 pub use self::gc::*;
-pub use self::disassembler::*;
\ No newline at end of file
+pub use self::disassembler::*;
+pub use self::error::*;
+pub use self::native::*;
+pub use self::bytecode::*;
+pub use self::verifier::*;
+pub use self::profiler::*;
+pub use self::limits::*;
+pub use self::interrupt::*;
+#[cfg(feature = "profiling")]
+pub use self::profile::*;
\ No newline at end of file
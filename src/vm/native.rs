@@ -0,0 +1,197 @@
+use super::*;
+
+use std::fmt::{self, Display};
+use std::rc::Rc;
+
+use fnv::FnvBuildHasher;
+use std::collections::HashMap;
+
+// The shape of every native function. Natives are shared, so they can be re-entered by
+// calling back into zub; state that needs to change between calls belongs in a `Cell` or `RefCell`.
+pub type NativeFn = Rc<dyn Fn(&mut NativeContext, &[Value]) -> Result<Value, RuntimeError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(u8),
+    AtLeast(u8),
+    Range(u8, u8),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: u8) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(min) => count >= min,
+            Arity::Range(min, max) => count >= min && count <= max,
+        }
+    }
+}
+
+impl From<u8> for Arity {
+    fn from(n: u8) -> Self {
+        Arity::Exact(n)
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(min) => write!(f, "at least {}", min),
+            Arity::Range(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+// Keeps a value alive while it's only held on to from Rust, e.g. by a native that allocates more
+// than once: any allocation may collect whatever isn't on the stack, in a global or rooted.
+#[derive(Debug, Clone)]
+pub struct Root {
+    value: Value,
+    _rooted: Option<Rooted<Object>>,
+}
+
+impl Root {
+    pub fn value(&self) -> Value {
+        self.value
+    }
+}
+
+impl Heap<Object> {
+    // The value is safe from collection until the guard is dropped.
+    pub fn root(&mut self, value: Value) -> Root {
+        let rooted = value.as_object()
+            .filter(|&handle| self.contains(handle))
+            .map(|handle| self.make_rooted(handle));
+
+        Root {
+            value,
+            _rooted: rooted,
+        }
+    }
+}
+
+// What a native function gets to see of the VM while it runs.
+pub struct NativeContext<'vm> {
+    vm: &'vm mut VM,
+}
+
+impl<'vm> NativeContext<'vm> {
+    pub(crate) fn new(vm: &'vm mut VM) -> Self {
+        NativeContext {
+            vm
+        }
+    }
+
+    pub fn heap(&self) -> &Heap<Object> {
+        &self.vm.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap<Object> {
+        &mut self.vm.heap
+    }
+
+    // Moves an object onto the heap, which may trigger a collection.
+    pub fn allocate(&mut self, object: Object) -> Value {
+        self.vm.allocate(object).into()
+    }
+
+    pub fn globals(&self) -> &HashMap<String, Value, FnvBuildHasher> {
+        &self.vm.globals
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.vm.globals.get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.vm.globals.insert(name.into(), value);
+    }
+
+    // Calls a zub closure or another native and waits for its result.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.vm.call_value(callee, args)
+    }
+
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.vm.call_global(name, args)
+    }
+
+    pub fn into_value(&mut self, value: impl IntoValue) -> Value {
+        value.into_value(&mut self.vm.heap)
+    }
+
+    pub fn from_value<T: FromValue>(&self, value: Value) -> Result<T, RuntimeError> {
+        T::from_value(value, &self.vm.heap).map_err(RuntimeError::from)
+    }
+
+    // Builds an error carrying the current zub call stack.
+    pub fn error<T>(&self, kind: RuntimeErrorKind, message: impl Into<String>) -> Result<T, RuntimeError> {
+        self.vm.runtime_error(kind, message)
+    }
+}
+
+// What a typed native may return, either a plain value or a `Result` of one.
+pub trait NativeReturn {
+    fn into_result(self, heap: &mut Heap<Object>) -> Result<Value, RuntimeError>;
+}
+
+impl<T: IntoValue> NativeReturn for T {
+    fn into_result(self, heap: &mut Heap<Object>) -> Result<Value, RuntimeError> {
+        Ok(self.into_value(heap))
+    }
+}
+
+impl<T: IntoValue> NativeReturn for Result<T, RuntimeError> {
+    fn into_result(self, heap: &mut Heap<Object>) -> Result<Value, RuntimeError> {
+        self.map(|value| value.into_value(heap))
+    }
+}
+
+// Rust closures whose arity and argument conversions are derived from their signature,
+// see `VM::add_typed_native`.
+pub trait TypedNative<Args> {
+    const ARITY: u8;
+
+    fn into_native(self, name: &str) -> NativeFn;
+}
+
+macro_rules! impl_typed_native {
+    ($arity:expr; $($arg:ident),*) => {
+        impl<F, R, $($arg),*> TypedNative<($($arg,)*)> for F
+            where
+                F: Fn($($arg),*) -> R + 'static,
+                R: NativeReturn,
+                $($arg: FromValue),*
+        {
+            const ARITY: u8 = $arity;
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_native(self, name: &str) -> NativeFn {
+                let name = name.to_owned();
+
+                Rc::new(move |context: &mut NativeContext, args: &[Value]| {
+                    let mut args = args.iter().enumerate();
+
+                    let result = self($({
+                        let (i, arg) = args.next().expect("arity is checked before natives are called");
+
+                        $arg::from_value(*arg, context.heap()).or_else(|err| {
+                            context.error(RuntimeErrorKind::Type, format!("argument {} of `{}`: {}", i + 1, name, err))
+                        })?
+                    }),*);
+
+                    result.into_result(context.heap_mut())
+                })
+            }
+        }
+    }
+}
+
+impl_typed_native!(0;);
+impl_typed_native!(1; A);
+impl_typed_native!(2; A, B);
+impl_typed_native!(3; A, B, C);
+impl_typed_native!(4; A, B, C, D);
+impl_typed_native!(5; A, B, C, D, E);
+impl_typed_native!(6; A, B, C, D, E, G);
@@ -0,0 +1,101 @@
+use flame::{ self, Span };
+
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, HashSet };
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    Html,   // an interactive flamegraph
+    Folded, // `a;b;c <nanoseconds>` per stack, as taken by `inferno` and `flamegraph.pl`
+    Json,   // flame's spans as they are, for every thread
+}
+
+// Where the VM writes what it recorded while running with the `profiling` feature, set on
+// `vm.profile`. Time spent running a zub function is recorded under its name, below the
+// function that called it and with the VM methods it ran into below that.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileConfig {
+    pub path: PathBuf,
+    pub format: ProfileFormat,
+}
+
+impl ProfileConfig {
+    pub fn new(path: impl Into<PathBuf>, format: ProfileFormat) -> Self {
+        ProfileConfig {
+            path: path.into(),
+            format,
+        }
+    }
+
+    // Writes everything recorded since the last write, and starts over.
+    pub fn write(&self) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+
+        match self.format {
+            ProfileFormat::Html => flame::dump_html(&mut out)?,
+            ProfileFormat::Json => flame::dump_json(&mut out)?,
+            ProfileFormat::Folded => write_folded(&mut out, &flame::spans())?,
+        }
+
+        flame::clear();
+        out.flush()
+    }
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        ProfileConfig::new("flamegraph.html", ProfileFormat::Html)
+    }
+}
+
+// The name a zub function's spans go by. Spans are started on every call and flame wants
+// names that live forever, so each distinct name is leaked once rather than copied each time.
+pub(crate) fn span_name(name: &str) -> &'static str {
+    thread_local! {
+        static NAMES: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    }
+
+    NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+
+        match names.get(name) {
+            Some(&name) => name,
+            None => {
+                let name: &'static str = Box::leak(name.into());
+                names.insert(name);
+                name
+            }
+        }
+    })
+}
+
+// Self time per distinct stack, so the same function called from the same place adds up to one line.
+pub fn write_folded(out: &mut impl Write, spans: &[Span]) -> io::Result<()> {
+    fn fold(span: &Span, stack: &mut Vec<String>, folded: &mut BTreeMap<String, u64>) {
+        stack.push(span.name.replace(';', ":"));
+
+        let children = span.children.iter().map(|child| child.delta).sum::<u64>();
+        *folded.entry(stack.join(";")).or_insert(0) += span.delta.saturating_sub(children);
+
+        for child in &span.children {
+            fold(child, stack, folded)
+        }
+
+        stack.pop();
+    }
+
+    let mut folded = BTreeMap::new();
+
+    for span in spans {
+        fold(span, &mut Vec::new(), &mut folded)
+    }
+
+    for (stack, nanos) in folded {
+        writeln!(out, "{} {}", stack, nanos)?
+    }
+
+    Ok(())
+}
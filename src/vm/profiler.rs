@@ -0,0 +1,220 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::fmt::{ self, Display };
+use std::io::{ self, Write };
+
+// Identifies the innermost frame well enough to tell when the stack of functions may have changed:
+// how many frames there are, counting those of whoever resumed a coroutine, and where the top one's
+// chunk and slots are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameKey {
+    pub depth: usize,
+    pub resumers: usize,
+    pub stack_start: usize,
+    pub chunk: *const Chunk,
+}
+
+// Counts what scripts do, set on `vm.profiler` to find out where their time goes. Time is measured
+// in instructions run, which is slower to collect than sampling but the same from one run to the next.
+// Functions are told apart by name, the names their chunks were compiled with.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    names: Vec<String>,
+    name_ids: HashMap<String, usize>,
+
+    stacks: Vec<Vec<usize>>, // of name ids, outermost first
+    stack_ids: HashMap<Vec<usize>, usize>,
+    instructions: Vec<u64>, // run with each stack, by the function on top
+
+    lines: HashMap<(usize, usize), u64>, // instructions per function and line
+    calls: HashMap<usize, u64>,
+
+    current: Option<(FrameKey, usize)>, // and the stack it was found to have
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    // Counts an instruction about to run at `ip`. `stack` names the functions on the stack,
+    // outermost first, and is only asked for when the frames look different from last time.
+    pub(crate) fn instruction(&mut self, key: FrameKey, stack: impl FnOnce() -> Vec<String>, chunk: &Chunk, ip: usize) {
+        let id = match self.current {
+            Some((current, id)) if current == key => id,
+            _ => {
+                let names = stack().into_iter().map(|name| self.name_id(name)).collect::<Vec<_>>();
+                let id = self.stack_id(names);
+
+                self.current = Some((key, id));
+                id
+            },
+        };
+
+        self.instructions[id] += 1;
+
+        let function = *self.stacks[id].last().expect("stacks are never empty");
+        *self.lines.entry((function, chunk.line(ip))).or_insert(0) += 1;
+    }
+
+    pub(crate) fn call(&mut self, name: &str) {
+        let id = self.name_id(name.to_owned());
+        *self.calls.entry(id).or_insert(0) += 1;
+    }
+
+    pub fn report(&self) -> Report {
+        let mut functions = self.names.iter().enumerate().map(|(id, name)| {
+            FunctionProfile {
+                name: name.clone(),
+                calls: self.calls.get(&id).copied().unwrap_or(0),
+                inclusive: 0,
+                exclusive: 0,
+            }
+        }).collect::<Vec<_>>();
+
+        for (stack, &count) in self.stacks.iter().zip(self.instructions.iter()) {
+            if let Some(&top) = stack.last() {
+                functions[top].exclusive += count
+            }
+
+            // Recursive calls count once, or they'd take up more than all the time there is
+            let mut seen = Vec::with_capacity(stack.len());
+
+            for &id in stack {
+                if !seen.contains(&id) {
+                    functions[id].inclusive += count;
+                    seen.push(id)
+                }
+            }
+        }
+
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then_with(|| a.name.cmp(&b.name)));
+
+        let mut lines = self.lines.iter().map(|(&(function, line), &instructions)| {
+            LineProfile {
+                function: self.names[function].clone(),
+                line,
+                instructions,
+            }
+        }).collect::<Vec<_>>();
+
+        lines.sort_by(|a, b| {
+            b.instructions.cmp(&a.instructions)
+                .then_with(|| a.function.cmp(&b.function))
+                .then_with(|| a.line.cmp(&b.line))
+        });
+
+        let mut stacks = self.stacks.iter().zip(self.instructions.iter())
+            .filter(|(_, &count)| count > 0)
+            .map(|(stack, &count)| {
+                let names = stack.iter()
+                    .map(|&id| self.names[id].replace(';', ":"))
+                    .collect::<Vec<_>>();
+
+                (names.join(";"), count)
+            })
+            .collect::<Vec<_>>();
+
+        stacks.sort();
+
+        Report {
+            total: self.instructions.iter().sum(),
+            functions,
+            lines,
+            stacks,
+        }
+    }
+
+    fn name_id(&mut self, name: String) -> usize {
+        if let Some(&id) = self.name_ids.get(&name) {
+            return id
+        }
+
+        self.names.push(name.clone());
+        self.name_ids.insert(name, self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    fn stack_id(&mut self, stack: Vec<usize>) -> usize {
+        if let Some(&id) = self.stack_ids.get(&stack) {
+            return id
+        }
+
+        self.stacks.push(stack.clone());
+        self.instructions.push(0);
+        self.stack_ids.insert(stack, self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64, // instructions run by the function and everything it called
+    pub exclusive: u64, // ... by the function itself
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineProfile {
+    pub function: String,
+    pub line: usize,
+    pub instructions: u64,
+}
+
+// What a `Profiler` counted, with the busiest functions and lines first.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub total: u64,
+    pub functions: Vec<FunctionProfile>,
+    pub lines: Vec<LineProfile>,
+    stacks: Vec<(String, u64)>,
+}
+
+impl Report {
+    pub fn function(&self, name: &str) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    // One line per stack of functions, `outer;inner <instructions>`, as taken by `inferno` and `flamegraph.pl`.
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        for (stack, count) in &self.stacks {
+            writeln!(out, "{} {}", stack, count)?
+        }
+
+        Ok(())
+    }
+}
+
+// The function table followed by the 20 hottest lines
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |count: u64| if self.total == 0 { 0.0 } else { count as f64 * 100.0 / self.total as f64 };
+
+        writeln!(f, "{:<24} {:>10} {:>14} {:>7} {:>14} {:>7}", "function", "calls", "inclusive", "%", "exclusive", "%")?;
+
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:<24} {:>10} {:>14} {:>6.1}% {:>14} {:>6.1}%",
+                function.name,
+                function.calls,
+                function.inclusive,
+                percent(function.inclusive),
+                function.exclusive,
+                percent(function.exclusive),
+            )?
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<32} {:>14} {:>7}", "line", "instructions", "%")?;
+
+        for line in self.lines.iter().take(20) {
+            let at = format!("{}:{}", line.function, line.line);
+            writeln!(f, "{:<32} {:>14} {:>6.1}%", at, line.instructions, percent(line.instructions))?
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use super::super::gc::*;
+use super::super::error::*;
+use super::*;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::hash::BuildHasher;
+
+// Raised when a `Value` doesn't have the shape a Rust type expects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl ConversionError {
+    pub fn new(expected: &'static str, value: Value, heap: &Heap<Object>) -> Self {
+        ConversionError {
+            expected,
+            found: value.type_name(heap),
+        }
+    }
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ConversionError> for RuntimeError {
+    fn from(err: ConversionError) -> Self {
+        RuntimeError::new(RuntimeErrorKind::Type, err.to_string())
+    }
+}
+
+pub trait IntoValue {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value;
+}
+
+pub trait FromValue: Sized {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError>;
+}
+
+// Objects created during conversion aren't rooted, they must end up somewhere the GC can see them.
+fn allocate(heap: &mut Heap<Object>, object: Object) -> Value {
+    heap.insert(object).into_handle().into()
+}
+
+fn object<'h>(expected: &'static str, value: Value, heap: &'h Heap<Object>) -> Result<&'h Object, ConversionError> {
+    value.as_object()
+        .and_then(|o| heap.get(o))
+        .ok_or_else(|| ConversionError::new(expected, value, heap))
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut Heap<Object>) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value, _: &Heap<Object>) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &mut Heap<Object>) -> Value {
+        Value::nil()
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        match value.decode() {
+            Variant::Nil => Ok(()),
+            _ => Err(ConversionError::new("nil", value, heap)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut Heap<Object>) -> Value {
+        self.into()
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        match value.decode() {
+            Variant::True => Ok(true),
+            Variant::False => Ok(false),
+            _ => Err(ConversionError::new("bool", value, heap)),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut Heap<Object>) -> Value {
+        Value::float(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        value.as_number()
+            .map(Number::to_f64)
+            .ok_or_else(|| ConversionError::new("number", value, heap))
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self, _: &mut Heap<Object>) -> Value {
+        Value::float(self as f64)
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        f64::from_value(value, heap).map(|n| n as f32)
+    }
+}
+
+// Integers become ints when they fit in 32 bits and floats otherwise. They convert back from either,
+// as long as they're whole and in range.
+macro_rules! impl_integer {
+    ($($typ:ty),*) => {
+        $(
+            impl IntoValue for $typ {
+                fn into_value(self, _: &mut Heap<Object>) -> Value {
+                    match i32::try_from(self) {
+                        Ok(n) => Value::int(n),
+                        Err(_) => Value::float(self as f64),
+                    }
+                }
+            }
+
+            impl FromValue for $typ {
+                fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+                    match value.decode() {
+                        Variant::Int(n) => <$typ>::try_from(n).map_err(|_| ConversionError::new(stringify!($typ), value, heap)),
+                        Variant::Float(n) if n.fract() == 0.0
+                            && n >= <$typ>::MIN as f64
+                            && n <= <$typ>::MAX as f64 => Ok(n as $typ),
+                        _ => Err(ConversionError::new(stringify!($typ), value, heap)),
+                    }
+                }
+            }
+        )*
+    }
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoValue for String {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value {
+        allocate(heap, Object::String(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value {
+        allocate(heap, Object::String(self.to_owned()))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        object("string", value, heap)?
+            .as_string()
+            .cloned()
+            .ok_or_else(|| ConversionError::new("string", value, heap))
+    }
+}
+
+// `None` is nil, and nil is `None`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value {
+        match self {
+            Some(value) => value.into_value(heap),
+            None => Value::nil(),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        match value.decode() {
+            Variant::Nil => Ok(None),
+            _ => T::from_value(value, heap).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value {
+        let content = self.into_iter()
+            .map(|element| element.into_value(heap))
+            .collect();
+
+        allocate(heap, Object::List(List::new(content)))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        object("list", value, heap)?
+            .as_list()
+            .ok_or_else(|| ConversionError::new("list", value, heap))?
+            .content
+            .iter()
+            .map(|element| T::from_value(*element, heap))
+            .collect()
+    }
+}
+
+impl<T: IntoValue, S: BuildHasher> IntoValue for HashMap<String, T, S> {
+    fn into_value(self, heap: &mut Heap<Object>) -> Value {
+        let mut dict = Dict::empty();
+
+        for (key, value) in self {
+            let key = HashValue { variant: HashVariant::Str(key) };
+            let value = value.into_value(heap);
+
+            dict.insert(key, value)
+        }
+
+        allocate(heap, Object::Dict(dict))
+    }
+}
+
+impl<T: FromValue, S: BuildHasher + Default> FromValue for HashMap<String, T, S> {
+    fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+        let dict = object("dict", value, heap)?
+            .as_dict()
+            .ok_or_else(|| ConversionError::new("dict", value, heap))?;
+
+        let mut map = HashMap::with_capacity_and_hasher(dict.content.len(), S::default());
+
+        for (key, element) in dict.content.iter() {
+            let key = match key.variant {
+                HashVariant::Str(ref key) => key.clone(),
+                _ => return Err(ConversionError { expected: "string key", found: "non-string key" }),
+            };
+
+            map.insert(key, T::from_value(*element, heap)?);
+        }
+
+        Ok(map)
+    }
+}
+
+// Tuples travel as lists of a fixed length.
+macro_rules! impl_tuple {
+    ($len:expr; $($name:ident),+) => {
+        impl<$($name: IntoValue),+> IntoValue for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_value(self, heap: &mut Heap<Object>) -> Value {
+                let ($($name,)+) = self;
+                let content = vec![$($name.into_value(heap)),+];
+
+                allocate(heap, Object::List(List::new(content)))
+            }
+        }
+
+        impl<$($name: FromValue),+> FromValue for ($($name,)+) {
+            fn from_value(value: Value, heap: &Heap<Object>) -> Result<Self, ConversionError> {
+                let expected = concat!("list of length ", stringify!($len));
+
+                let content = &object(expected, value, heap)?
+                    .as_list()
+                    .ok_or_else(|| ConversionError::new(expected, value, heap))?
+                    .content;
+
+                if content.len() != $len {
+                    return Err(ConversionError { expected, found: "list of another length" })
+                }
+
+                let mut content = content.iter();
+
+                Ok(($($name::from_value(*content.next().unwrap(), heap)?,)+))
+            }
+        }
+    }
+}
+
+impl_tuple!(1; A);
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
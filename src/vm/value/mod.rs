@@ -1,7 +1,12 @@
+#[allow(clippy::module_inception)]
 pub mod value;
 pub mod object;
+pub mod convert;
+pub mod number;
 
 use super::*;
 
 pub use self::value::*;
 pub use self::object::*;
+pub use self::convert::*;
+pub use self::number::*;
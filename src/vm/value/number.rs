@@ -0,0 +1,150 @@
+use std::cmp::Ordering;
+use std::fmt::{ self, Display };
+use std::ops::Neg;
+
+// A number as scripts see it. Ints are 32 bits, and anything done to two of them stays an int as
+// long as the result is one, otherwise it's done to floats instead: adding past `i32::MAX` and
+// dividing unevenly give floats, much as they did when every number was one. Anything with a
+// float in it is done to floats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i32),
+    Float(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    IntDiv, // rounds towards zero, what `/` does for expressions typed `Type::Int`
+}
+
+// Only ever done to ints. Shifts take the count mod 32, as Rust's `wrapping_shl` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bitwise {
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl Arith {
+    pub fn symbol(self) -> &'static str {
+        use self::Arith::*;
+
+        match self {
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "/",
+            Rem => "%",
+            Pow => "^",
+            IntDiv => "//",
+        }
+    }
+}
+
+impl Bitwise {
+    pub fn symbol(self) -> &'static str {
+        use self::Bitwise::*;
+
+        match self {
+            And => "&",
+            Or => "|",
+            Xor => "^",
+            Shl => "<<",
+            Shr => ">>",
+        }
+    }
+
+    pub fn apply(self, a: i32, b: i32) -> i32 {
+        use self::Bitwise::*;
+
+        match self {
+            And => a & b,
+            Or => a | b,
+            Xor => a ^ b,
+            Shl => a.wrapping_shl(b as u32),
+            Shr => a.wrapping_shr(b as u32),
+        }
+    }
+}
+
+impl Number {
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+
+    // `None` only for ints divided by zero with `IntDiv`, floats give infinity or NaN as always
+    pub fn apply(self, op: Arith, rhs: Number) -> Option<Number> {
+        use self::Arith::*;
+
+        if let (Number::Int(a), Number::Int(b)) = (self, rhs) {
+            let int = match op {
+                Add => a.checked_add(b),
+                Sub => a.checked_sub(b),
+                Mul => a.checked_mul(b),
+                Div => a.checked_rem(b).filter(|&rem| rem == 0).and_then(|_| a.checked_div(b)),
+                Rem => a.checked_rem(b),
+                Pow if b >= 0 => a.checked_pow(b as u32),
+                Pow => None,
+                IntDiv if b == 0 => return None,
+                IntDiv => a.checked_div(b),
+            };
+
+            if let Some(int) = int {
+                return Some(Number::Int(int))
+            }
+        }
+
+        let (a, b) = (self.to_f64(), rhs.to_f64());
+
+        let float = match op {
+            Add => a + b,
+            Sub => a - b,
+            Mul => a * b,
+            Div => a / b,
+            Rem => a % b,
+            Pow => a.powf(b),
+            IntDiv => (a / b).trunc(),
+        };
+
+        Some(Number::Float(float))
+    }
+
+    // Ints and floats compare by value, every int has a float of its own. NaN is unordered.
+    pub fn compare(self, rhs: Number) -> Option<Ordering> {
+        match (self, rhs) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+            (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+        }
+    }
+}
+
+impl Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Number {
+        match self {
+            Number::Int(n) => n.checked_neg().map_or(Number::Float(-(n as f64)), Number::Int),
+            Number::Float(n) => Number::Float(-n),
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{}", n),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use super::super::gc::{ *, tag::*, trace::* };
+use super::super::gc::trace::*;
 use super::*;
 
 use std::fmt::{Debug, Display};
+use std::mem;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -26,7 +27,11 @@ pub enum Object {
     NativeFunction(NativeFunction),
     Closure(Closure),
     List(List),
-    Dict(Dict)
+    Dict(Dict),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Coroutine(Coroutine),
 }
 
 impl Object {
@@ -35,8 +40,28 @@ impl Object {
     impl_as!(as_function, Function);
     impl_as!(as_list, List);
     impl_as!(as_dict, Dict);
+    impl_as!(as_class, Class);
+    impl_as!(as_instance, Instance);
+    impl_as!(as_bound_method, BoundMethod);
+    impl_as!(as_coroutine, Coroutine);
 
-    pub fn native_fn(name: &str, arity: u8, function: fn(&mut Heap<Object>, &[Value]) -> Value) -> Self {
+    pub fn type_name(&self) -> &'static str {
+        use self::Object::*;
+
+        match self {
+            String(_) => "string",
+            Function(_) | Closure(_) => "function",
+            NativeFunction(_) => "native function",
+            List(_) => "list",
+            Dict(_) => "dict",
+            Class(_) => "class",
+            Instance(_) => "instance",
+            BoundMethod(_) => "method",
+            Coroutine(_) => "coroutine",
+        }
+    }
+
+    pub fn native_fn(name: &str, arity: Arity, function: NativeFn) -> Self {
         Object::NativeFunction(
             NativeFunction {
                 name: name.into(),
@@ -53,6 +78,22 @@ impl Object {
             None
         }
     }
+
+    pub fn as_instance_mut(&mut self) -> Option<&mut Instance> {
+        if let Object::Instance(ref mut o) = *self {
+            Some(o)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_coroutine_mut(&mut self) -> Option<&mut Coroutine> {
+        if let Object::Coroutine(ref mut o) = *self {
+            Some(o)
+        } else {
+            None
+        }
+    }
 }
 
 impl Trace<Self> for Object {
@@ -65,11 +106,51 @@ impl Trace<Self> for Object {
             NativeFunction(_) => {},
             Closure(c) => c.trace(tracer),
             List(l) => l.trace(tracer),
-            Dict(d) => d.trace(tracer)
+            Dict(d) => d.trace(tracer),
+            Class(c) => c.trace(tracer),
+            Instance(i) => i.trace(tracer),
+            BoundMethod(b) => b.trace(tracer),
+            Coroutine(c) => c.trace(tracer),
         }
     }
 }
 
+// Counts what's owned directly, not objects only pointed to, and hashed collections by their entries.
+impl HeapSize for Object {
+    fn heap_size(&self) -> usize {
+        use self::Object::*;
+
+        let owned = match self {
+            String(s) => s.capacity(),
+            Function(f) => f.heap_size(),
+            NativeFunction(n) => n.name.capacity(),
+            Closure(c) => c.function.heap_size() + c.upvalues.capacity() * mem::size_of::<UpValue>(),
+            List(l) => l.content.capacity() * mem::size_of::<Value>(),
+            Dict(d) => d.content.len() * mem::size_of::<(HashValue, Value)>(),
+            Class(c) => {
+                c.name.capacity() + c.methods.keys()
+                    .map(|name| name.capacity() + mem::size_of::<(std::string::String, Handle<Object>)>())
+                    .sum::<usize>()
+            },
+            Instance(i) => {
+                i.fields.keys()
+                    .map(|name| name.capacity() + mem::size_of::<(std::string::String, Value)>())
+                    .sum()
+            },
+            BoundMethod(_) => 0,
+            Coroutine(c) => {
+                let context = &c.context;
+
+                context.stack.capacity() * mem::size_of::<Value>()
+                    + context.frames.capacity() * mem::size_of::<CallFrame>()
+                    + context.upvalues.capacity() * mem::size_of::<(usize, UpValue)>()
+            },
+        };
+
+        mem::size_of::<Self>() + owned
+    }
+}
+
 impl Debug for Object {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         use self::Object::*;
@@ -81,6 +162,10 @@ impl Debug for Object {
             Closure(ref cl) => write!(f, "<closure {:?}>", cl.function),
             List(ref ls) => write!(f, "<list [{:?}]>", ls.content.len()),
             Dict(ref dict) => write!(f, "<dict [{:?}]>", dict.content.len()),
+            Class(ref class) => write!(f, "<class {:?}>", class.name),
+            Instance(ref instance) => write!(f, "<instance [{:?}]>", instance.fields.len()),
+            BoundMethod(ref bound) => write!(f, "<bound method {:?}>", bound.method),
+            Coroutine(ref coroutine) => write!(f, "<coroutine {}>", coroutine.status),
         }
     }
 }
@@ -96,6 +181,18 @@ impl<'h, 'a> Display for WithHeap<'h, &'a Object> {
             Closure(ref cl) => write!(f, "<fn {}>", cl.function.name),
             List(ref ls) => write!(f, "<list [{}]>", ls.content.len()),
             Dict(ref ls) => write!(f, "<dict [{}]>", ls.content.len()),
+            Class(ref class) => write!(f, "<class {}>", class.name),
+            Instance(ref instance) => {
+                let class = self.heap.get(instance.class).ok_or(::std::fmt::Error)?;
+                let name = class.as_class().map(|c| c.name.as_str()).unwrap_or("?");
+
+                write!(f, "<{} instance>", name)
+            },
+            BoundMethod(ref bound) => {
+                let method = self.heap.get(bound.method).ok_or(::std::fmt::Error)?;
+                write!(f, "{}", self.with(method))
+            },
+            Coroutine(ref coroutine) => write!(f, "<coroutine {}>", coroutine.status),
         }
     }
 }
@@ -158,11 +255,22 @@ impl Function {
         &self.chunk
     }
 
+    pub fn arity(&self) -> u8 {
+        self.arity
+    }
+
     pub fn upvalue_count(&self) -> usize {
         self.upvalue_count
     }
 }
 
+impl Function {
+    // The name and chunk, which closures carry copies of
+    fn heap_size(&self) -> usize {
+        self.name.capacity() + self.chunk.heap_size()
+    }
+}
+
 impl Trace<Object> for Function {
     fn trace(&self, tracer: &mut Tracer<Object>) {
         self.chunk.trace(tracer);
@@ -172,8 +280,8 @@ impl Trace<Object> for Function {
 #[derive(Clone)]
 pub struct NativeFunction {
     pub name: String,
-    pub arity: u8,
-    pub function: fn(&mut Heap<Object>, &[Value]) -> Value,
+    pub arity: Arity,
+    pub function: NativeFn,
 }
 
 #[derive(Debug, Clone)]
@@ -200,7 +308,7 @@ impl UpValue {
     }
 
     pub fn get(&self) -> Result<Value, usize> {
-        self.inner.borrow().clone()
+        *self.inner.borrow()
     }
 
     pub fn set(&mut self, value: Value) -> Result<(), usize> {
@@ -211,6 +319,11 @@ impl UpValue {
 
         Ok(())
     }
+
+    // Points a closed upvalue back at a stack slot, used when a suspended context runs again.
+    pub fn reopen(&mut self, local: usize) {
+        *self.inner.borrow_mut() = Err(local)
+    }
 }
 
 pub struct Dict {
@@ -278,7 +391,7 @@ impl List {
 
     #[inline]
     pub fn get(&self, idx: usize) -> Value {
-        self.content[idx].clone() // Might not have to use a clone here
+        self.content[idx]
     }
 }
 
@@ -334,3 +447,120 @@ impl Trace<Object> for Closure {
     }
 }
 
+
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Handle<Object>>, // closures, taking their receiver as `self`
+}
+
+impl Class {
+    pub fn new(name: &str, methods: HashMap<String, Handle<Object>>) -> Self {
+        Class {
+            name: name.into(),
+            methods,
+        }
+    }
+
+    pub fn method(&self, name: &str) -> Option<Handle<Object>> {
+        self.methods.get(name).cloned()
+    }
+}
+
+impl Trace<Object> for Class {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.methods.values().for_each(|m| m.trace(tracer));
+    }
+}
+
+pub struct Instance {
+    pub class: Handle<Object>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Handle<Object>) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl Trace<Object> for Instance {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.class.trace(tracer);
+        self.fields.values().for_each(|v| v.trace(tracer));
+    }
+}
+
+// A method closure that remembers the instance it was read from.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Handle<Object>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Handle<Object>) -> Self {
+        BoundMethod {
+            receiver,
+            method,
+        }
+    }
+}
+
+impl Trace<Object> for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.receiver.trace(tracer);
+        self.method.trace(tracer);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    Dead,
+}
+
+impl Display for CoroutineStatus {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let name = match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Dead => "dead",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// A function with a stack of its own, which can pause itself with `yield`. While it runs,
+// its context is swapped into the VM and this holds on to an empty one.
+pub struct Coroutine {
+    pub status: CoroutineStatus,
+    pub(crate) context: ExecutionContext,
+}
+
+impl Coroutine {
+    pub fn new(closure: Handle<Object>) -> Self {
+        Coroutine {
+            status: CoroutineStatus::Suspended,
+            context: ExecutionContext::new(vec![closure.into()]),
+        }
+    }
+
+    // Whether it has been resumed before, and its function has been called.
+    pub fn started(&self) -> bool {
+        !self.context.frames.is_empty()
+    }
+
+    pub fn function(&self) -> Option<Handle<Object>> {
+        self.context.stack.first().and_then(Value::as_object)
+    }
+}
+
+impl Trace<Object> for Coroutine {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.context.trace(tracer);
+    }
+}
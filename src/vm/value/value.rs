@@ -2,7 +2,6 @@ use super::super::gc::{ *, tag::*, trace::* };
 use super::*;
 
 use std::fmt::{Debug, Display};
-use std::mem;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Value {
@@ -25,6 +24,7 @@ pub struct HashValue {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Variant {
     Float(f64),
+    Int(i32),
     True,
     False,
     Nil,
@@ -32,27 +32,27 @@ pub enum Variant {
 }
 
 impl Variant {
-    pub fn to_hash(&self, heap: &Heap<Object>) -> HashVariant {
+    /// Turn the variant into a dictionary key. Only strings are hashable objects, so any other
+    /// object yields `None`.
+    pub fn to_hash(&self, heap: &Heap<Object>) -> Option<HashVariant> {
         use self::Variant::*;
 
-        match *self {
-            Float(ref f) => {
-                unsafe {
-                    HashVariant::Int(
-                        mem::transmute::<f64, i64>(*f)
-                    )
-                }
-            },
+        let variant = match *self {
+            Float(ref f) => HashVariant::Int(f.to_bits() as i64),
+            // The same key as the float it equals
+            Int(n) => HashVariant::Int((n as f64).to_bits() as i64),
 
             True  => HashVariant::Bool(true),
             False => HashVariant::Bool(false),
 
             Obj(ref n) => unsafe {
-                HashVariant::Str(heap.get_unchecked(n).as_string().unwrap().clone().to_string())
+                HashVariant::Str(heap.get_unchecked(n).as_string()?.clone())
             },
 
             Nil => HashVariant::Nil,
-        }
+        };
+
+        Some(variant)
     }
 }
 
@@ -61,6 +61,10 @@ const TAG_FALSE: u8 = 0x02;
 const TAG_NIL:   u8 = 0x03;
 
 impl Value {
+    /// # Safety
+    ///
+    /// `raw` must have been produced by `Value::to_raw`, and any object it refers to must still
+    /// be alive.
     #[inline]
     pub unsafe fn from_raw(raw: u64) -> Self {
         Value {
@@ -72,21 +76,31 @@ impl Value {
         self.handle.to_raw()
     }
 
+    // Ints too, as the float they equal
     #[inline]
     pub fn as_float(&self) -> f64 {
-        if let Variant::Float(f) = self.decode() {
-            return f
+        match self.as_number() {
+            Some(n) => n.to_f64(),
+            None => panic!("non-number"),
         }
+    }
 
-        panic!("non-float")
+    #[inline]
+    pub fn as_number(&self) -> Option<Number> {
+        match self.decode() {
+            Variant::Float(n) => Some(Number::Float(n)),
+            Variant::Int(n) => Some(Number::Int(n)),
+            _ => None,
+        }
     }
 
     #[inline]
     pub fn decode(&self) -> Variant {
         use self::Tag::*;
 
-        match self.handle.clone().decode() {
+        match self.handle.decode() {
             Float(n) => Variant::Float(n),
+            Int(n) => Variant::Int(n),
             Handle(n) => Variant::Obj(n),
             Tag(t) if t == TAG_TRUE  => Variant::True,
             Tag(t) if t == TAG_FALSE => Variant::False,
@@ -96,13 +110,23 @@ impl Value {
     }
 
     #[inline]
-    pub fn as_object<'a>(&self) -> Option<Handle<Object>> {
+    pub fn as_object(&self) -> Option<Handle<Object>> {
         match self.decode() {
             Variant::Obj(o) => Some(o),
             _ => None,
         }
     }
 
+    pub fn type_name(&self, heap: &Heap<Object>) -> &'static str {
+        match self.decode() {
+            Variant::Float(_) => "number",
+            Variant::Int(_) => "int",
+            Variant::True | Variant::False => "bool",
+            Variant::Nil => "nil",
+            Variant::Obj(o) => heap.get(o).map(Object::type_name).unwrap_or("object"),
+        }
+    }
+
     pub fn with_heap<'h>(&self, heap: &'h Heap<Object>) -> WithHeap<'h, Self> {
         WithHeap::new(heap, *self)
     }
@@ -113,6 +137,12 @@ impl Value {
         }
     }
 
+    pub fn int(int: i32) -> Self {
+        Value {
+            handle: TaggedHandle::from_int(int),
+        }
+    }
+
     pub fn truelit() -> Self {
         Value {
             handle: TaggedHandle::from_tag(TAG_TRUE),
@@ -126,10 +156,7 @@ impl Value {
     }
 
     pub fn truthy(&self) -> bool {
-        match self.decode() {
-            Variant::False | Variant::Nil => false,
-            _ => true,
-        }
+        !matches!(self.decode(), Variant::False | Variant::Nil)
     }
 
     pub fn nil() -> Self {
@@ -166,20 +193,36 @@ impl Debug for Value {
             Variant::False => write!(f, "false"),
             Variant::True => write!(f, "true"),
             Variant::Float(n) => write!(f, "{:?}", n),
+            Variant::Int(n) => write!(f, "{}", n),
             Variant::Obj(o) => write!(f, "{:?}", o),
         }
     }
 }
 
-impl Into<Value> for f64 {
-    fn into(self) -> Value {
-        Value::float(self)
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Value::float(float)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(int: i32) -> Self {
+        Value::int(int)
+    }
+}
+
+impl From<Number> for Value {
+    fn from(number: Number) -> Self {
+        match number {
+            Number::Int(n) => Value::int(n),
+            Number::Float(n) => Value::float(n),
+        }
     }
 }
 
-impl Into<Value> for bool {
-    fn into(self) -> Value {
-        if self {
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        if b {
             Value::truelit()
         } else {
             Value::falselit()
@@ -197,7 +240,7 @@ impl<'h, T> WithHeap<'h, T> {
         WithHeap { heap, item }
     }
 
-    pub fn with<U>(&self, item: U) -> WithHeap<U> {
+    pub fn with<U>(&self, item: U) -> WithHeap<'_, U> {
         WithHeap { heap: self.heap, item }
     }
 }
@@ -209,6 +252,7 @@ impl<'h> Display for WithHeap<'h, Value> {
             Variant::False => write!(f, "false"),
             Variant::True => write!(f, "true"),
             Variant::Float(n) => write!(f, "{}", n),
+            Variant::Int(n) => write!(f, "{}", n),
             Variant::Obj(o) => {
                 let o = self.heap.get(o).ok_or(::std::fmt::Error)?;
                 write!(f, "{}", self.with(o))
@@ -0,0 +1,559 @@
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::mem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning, // harmless to run, but not what the compiler means to produce
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub function: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        write!(f, "{} in `{}` at {:04}: {}", severity, self.function, self.offset, self.message)
+    }
+}
+
+// Where control can go after an instruction.
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),  // either way, with the stack as it is
+    Handler(usize), // either way, the handler getting the thrown value on top
+    Stop,
+}
+
+#[derive(Debug)]
+struct Instruction {
+    start: usize,
+    end: usize,
+    pops: usize,
+    pushes: usize,
+    locals: Vec<usize>, // slots read or written, which must exist
+    captures: Vec<usize>, // slots closed over, which may be the one the closure itself goes into
+    flow: Flow,
+}
+
+// Where an instruction sits in the chunk, and where it can jump to. Enough for passes rewriting
+// code to move instructions around without knowing the layout of every opcode.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub target: Option<usize>,
+}
+
+// Checks a function's bytecode, and every function nested in its constants, before it's run.
+// `decode_op!` and `CallFrame` trust the bytes completely, so anything not produced by our own
+// compiler should go through here first. Bytecode read with `VM::load_bytecode` always does.
+pub struct Verifier<'c> {
+    function: &'c Function,
+    heap: &'c Heap<Object>,
+    offset: usize,
+    truncated: bool,
+    current: Instruction,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'c> Verifier<'c> {
+    pub fn new(function: &'c Function, heap: &'c Heap<Object>) -> Self {
+        Verifier {
+            function,
+            heap,
+            offset: 0,
+            truncated: false,
+            current: Instruction::new(0),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn verify(mut self) -> Vec<Diagnostic> {
+        let instructions = self.decode();
+
+        self.check_targets(&instructions);
+        self.check_stack(&instructions);
+
+        for constant in self.chunk().constants() {
+            if let Some(function) = constant.as_object().and_then(|o| self.heap.get(o)).and_then(Object::as_function) {
+                let nested = Verifier::new(function, self.heap).verify();
+                self.diagnostics.extend(nested)
+            }
+        }
+
+        self.diagnostics
+    }
+
+    // Lays out the function's own instructions, or gives `None` when they don't decode cleanly.
+    pub(crate) fn spans(mut self) -> Option<Vec<Span>> {
+        let instructions = self.decode();
+
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            return None
+        }
+
+        let spans = instructions.iter().map(|inst| {
+            let target = match inst.flow {
+                Flow::Jump(target) | Flow::Branch(target) | Flow::Handler(target) => Some(target),
+                Flow::Next | Flow::Stop => None,
+            };
+
+            Span { start: inst.start, end: inst.end, target }
+        });
+
+        Some(spans.collect())
+    }
+
+    fn decode(&mut self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+
+        while self.offset < self.chunk().len() {
+            self.current = Instruction::new(self.offset);
+
+            let inst = self.read_byte();
+            decode_op!(inst, self);
+
+            if self.truncated {
+                let start = self.current.start;
+                self.error(start, "instruction runs past the end of the chunk");
+                break
+            }
+
+            self.current.end = self.offset;
+            instructions.push(mem::replace(&mut self.current, Instruction::new(self.offset)))
+        }
+
+        instructions
+    }
+
+    fn check_targets(&mut self, instructions: &[Instruction]) {
+        let starts = instructions.iter().map(|i| i.start).collect::<HashSet<_>>();
+
+        for instruction in instructions {
+            let target = match instruction.flow {
+                Flow::Jump(target) | Flow::Branch(target) | Flow::Handler(target) => target,
+                _ => continue,
+            };
+
+            if !starts.contains(&target) {
+                self.error(instruction.start, format!("jump to {}, which isn't the start of an instruction", target))
+            }
+        }
+    }
+
+    // Follows every path through the function, tracking how deep the stack is relative to the frame.
+    fn check_stack(&mut self, instructions: &[Instruction]) {
+        let index_of = instructions.iter()
+            .enumerate()
+            .map(|(i, inst)| (inst.start, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        let mut warned = HashSet::new();
+        let mut pending = Vec::new();
+
+        // The callee and its arguments
+        if !instructions.is_empty() {
+            pending.push((0, self.function.arity() as usize + 1));
+        }
+
+        while let Some((i, depth)) = pending.pop() {
+            match depths[i] {
+                Some(known) if known == depth => continue,
+                // Paths disagreeing, e.g. loops leaving values behind each time around. Carry on with
+                // the shallower one, which is what every check below has to hold for.
+                Some(known) => {
+                    if warned.insert(i) {
+                        self.warning(instructions[i].start, format!("stack depth is {} or {} depending on the path here", known, depth));
+                    }
+
+                    if known < depth {
+                        continue
+                    }
+                },
+                None => (),
+            }
+
+            depths[i] = Some(depth);
+
+            let inst = &instructions[i];
+
+            if depth < inst.pops {
+                self.error(inst.start, format!("pops {} value(s) from a stack of {}", inst.pops, depth));
+                continue
+            }
+
+            for &slot in &inst.locals {
+                if slot >= depth {
+                    self.error(inst.start, format!("local slot {} is out of range, the stack only holds {}", slot, depth))
+                }
+            }
+
+            let after = depth - inst.pops + inst.pushes;
+
+            // Local functions capture themselves, so they can recurse
+            for &slot in &inst.captures {
+                if slot >= after {
+                    self.error(inst.start, format!("captured slot {} is out of range, the stack only holds {}", slot, after))
+                }
+            }
+
+            let mut follow = |start: usize, depth: usize, this: &mut Self| {
+                if start == this.chunk().len() {
+                    this.error(inst.start, "execution runs off the end of the chunk");
+                } else if let Some(&next) = index_of.get(&start) {
+                    pending.push((next, depth))
+                }
+            };
+
+            match inst.flow {
+                Flow::Next => follow(inst.end, after, self),
+                Flow::Jump(target) => follow(target, after, self),
+                Flow::Branch(target) => {
+                    follow(inst.end, after, self);
+                    follow(target, after, self)
+                },
+                Flow::Handler(target) => {
+                    follow(inst.end, after, self);
+                    follow(target, after + 1, self)
+                },
+                Flow::Stop => (),
+            }
+        }
+    }
+
+    fn chunk(&self) -> &'c Chunk {
+        self.function.chunk()
+    }
+
+    fn error(&mut self, offset: usize, message: impl Into<String>) {
+        self.diagnose(Severity::Error, offset, message.into())
+    }
+
+    fn warning(&mut self, offset: usize, message: impl Into<String>) {
+        self.diagnose(Severity::Warning, offset, message.into())
+    }
+
+    fn diagnose(&mut self, severity: Severity, offset: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            function: self.function.name().to_owned(),
+            offset,
+            message,
+        })
+    }
+
+    fn effect(&mut self, pops: usize, pushes: usize) {
+        self.current.pops = pops;
+        self.current.pushes = pushes;
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        if self.offset >= self.chunk().len() {
+            self.truncated = true;
+            return 0
+        }
+
+        self.offset += 1;
+        self.chunk().get(self.offset - 1)
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let lo = self.read_byte() as u16;
+        let hi = self.read_byte() as u16;
+        lo + (hi << 8)
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let lo = self.read_u16() as u32;
+        let hi = self.read_u16() as u32;
+        lo + (hi << 16)
+    }
+
+    fn read_u64(&mut self) -> u64 {
+        let lo = self.read_u32() as u64;
+        let hi = self.read_u32() as u64;
+        lo + (hi << 32)
+    }
+
+    // Objects only get into code through the constants, an immediate pointing at one would be
+    // dereferenced wherever it happens to point.
+    fn immediate_value(&mut self) {
+        let start = self.current.start;
+        let raw = self.read_u64();
+
+        if self.truncated {
+            return
+        }
+
+        // Decoding only looks at the bits, nothing is dereferenced
+        if let Variant::Obj(_) = unsafe { Value::from_raw(raw) }.decode() {
+            self.error(start, format!("immediate {:#018x} is an object handle", raw))
+        }
+    }
+
+    fn constant_at(&mut self, idx: usize) -> Option<&'c Object> {
+        let start = self.current.start;
+
+        match self.chunk().get_constant(idx) {
+            Some(value) => value.as_object().and_then(|o| self.heap.get(o)),
+            None => {
+                self.error(start, format!("constant {} is out of range", idx));
+                None
+            },
+        }
+    }
+
+    // The constant has to be a string, e.g. a global or property name.
+    fn name_at(&mut self, idx: usize) {
+        let start = self.current.start;
+
+        if let Some(object) = self.constant_at(idx) {
+            if object.as_string().is_none() {
+                self.error(start, format!("constant {} should be a string, not a {}", idx, object.type_name()))
+            }
+        } else if self.chunk().get_constant(idx).is_some() {
+            self.error(start, format!("constant {} should be a string", idx))
+        }
+    }
+
+    fn upvalue_index(&mut self, idx: usize) {
+        let count = self.function.upvalue_count();
+        let start = self.current.start;
+
+        if idx >= count {
+            self.error(start, format!("upvalue {} is out of range, `{}` has {}", idx, self.function.name(), count))
+        }
+    }
+
+    fn ret(&mut self) { self.effect(1, 0); self.current.flow = Flow::Stop }
+    fn print(&mut self) { self.effect(1, 0) }
+    fn add(&mut self) { self.effect(2, 1) }
+    fn sub(&mut self) { self.effect(2, 1) }
+    fn mul(&mut self) { self.effect(2, 1) }
+    fn rem(&mut self) { self.effect(2, 1) }
+    fn pow(&mut self) { self.effect(2, 1) }
+    fn div(&mut self) { self.effect(2, 1) }
+    fn neg(&mut self) { self.effect(1, 1) }
+    fn int_div(&mut self) { self.effect(2, 1) }
+    fn bit_and(&mut self) { self.effect(2, 1) }
+    fn bit_or(&mut self) { self.effect(2, 1) }
+    fn bit_xor(&mut self) { self.effect(2, 1) }
+    fn shl(&mut self) { self.effect(2, 1) }
+    fn shr(&mut self) { self.effect(2, 1) }
+    fn bit_not(&mut self) { self.effect(1, 1) }
+    fn not(&mut self) { self.effect(1, 1) }
+    fn eq(&mut self) { self.effect(2, 1) }
+    fn gt(&mut self) { self.effect(2, 1) }
+    fn lt(&mut self) { self.effect(2, 1) }
+    fn ge(&mut self) { self.effect(2, 1) }
+    fn le(&mut self) { self.effect(2, 1) }
+    fn neq(&mut self) { self.effect(2, 1) }
+    fn op_pop(&mut self) { self.effect(1, 0) }
+    fn index(&mut self) { self.effect(2, 1) }
+    fn set_element(&mut self) { self.effect(3, 0) }
+    fn inherit(&mut self) { self.effect(2, 2) }
+    fn close_upvalue(&mut self) { self.effect(1, 0) }
+    fn imm_nil(&mut self) { self.effect(0, 1) }
+    fn imm_true(&mut self) { self.effect(0, 1) }
+    fn imm_false(&mut self) { self.effect(0, 1) }
+    fn pop_handler(&mut self) {}
+    fn throw(&mut self) { self.effect(1, 0); self.current.flow = Flow::Stop }
+    fn coroutine(&mut self) { self.effect(1, 1) }
+    fn op_yield(&mut self) { self.effect(1, 1) }
+    fn coroutine_status(&mut self) { self.effect(1, 1) }
+
+    fn constant(&mut self, idx: usize) {
+        self.constant_at(idx);
+        self.effect(0, 1)
+    }
+
+    fn immediate(&mut self) {
+        self.immediate_value();
+        self.effect(0, 1)
+    }
+
+    fn add_const(&mut self) {
+        self.immediate_value();
+        self.effect(1, 1)
+    }
+
+    fn list(&mut self, count: usize) {
+        self.effect(count, 1)
+    }
+
+    fn dict(&mut self, count: usize) {
+        self.effect(count * 2, 1)
+    }
+
+    fn jmp(&mut self, target: usize) {
+        self.current.flow = Flow::Jump(target)
+    }
+
+    fn jze(&mut self, target: usize) {
+        self.effect(1, 1);
+        self.current.flow = Flow::Branch(target)
+    }
+
+    fn jze_pop(&mut self, target: usize) {
+        self.effect(1, 0);
+        self.current.flow = Flow::Branch(target)
+    }
+
+    fn op_loop(&mut self, sub: usize) {
+        let start = self.current.start;
+
+        match self.offset.checked_sub(sub) {
+            Some(target) => self.current.flow = Flow::Jump(target),
+            None => {
+                self.error(start, format!("loop jumps {} bytes back, before the start of the chunk", sub));
+                self.current.flow = Flow::Stop
+            },
+        }
+    }
+
+    fn push_handler(&mut self, target: usize) {
+        self.current.flow = Flow::Handler(target)
+    }
+
+    fn get_global(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(0, 1)
+    }
+
+    fn set_global(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(1, 1)
+    }
+
+    fn define_global(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(1, 0)
+    }
+
+    fn get_local(&mut self, slot: usize) {
+        self.current.locals.push(slot);
+        self.effect(0, 1)
+    }
+
+    fn set_local(&mut self, slot: usize) {
+        self.current.locals.push(slot);
+        self.effect(1, 1)
+    }
+
+    fn get_upvalue(&mut self, idx: usize) {
+        self.upvalue_index(idx);
+        self.effect(0, 1)
+    }
+
+    fn set_upvalue(&mut self, idx: usize) {
+        self.upvalue_index(idx);
+        self.effect(1, 1)
+    }
+
+    fn call(&mut self, arity: u8) {
+        self.effect(arity as usize + 1, 1)
+    }
+
+    fn closure(&mut self, idx: usize, wide: bool) {
+        let start = self.current.start;
+
+        let count = match self.constant_at(idx) {
+            Some(Object::Function(function)) => function.upvalue_count(),
+            _ => {
+                // Without the function there's no telling how long this instruction is, or where the next starts
+                self.error(start, "closure of a constant that isn't a function");
+                self.offset = self.chunk().len();
+                self.current.flow = Flow::Stop;
+                return
+            },
+        };
+
+        for _ in 0..count {
+            let is_local = self.read_byte();
+            let idx = if wide { self.read_u16() as usize } else { self.read_byte() as usize };
+
+            match is_local {
+                1 => self.current.captures.push(idx),
+                0 => self.upvalue_index(idx),
+                _ => self.error(start, format!("upvalue descriptor flag {} isn't 0 or 1", is_local)),
+            }
+        }
+
+        self.effect(0, 1)
+    }
+
+    fn class(&mut self, idx: usize) {
+        self.name_at(idx);
+
+        let methods = self.read_byte() as usize;
+        self.effect(methods, 1)
+    }
+
+    fn get_property(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(1, 1)
+    }
+
+    fn set_property(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(2, 1)
+    }
+
+    fn invoke(&mut self, arity: u8, idx: usize) {
+        self.name_at(idx);
+        self.effect(arity as usize + 1, 1)
+    }
+
+    fn get_super(&mut self, idx: usize) {
+        self.name_at(idx);
+        self.effect(2, 1)
+    }
+
+    fn super_invoke(&mut self, arity: u8, idx: usize) {
+        self.name_at(idx);
+        self.effect(arity as usize + 2, 1)
+    }
+
+    fn unknown_op(&mut self, op: u8) {
+        let start = self.current.start;
+
+        self.error(start, format!("unknown opcode {:#04x}", op));
+        self.current.flow = Flow::Stop
+    }
+}
+
+impl Instruction {
+    fn new(start: usize) -> Self {
+        Instruction {
+            start,
+            end: start,
+            pops: 0,
+            pushes: 0,
+            locals: Vec::new(),
+            captures: Vec::new(),
+            flow: Flow::Next,
+        }
+    }
+}
@@ -1,33 +1,58 @@
 use std::collections::HashMap;
-use std::fs::File;
 
 use fnv::FnvBuildHasher;
 
+#[cfg(feature = "profiling")]
 use flame as f;
+#[cfg(feature = "profiling")]
 use flamer::flame;
 
 use super::*;
-use super::compiler::CompileState;
+use gc::trace::{ Trace, Tracer };
+use crate::ir::opt::{ OptLevel, PassManager };
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::mem;
+use std::rc::Rc;
 
-const STACK_SIZE:  usize = 4096;
-const HEAP_GROWTH: usize = 2;
-
-const GC_TRIGGER_COUNT: usize = 1024;
+// Where to go when something is thrown inside a `try`, and how much of the stack to keep.
+#[derive(Debug, Clone, Copy)]
+pub struct Handler {
+    ip: usize,
+    stack_len: usize,
+}
 
 pub struct CallFrame {
     closure: Handle<Object>,
+    // The closure's chunk, looked up once instead of on every read. Heap objects don't move,
+    // and the frame keeps the closure alive for as long as it's around.
+    chunk: *const Chunk,
     ip: usize,
     stack_start: usize,
+    handlers: Vec<Handler>,
+    // Open for as long as the frame runs, so calls nest under their caller in the profile
+    #[cfg(feature = "profiling")]
+    span: Option<f::SpanGuard>,
 }
 
 impl CallFrame {
     pub fn new(closure: Handle<Object>, stack_start: usize) -> Self {
+        let chunk = unsafe {
+            closure.get_unchecked()
+                .as_closure()
+                .expect("closure reference by construction")
+                .chunk() as *const Chunk
+        };
+
         CallFrame {
             closure,
+            chunk,
             ip: 0,
             stack_start,
+            handlers: Vec::new(),
+            #[cfg(feature = "profiling")]
+            span: None,
         }
     }
 
@@ -43,79 +68,204 @@ impl CallFrame {
         self.with_chunk(|c| c.read_u16(ip))
     }
 
+    pub fn read_u32(&mut self) -> u32 {
+        let ip = self.ip;
+        self.ip += 4;
+        self.with_chunk(|c| c.read_u32(ip))
+    }
+
     pub fn read_u64(&mut self) -> u64 {
         let ip = self.ip;
         self.ip += 8;
         self.with_chunk(|c| c.read_u64(ip))
     }
 
-    pub fn read_constant_at(&mut self, idx: u8) -> Value {
+    pub fn read_constant_at(&mut self, idx: usize) -> Value {
         self.with_chunk(|c| *c.get_constant(idx).expect("invalid constant index"))
     }
 
-    pub fn read_constant(&mut self) -> Value {
-        let idx = self.read_byte();
-        self.read_constant_at(idx)
-    }
-
     pub fn with_chunk<F, T>(&self, fun: F) -> T
         where
             F: FnOnce(&Chunk) -> T
     {
-        unsafe {
-            let closure = self.closure.get_unchecked()
-                .as_closure()
-                .expect("closure reference by construction");
-            fun(closure.chunk())
-        }
+        fun(unsafe { &*self.chunk })
     }
 }
 
-macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
-        let b = $self.pop();
-        let a = $self.pop();
+// Ends the spans of frames about to go away or be suspended, innermost first as flame wants them.
+#[cfg(feature = "profiling")]
+fn close_spans(frames: &mut [CallFrame]) {
+    frames.iter_mut().rev().for_each(|frame| drop(frame.span.take()))
+}
 
-        if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
-            let c = a $op b;
-            $self.push(c.into());
+// A value stack and the call frames running on it. The VM runs one at a time, and swaps
+// them in and out as coroutines are resumed and yield.
+#[derive(Default)]
+pub struct ExecutionContext {
+    pub(crate) stack: Vec<Value>,
+    pub(crate) frames: Vec<CallFrame>,
+    // Closed while the context is suspended, along with the slots to reopen them at
+    pub(crate) upvalues: Vec<(usize, UpValue)>,
+}
 
-            return
+impl ExecutionContext {
+    pub fn new(stack: Vec<Value>) -> Self {
+        ExecutionContext {
+            stack,
+            frames: Vec::new(),
+            upvalues: Vec::new(),
         }
+    }
+}
 
-        // TODO: ERROR HERE
+impl Trace<Object> for ExecutionContext {
+    fn trace(&self, tracer: &mut Tracer<Object>) {
+        self.stack.trace(tracer);
+        self.frames.iter().for_each(|f| f.closure.trace(tracer));
+        self.upvalues.iter()
+            .flat_map(|(_, u)| u.get())
+            .for_each(|v| v.trace(tracer));
     }
 }
 
 pub struct VM {
     pub heap: Heap<Object>,
-    next_gc: usize,
+    pub gc: GcConfig,
+    next_gc: usize, // heap bytes at which the next collection starts
 
     pub globals: HashMap<String, Value, FnvBuildHasher>,
     pub open_upvalues: Vec<UpValue>,
 
     pub stack: Vec<Value>,
     pub frames: Vec<CallFrame>,
+
+    // Running coroutines, each with the context of whoever resumed it
+    resumers: Vec<(Handle<Object>, ExecutionContext)>,
+    // Coroutines below this were resumed by an outer run loop, so only that loop may switch back to them
+    resumers_floor: usize,
+
+    // What scripts may use up, nothing but the stack is limited by default
+    pub limits: Limits,
+    interrupt: InterruptHandle,
+    periodic: Option<Periodic>,
+
+    // Whether the last run was stopped by a limit with its frames kept for `resume`
+    suspended: bool,
+    // Runs started by `call_value` that haven't returned yet, which can't be resumed once they've stopped
+    nested: usize,
+
+    // How hard the IR is optimized before it's compiled, off by default
+    pub opt_level: OptLevel,
+
+    // Counts what scripts run while it's set, see `Profiler::report`
+    pub profiler: Option<Profiler>,
+
+    // Where `write_profile` writes what was recorded, nothing is recorded without one
+    #[cfg(feature = "profiling")]
+    pub profile: Option<ProfileConfig>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::new_with(GcConfig::default())
+    }
+
+    pub fn new_with(gc: GcConfig) -> Self {
+        let limits = Limits::default();
+
         VM {
-            stack:   Vec::with_capacity(STACK_SIZE),
+            stack:   Vec::with_capacity(limits.stack),
             heap:    Heap::default(),
-            next_gc: GC_TRIGGER_COUNT,
+            gc,
+            next_gc: gc.threshold,
             globals: HashMap::with_hasher(FnvBuildHasher::default()),
             frames:  Vec::with_capacity(256),
-            open_upvalues: Vec::with_capacity(16)
+            open_upvalues: Vec::with_capacity(16),
+            resumers: Vec::new(),
+            resumers_floor: 0,
+            limits,
+            interrupt: InterruptHandle::new(),
+            periodic: None,
+            suspended: false,
+            nested: 0,
+            opt_level: OptLevel::None,
+            profiler: None,
+            #[cfg(feature = "profiling")]
+            profile: None,
         }
     }
 
-    pub fn exec_from(&mut self, atoms: &[ExprNode], locals: Vec<Local>, debug: bool) -> Vec<Local> {
+    pub fn exec_from(&mut self, atoms: &[ExprNode], locals: Vec<Local>, debug: bool) -> Result<Vec<Local>, ExecError> {
+        let atoms = self.optimize(atoms);
         let mut compiler = Compiler::new(&mut self.heap);
 
-        let function = compiler.compile_from(atoms, locals);
+        let function = compiler.compile_from(&atoms, locals)?;
         let locals = compiler.locals_cache;
 
+        self.execute(function, debug)?;
+
+        Ok(locals)
+    }
+
+    pub fn exec(&mut self, atoms: &[ExprNode], debug: bool) -> Result<Value, ExecError> {
+        let function = self.compile(atoms)?;
+
+        Ok(self.execute(function, debug)?)
+    }
+
+    // Compiles without running, e.g. to save the result with `Function::write_to`.
+    // Its constants live on this VM's heap, so it's only good for as long as the VM is.
+    pub fn compile(&mut self, atoms: &[ExprNode]) -> Result<Function, Vec<CompileError>> {
+        let atoms = self.optimize(atoms);
+        let mut compiler = Compiler::new(&mut self.heap);
+
+        compiler.compile(&atoms)
+    }
+
+    fn optimize<'a>(&self, atoms: &'a [ExprNode]) -> Cow<'a, [ExprNode]> {
+        if self.opt_level == OptLevel::None {
+            return Cow::Borrowed(atoms)
+        }
+
+        let mut atoms = atoms.to_vec();
+        PassManager::new(self.opt_level).run(&mut atoms);
+
+        Cow::Owned(atoms)
+    }
+
+    // Runs a function compiled earlier, or read back with `load_bytecode`.
+    pub fn exec_function(&mut self, function: Function, debug: bool) -> Result<Value, RuntimeError> {
+        self.execute(function, debug)
+    }
+
+    pub fn add_native<F>(&mut self, name: &str, func: F, arity: impl Into<Arity>)
+        where
+            F: Fn(&mut NativeContext, &[Value]) -> Result<Value, RuntimeError> + 'static
+    {
+        let function = self.allocate(
+            Object::native_fn(name, arity.into(), Rc::new(func))
+        );
+
+        self.globals.insert(name.into(), function.into());
+    }
+
+    // Like `add_native`, but arguments are converted with `FromValue` and the result with `IntoValue`,
+    // e.g. `vm.add_typed_native("len", |s: String| s.len())`.
+    pub fn add_typed_native<Args, F: TypedNative<Args>>(&mut self, name: &str, func: F) {
+        let function = self.allocate(
+            Object::native_fn(name, Arity::Exact(F::ARITY), func.into_native(name))
+        );
+
+        self.globals.insert(name.into(), function.into());
+    }
+
+    fn execute(&mut self, function: Function, debug: bool) -> Result<Value, RuntimeError> {
         if debug {
             let dis = Disassembler::new(function.chunk(), &self.heap);
             dis.disassemble();
@@ -124,60 +274,582 @@ impl VM {
         let closure = Closure::new(function, Vec::new());
         let value = self.allocate(Object::Closure(closure)).into();
 
-        self.push(value);
-        self.call(0);
+        // Whatever was stopped before is given up on
+        if self.suspended {
+            self.reset()
+        }
 
-        self.run();
+        let result = self.push(value)
+            .and_then(|_| self.call(0))
+            .and_then(|_| self.run());
 
-        if debug {
-            f::dump_html(File::create("flamegraph.html").unwrap()).unwrap();
+        self.finish(result)
+    }
+
+    // Carries on with the run that was stopped by going over `limits.fuel` or `limits.heap_bytes`,
+    // once the host has raised them. It ends like the `exec` it was stopped in would have.
+    pub fn resume(&mut self) -> Result<Value, RuntimeError> {
+        if !self.suspended {
+            return Err(RuntimeError::new(RuntimeErrorKind::InvalidOp, "there is no stopped run to resume"))
+        }
+
+        self.suspended = false;
+
+        let result = self.run();
+
+        self.finish(result)
+    }
+
+    // Gives `limits.fuel` `fuel` more instructions, if it's limited at all.
+    pub fn refuel(&mut self, fuel: u64) {
+        if let Some(ref mut left) = self.limits.fuel {
+            *left = left.saturating_add(fuel)
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    // Every handle got here interrupts this VM, see `InterruptHandle`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    // Has `callback` run every `every` instructions from now on, e.g. to check for a "stop" button
+    // or a deadline. Returning false stops the run with `RuntimeErrorKind::Interrupted`.
+    pub fn set_periodic(&mut self, every: u64, callback: impl FnMut() -> bool + 'static) {
+        let every = every.max(1);
+
+        self.periodic = Some(Periodic {
+            every,
+            left: every,
+            callback: Box::new(callback),
+        })
+    }
+
+    pub fn clear_periodic(&mut self) {
+        self.periodic = None
+    }
+
+    // Writes what was recorded since the last write to `profile`, left to the host so it can
+    // decide what to do when that fails.
+    #[cfg(feature = "profiling")]
+    pub fn write_profile(&self) -> std::io::Result<()> {
+        match self.profile {
+            Some(ref profile) => profile.write(),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self, result: Result<(), RuntimeError>) -> Result<Value, RuntimeError> {
+        match result {
+            Ok(()) => Ok(self.pop()),
+            Err(err) if self.suspended => Err(err),
+            Err(err) => {
+                self.reset();
+                Err(err)
+            }
+        }
+    }
+
+    // Drops whatever was left behind by a failed execution, so the VM can be used again.
+    // Globals and the heap survive, as they may still be referenced by the host.
+    fn reset(&mut self) {
+        #[cfg(feature = "profiling")]
+        {
+            close_spans(&mut self.frames);
+            self.resumers.iter_mut().rev().for_each(|(_, context)| close_spans(&mut context.frames));
+        }
+
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.resumers.clear();
+        self.resumers_floor = 0;
+        self.suspended = false;
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        self.run_until(0)
+    }
+
+    // Runs until the frame stack is back down to `depth` frames, and any coroutine resumed
+    // along the way has yielded or finished.
+    fn run_until(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        while self.frames.len() > depth || self.resumers.len() > self.resumers_floor {
+            let result = if self.profiler.is_some() {
+                self.profiled_step()
+            } else {
+                self.dispatch()
+            };
+
+            let result = result.and_then(|_| self.check_heap());
+
+            if let Err(err) = result {
+                // Left where it stopped for the host, scripts don't get to carry on past a limit
+                if !err.kind.is_catchable() {
+                    return Err(err)
+                }
+
+                self.catch(err, depth)?
+            }
         }
 
-        locals
+        Ok(())
+    }
+
+    // Limits are only checked between instructions, so unless a native is waiting on the run
+    // that went over one, it can be picked up again right where it stopped.
+    fn stop<T>(&mut self, kind: RuntimeErrorKind, message: String) -> Result<T, RuntimeError> {
+        self.suspended = self.nested == 0;
+        self.runtime_error(kind, message)
     }
 
-    pub fn exec(&mut self, atoms: &[ExprNode], debug: bool) {
-        let function = {
-            let mut compiler = Compiler::new(&mut self.heap);
-            compiler.compile(atoms)
+    fn check_heap(&mut self) -> Result<(), RuntimeError> {
+        let max = match self.limits.heap_bytes {
+            Some(max) if self.heap.bytes() > max => max,
+            _ => return Ok(()),
         };
 
-        if debug {
-            let dis = Disassembler::new(function.chunk(), &self.heap);
-            dis.disassemble();
+        self.collect();
+
+        if self.heap.bytes() > max {
+            let message = format!("{} bytes alive on the heap, the limit is {}", self.heap.bytes(), max);
+            return self.stop(RuntimeErrorKind::HeapLimit, message)
         }
 
-        let closure = Closure::new(function, Vec::new());
-        let value = self.allocate(Object::Closure(closure)).into();
+        Ok(())
+    }
 
-        self.push(value);
-        self.call(0);
+    // How many instructions can run before `tick` has something to do, fuel and the periodic
+    // callback are counted down together. Runs with neither just never get to 0.
+    fn budget(&self) -> u64 {
+        let fuel = self.limits.fuel.unwrap_or(u64::MAX);
+        let periodic = self.periodic.as_ref().map_or(u64::MAX, |periodic| periodic.left);
 
-        self.run();
+        fuel.min(periodic)
+    }
 
-        if debug {
-            f::dump_html(File::create("flamegraph.html").unwrap()).unwrap();
+    fn spend(&mut self, instructions: u64) {
+        if let Some(ref mut fuel) = self.limits.fuel {
+            *fuel -= instructions
+        }
+
+        if let Some(ref mut periodic) = self.periodic {
+            periodic.left -= instructions
         }
     }
 
-    pub fn add_native(&mut self, name: &str, func: fn(&mut Heap<Object>, &[Value]) -> Value, arity: u8) {
-        let function = self.allocate(
-            Object::native_fn(name, arity, func)
-        );
+    // Runs once the budget is used up, before the next instruction
+    fn tick(&mut self) -> Result<(), RuntimeError> {
+        if self.limits.fuel == Some(0) {
+            return self.stop(RuntimeErrorKind::OutOfFuel, "ran out of fuel".to_string())
+        }
 
-        self.globals.insert(name.into(), function.into());
+        let carry_on = match self.periodic {
+            Some(ref mut periodic) if periodic.left == 0 => {
+                periodic.left = periodic.every;
+                (periodic.callback)()
+            },
+            _ => true,
+        };
+
+        if !carry_on {
+            return self.runtime_error(RuntimeErrorKind::Interrupted, "stopped by the periodic callback")
+        }
+
+        Ok(())
+    }
+
+    // Checked wherever a script could keep going forever: loops going back and calls
+    fn check_interrupt(&self) -> Result<(), RuntimeError> {
+        if self.interrupt.take() {
+            return self.runtime_error(RuntimeErrorKind::Interrupted, "interrupted by the host")
+        }
+
+        Ok(())
+    }
+
+    // Runs a single instruction the long way round, counting it first.
+    fn profiled_step(&mut self) -> Result<(), RuntimeError> {
+        if self.budget() == 0 {
+            self.tick()?
+        }
+
+        self.spend(1);
+
+        let frames = &self.frames;
+        let resumers = &self.resumers;
+        let frame = frames.last().expect("frames to be nonempty");
+
+        if let Some(ref mut profiler) = self.profiler {
+            let key = FrameKey {
+                depth: frames.len(),
+                resumers: resumers.len(),
+                stack_start: frame.stack_start,
+                chunk: frame.chunk,
+            };
+
+            let stack = || {
+                resumers.iter()
+                    .flat_map(|(_, context)| context.frames.iter())
+                    .chain(frames.iter())
+                    .map(|frame| frame.with_chunk(|chunk| chunk.name().to_owned()))
+                    .collect()
+            };
+
+            frame.with_chunk(|chunk| profiler.instruction(key, stack, chunk, frame.ip))
+        }
+
+        let inst = self.read_byte();
+        decode_op!(inst, self)
+    }
+
+    // Runs the current frame with its code, constants and stack base kept in locals, doing the
+    // common instructions that can't fail, allocate or switch frames right here. The first one
+    // that can is handed to `decode_op!` with the frame brought up to date, and then it returns
+    // for `run_until` to see whether it's done and start over on whatever frame is current.
+    fn dispatch(&mut self) -> Result<(), RuntimeError> {
+        let (chunk, base, mut ip) = {
+            let frame = self.frame();
+
+            // The chunk outlives this call, nothing here can collect or leave the frame
+            (unsafe { &*frame.chunk }, frame.stack_start, frame.ip)
+        };
+
+        let code = chunk.as_ref();
+        let constants = chunk.constant_values();
+
+        // Counted here and spent before anything else can look at it
+        let budget = self.budget();
+        let mut left = budget;
+
+        loop {
+            let start = ip;
+            let op = code[ip];
+            let room = self.stack.len() < self.limits.stack;
+
+            if left == 0 {
+                self.spend(budget);
+                self.frame_mut().ip = start;
+                return self.tick()
+            }
+
+            left -= 1;
+            ip += 1;
+
+            let done = match op {
+                0x01 if room => {
+                    let idx = code[ip] as usize;
+                    ip += 1;
+                    self.stack.push(constants[idx]);
+                    true
+                },
+                0x39 if room => {
+                    let idx = read_u16(code, ip) as usize;
+                    ip += 2;
+                    self.stack.push(constants[idx]);
+                    true
+                },
+                0x13 if room => {
+                    let raw = read_u64(code, ip);
+                    ip += 8;
+                    self.stack.push(unsafe { Value::from_raw(raw) });
+                    true
+                },
+                0x14 if room => { self.stack.push(Value::nil()); true },
+                0x15 if room => { self.stack.push(Value::truelit()); true },
+                0x16 if room => { self.stack.push(Value::falselit()); true },
+
+                0x11 if room => {
+                    let slot = code[ip] as usize;
+                    ip += 1;
+                    self.stack.push(self.stack[base + slot]);
+                    true
+                },
+                0x52..=0x55 if room => {
+                    self.stack.push(self.stack[base + (op - 0x52) as usize]);
+                    true
+                },
+                0x3d if room => {
+                    let slot = read_u16(code, ip) as usize;
+                    ip += 2;
+                    self.stack.push(self.stack[base + slot]);
+                    true
+                },
+                0x12 => {
+                    let slot = code[ip] as usize;
+                    ip += 1;
+                    self.stack[base + slot] = self.peek();
+                    true
+                },
+                0x3e => {
+                    let slot = read_u16(code, ip) as usize;
+                    ip += 2;
+                    self.stack[base + slot] = self.peek();
+                    true
+                },
+                0x0e => { self.pop(); true },
+
+                // Globals that are already defined, undefined ones have to be reported or created
+                0x0f if room => {
+                    let name = global_name(constants, code[ip]);
+
+                    match self.globals.get(name) {
+                        Some(&value) => {
+                            ip += 1;
+                            self.stack.push(value);
+                            true
+                        },
+                        None => false,
+                    }
+                },
+                0x10 => {
+                    let value = self.peek();
+                    let name = global_name(constants, code[ip]);
+
+                    match self.globals.get_mut(name) {
+                        Some(slot) => {
+                            ip += 1;
+                            *slot = value;
+                            true
+                        },
+                        None => false,
+                    }
+                },
+
+                // Jumps may go back too once threaded, those are left to `jmp` when interrupted
+                0x0c | 0x49 if self.interrupt.is_interrupted() => false,
+                0x0c => { ip = read_u16(code, ip) as usize; true },
+                0x49 => { ip = read_u32(code, ip) as usize; true },
+                0x0d | 0x4a | 0x50 => {
+                    let (target, next) = match op {
+                        0x4a => (read_u32(code, ip) as usize, ip + 4),
+                        _ => (read_u16(code, ip) as usize, ip + 2),
+                    };
+
+                    let cond = if op == 0x50 { self.pop() } else { self.peek() };
+                    ip = if cond.truthy() { next } else { target };
+                    true
+                },
+                // Looping back, unless the host wants it stopped
+                0x20 | 0x4b if self.interrupt.is_interrupted() => false,
+                0x20 => { ip = ip + 2 - read_u16(code, ip) as usize; true },
+                0x4b => { ip = ip + 4 - read_u32(code, ip) as usize; true },
+
+                0x07 => {
+                    let value = self.pop();
+                    self.stack.push((!value.truthy()).into());
+                    true
+                },
+
+                // Numbers only, anything else takes the long way round to be added, compared or rejected.
+                // So do ints that don't stay ints, and dividing them.
+                0x03..=0x06 | 0x09..=0x0b | 0x4d..=0x4f => {
+                    let len = self.stack.len();
+
+                    match (self.stack[len - 2].decode(), self.stack[len - 1].decode()) {
+                        (Variant::Float(a), Variant::Float(b)) => {
+                            let result: Value = match op {
+                                0x03 => (a + b).into(),
+                                0x04 => (a - b).into(),
+                                0x05 => (a * b).into(),
+                                0x06 => (a / b).into(),
+                                0x09 => (a == b).into(),
+                                0x0a => (a > b).into(),
+                                0x0b => (a < b).into(),
+                                0x4d => (a.partial_cmp(&b) != Some(Ordering::Less)).into(),
+                                0x4e => (a.partial_cmp(&b) != Some(Ordering::Greater)).into(),
+                                _ => (a != b).into(),
+                            };
+
+                            self.stack.truncate(len - 1);
+                            self.stack[len - 2] = result;
+                            true
+                        },
+                        (Variant::Int(a), Variant::Int(b)) => {
+                            let result: Option<Value> = match op {
+                                0x03 => a.checked_add(b).map(Value::int),
+                                0x04 => a.checked_sub(b).map(Value::int),
+                                0x05 => a.checked_mul(b).map(Value::int),
+                                0x06 => None,
+                                0x09 => Some((a == b).into()),
+                                0x0a => Some((a > b).into()),
+                                0x0b => Some((a < b).into()),
+                                0x4d => Some((a >= b).into()),
+                                0x4e => Some((a <= b).into()),
+                                _ => Some((a != b).into()),
+                            };
+
+                            match result {
+                                Some(result) => {
+                                    self.stack.truncate(len - 1);
+                                    self.stack[len - 2] = result;
+                                    true
+                                },
+                                None => false,
+                            }
+                        },
+                        _ => false,
+                    }
+                },
+                0x51 => match (self.peek().decode(), unsafe { Value::from_raw(read_u64(code, ip)) }.decode()) {
+                    (Variant::Float(a), Variant::Float(b)) => {
+                        ip += 8;
+                        self.stack.pop();
+                        self.stack.push((a + b).into());
+                        true
+                    },
+                    (Variant::Int(a), Variant::Int(b)) => match a.checked_add(b) {
+                        Some(sum) => {
+                            ip += 8;
+                            self.stack.pop();
+                            self.stack.push(Value::int(sum));
+                            true
+                        },
+                        None => false,
+                    },
+                    _ => false,
+                },
+
+                _ => false,
+            };
+
+            if !done {
+                self.spend(budget - left);
+
+                self.frame_mut().ip = start + 1;
+                return decode_op!(op, self)
+            }
+        }
+    }
+
+    // Unwinds to the innermost handler above `depth` and hands it the error as a value.
+    // Without one the error is passed on, leaving the stack for the caller to clean up.
+    fn catch(&mut self, err: RuntimeError, depth: usize) -> Result<(), RuntimeError> {
+        let frame = loop {
+            // `depth` counts frames of the context this loop started in, coroutines are searched whole
+            let start = if self.resumers.len() > self.resumers_floor { 0 } else { depth };
+
+            if let Some(i) = self.frames[start..].iter().rposition(|f| !f.handlers.is_empty()) {
+                break start + i
+            }
+
+            if self.resumers.len() == self.resumers_floor {
+                return Err(err)
+            }
+
+            // Nothing in the coroutine handles it, so it dies and whoever resumed it gets the error
+            self.leave_coroutine(CoroutineStatus::Dead);
+        };
+
+        let value = match err.value {
+            Some(value) => value,
+            None => self.error_value(&err),
+        };
+
+        #[cfg(feature = "profiling")]
+        close_spans(&mut self.frames[frame + 1..]);
+
+        self.frames.truncate(frame + 1);
+
+        let handler = self.frame_mut().handlers.pop().expect("frame with a handler");
+
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.frame_mut().ip = handler.ip;
+
+        self.push(value)
+    }
+
+    // Errors raised by the VM itself reach zub as `{ "kind": ..., "message": ... }`.
+    fn error_value(&mut self, err: &RuntimeError) -> Value {
+        let mut fields = HashMap::new();
+
+        fields.insert("kind".to_string(), err.kind.to_string());
+        fields.insert("message".to_string(), err.message.clone());
+
+        fields.into_value(&mut self.heap)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn push_handler(&mut self, ip: usize) -> Result<(), RuntimeError> {
+        let stack_len = self.stack.len();
+
+        self.frame_mut().handlers.push(Handler { ip, stack_len });
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn pop_handler(&mut self) -> Result<(), RuntimeError> {
+        self.frame_mut().handlers.pop();
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn throw(&mut self) -> Result<(), RuntimeError> {
+        let value = self.pop();
+        let message = format!("{}", value.with_heap(&self.heap));
+
+        let mut err = self.with_trace(RuntimeError::new(RuntimeErrorKind::Thrown, message));
+        err.value = Some(value);
+
+        Err(err)
+    }
+
+    // Calls a closure or native from Rust and runs it to completion. This works both from the
+    // host and while the VM is already running, e.g. from inside a native.
+    pub fn call_value(&mut self, callee: Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        if args.len() > u8::MAX as usize {
+            return self.runtime_error(RuntimeErrorKind::Arity, format!("too many arguments: {}", args.len()))
+        }
+
+        let depth = self.frames.len();
+        let stack_start = self.stack.len();
+        let floor = mem::replace(&mut self.resumers_floor, self.resumers.len());
+
+        self.nested += 1;
+
+        let result = self.push(callee)
+            .and_then(|_| args.iter().try_for_each(|arg| self.push(*arg)))
+            .and_then(|_| self.call(args.len() as u8))
+            .and_then(|_| self.run_until(depth));
+
+        self.nested -= 1;
+        self.resumers_floor = floor;
+
+        match result {
+            Ok(()) => Ok(self.pop()),
+            Err(err) => {
+                // Unwind only what this call left behind, an outer call may want to carry on
+                self.close_upvalues(stack_start);
+
+                #[cfg(feature = "profiling")]
+                close_spans(&mut self.frames[depth..]);
+
+                self.frames.truncate(depth);
+                self.stack.truncate(stack_start);
+
+                Err(err)
+            }
+        }
     }
 
-    fn run(&mut self)  {
-        while !self.frames.is_empty() {
-            let inst = self.read_byte();
-            decode_op!(inst, self)
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        match self.globals.get(name).cloned() {
+            Some(callee) => self.call_value(callee, args),
+            None => self.runtime_error(RuntimeErrorKind::UndefinedGlobal, format!("undefined global variable: `{}`", name)),
         }
     }
 
-    #[flame]
-    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) {
-        let closure = self.deref(handle)
+    // Left out of the profile like everything else that pushes or pops frames, since flame
+    // wants spans ended in the order they started and a frame's span outlives the call making it.
+    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) -> Result<(), RuntimeError> {
+        // Borrowing just the heap, so the profiler can be told about the call
+        let closure = unsafe { self.heap.get_unchecked(handle) }
             .as_closure()
             .expect("redundant cast to succeed");
 
@@ -185,16 +857,36 @@ impl VM {
         let frame_start = if last < arity as usize { 0 } else { last - (arity + 1) as usize };
 
         if closure.arity() != arity {
-            self.runtime_error(&format!("arity mismatch: {} != {} @ {}: {:#?}", closure.arity(), arity, closure.name(), self.stack))
+            return self.runtime_error(
+                RuntimeErrorKind::Arity,
+                format!("`{}` expects {} argument(s), got {}", closure.name(), closure.arity(), arity)
+            )
+        }
+
+        self.check_interrupt()?;
+
+        if let Some(max) = self.limits.frames {
+            if self.frames.len() >= max {
+                return self.runtime_error(RuntimeErrorKind::CallDepth, format!("calls nested more than {} deep", max))
+            }
+        }
+
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.call(closure.name())
         }
 
         let frame = CallFrame::new(handle, frame_start);
         self.frames.push(frame);
+
+        #[cfg(feature = "profiling")]
+        self.open_spans();
+
+        Ok(())
     }
 
-    #[flame]
-    fn closure(&mut self) {
-        let value = self.frame_mut().read_constant();
+    #[cfg_attr(feature = "profiling", flame)]
+    fn closure(&mut self, idx: usize, wide: bool) -> Result<(), RuntimeError> {
+        let value = self.frame_mut().read_constant_at(idx);
         let function = value.as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_function())
@@ -205,7 +897,7 @@ impl VM {
 
         for _ in 0 .. function.upvalue_count() {
             let is_local = self.read_byte() > 0;
-            let idx = self.read_byte() as usize;
+            let idx = if wide { self.read_u16() as usize } else { self.read_byte() as usize };
             let upvalue = if is_local {
                 self.capture_upvalue(idx)
             } else {
@@ -218,59 +910,486 @@ impl VM {
         let closure = Closure::new(function, upvalues);
         let value = self.allocate(Object::Closure(closure)).into();
 
-        self.push(value)
+        self.push(value)
+    }
+
+    fn call(&mut self, arity: u8) -> Result<(), RuntimeError> {
+        let last = self.stack.len();
+
+        let frame_start = if last < arity as usize { 0 } else { last - (arity + 1) as usize };
+
+        let callee = self.stack[frame_start];
+
+        if let Variant::Obj(handle) = callee.decode() {
+            use self::Object::*;
+
+            match unsafe { self.heap.get_unchecked(handle) } {
+                Closure(_) => {
+                    return self.call_closure(handle, arity)
+                },
+                Class(ref class) => {
+                    let init = class.method("init");
+
+                    // The class stays on the stack until the instance exists, keeping it alive
+                    let instance = self.allocate(Object::Instance(super::Instance::new(handle)));
+                    self.stack[frame_start] = instance.into();
+
+                    if let Some(init) = init {
+                        return self.call_closure(init, arity)
+                    }
+
+                    if arity != 0 {
+                        return self.runtime_error(
+                            RuntimeErrorKind::Arity,
+                            format!("`{}` expects 0 argument(s), got {}", self.class_name(handle), arity)
+                        )
+                    }
+
+                    return Ok(())
+                },
+                BoundMethod(ref bound) => {
+                    let method = bound.method;
+
+                    self.stack[frame_start] = bound.receiver;
+
+                    return self.call_closure(method, arity)
+                },
+                Coroutine(_) => {
+                    return self.resume_coroutine(handle, frame_start, arity)
+                },
+                NativeFunction(ref native) => {
+                    self.check_interrupt()?;
+
+                    if !native.arity.accepts(arity) {
+                        return self.runtime_error(
+                            RuntimeErrorKind::Arity,
+                            format!("`{}` expects {} argument(s), got {}", native.name, native.arity, arity)
+                        )
+                    }
+
+                    let function = native.function.clone();
+
+                    // The arguments stay on the stack while the native runs, keeping them alive
+                    let args = self.stack[frame_start + 1..].to_vec();
+                    let value = function(&mut NativeContext::new(self), &args)
+                        .map_err(|err| self.with_trace(err))?;
+
+                    self.stack.truncate(frame_start);
+
+                    return self.push(value)
+                },
+
+                _ => ()
+            }
+        }
+
+        let callee = callee.type_name(&self.heap);
+        self.runtime_error(RuntimeErrorKind::Type, format!("can't call value of type {}", callee))
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn coroutine(&mut self) -> Result<(), RuntimeError> {
+        let function = self.peek();
+
+        let handle = match function.as_object() {
+            Some(handle) if self.deref(handle).as_closure().is_some() => handle,
+            _ => {
+                let message = format!("can't make a coroutine of value of type {}", function.type_name(&self.heap));
+                return self.runtime_error(RuntimeErrorKind::Type, message)
+            },
+        };
+
+        let coroutine = self.allocate(Object::Coroutine(Coroutine::new(handle)));
+
+        self.pop();
+        self.push(coroutine.into())
+    }
+
+    // Switches over to a coroutine. The first resume calls its function with the arguments,
+    // after that the argument, if any, is what the paused `yield` evaluates to.
+    fn resume_coroutine(&mut self, handle: Handle<Object>, frame_start: usize, arity: u8) -> Result<(), RuntimeError> {
+        let (status, started, function) = {
+            let coroutine = self.deref(handle).as_coroutine().expect("checked to be a coroutine");
+
+            (coroutine.status, coroutine.started(), coroutine.function())
+        };
+
+        if status != CoroutineStatus::Suspended {
+            return self.runtime_error(RuntimeErrorKind::Coroutine, format!("can't resume a {} coroutine", status))
+        }
+
+        // Checked up front, nothing may fail half way through switching
+        if started && arity > 1 {
+            return self.runtime_error(
+                RuntimeErrorKind::Arity,
+                format!("resuming a coroutine takes at most 1 argument, got {}", arity)
+            )
+        }
+
+        let function = function.expect("coroutines hold their function until they're dead");
+
+        if !started {
+            let closure = self.deref(function).as_closure().expect("coroutines are made of closures");
+
+            if closure.arity() != arity {
+                return self.runtime_error(
+                    RuntimeErrorKind::Arity,
+                    format!("`{}` expects {} argument(s), got {}", closure.name(), closure.arity(), arity)
+                )
+            }
+        }
+
+        let args = self.stack.split_off(frame_start + 1);
+        self.stack.truncate(frame_start);
+
+        let context = {
+            let coroutine = self.deref_mut(handle).as_coroutine_mut().expect("checked to be a coroutine");
+
+            coroutine.status = CoroutineStatus::Running;
+            mem::take(&mut coroutine.context)
+        };
+
+        let resumer = self.switch_context(context);
+        self.resumers.push((handle, resumer));
+
+        #[cfg(feature = "profiling")]
+        self.open_spans();
+
+        if started {
+            self.push(args.first().cloned().unwrap_or_else(Value::nil))
+        } else {
+            self.stack.extend(args);
+            self.call_closure(function, arity)
+        }
+    }
+
+    fn op_yield(&mut self) -> Result<(), RuntimeError> {
+        if self.resumers.is_empty() {
+            return self.runtime_error(RuntimeErrorKind::Coroutine, "can't yield outside of a coroutine")
+        }
+
+        if self.resumers.len() == self.resumers_floor {
+            return self.runtime_error(RuntimeErrorKind::Coroutine, "can't yield across a native call")
+        }
+
+        let value = self.pop();
+
+        self.leave_coroutine(CoroutineStatus::Suspended);
+        self.push(value)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn coroutine_status(&mut self) -> Result<(), RuntimeError> {
+        let value = self.pop();
+
+        match self.status_of(value) {
+            Some(status) => {
+                let status = self.allocate(Object::String(status.to_string()));
+
+                self.push(status.into())
+            },
+            None => {
+                let message = format!("can't get the status of value of type {}", value.type_name(&self.heap));
+                self.runtime_error(RuntimeErrorKind::Type, message)
+            },
+        }
+    }
+
+    // The status of a coroutine, or `None` for anything else.
+    pub fn status_of(&self, value: Value) -> Option<CoroutineStatus> {
+        value.as_object()
+            .and_then(|o| self.heap.get(o))
+            .and_then(Object::as_coroutine)
+            .map(|c| c.status)
+    }
+
+    // Hands control back to whoever resumed the running coroutine.
+    fn leave_coroutine(&mut self, status: CoroutineStatus) {
+        let (handle, resumer) = self.resumers.pop().expect("a running coroutine");
+
+        #[cfg(feature = "profiling")]
+        close_spans(&mut self.frames);
+
+        let context = self.switch_context(resumer);
+
+        if let Some(coroutine) = self.deref_mut(handle).as_coroutine_mut() {
+            coroutine.status = status;
+
+            if status != CoroutineStatus::Dead {
+                coroutine.context = context
+            }
+        }
+    }
+
+    // Starts a span for each frame from the first one without, named after its function, so
+    // time spent in a call is recorded under it and under whoever called it.
+    #[cfg(feature = "profiling")]
+    fn open_spans(&mut self) {
+        if self.profile.is_none() {
+            return
+        }
+
+        let start = self.frames.iter().rposition(|frame| frame.span.is_some()).map_or(0, |i| i + 1);
+
+        for frame in &mut self.frames[start..] {
+            frame.span = Some(f::start_guard(frame.with_chunk(|c| span_name(c.name()))))
+        }
+    }
+
+    // Swaps in another context and returns the one that was running. Open upvalues are closed
+    // on the way out, so closures called from elsewhere don't read some other stack.
+    fn switch_context(&mut self, context: ExecutionContext) -> ExecutionContext {
+        let mut upvalues = Vec::new();
+
+        for mut up in mem::take(&mut self.open_upvalues) {
+            if let Some(slot) = up.as_local() {
+                up.close(|i| self.stack[i]);
+                upvalues.push((slot, up))
+            }
+        }
+
+        let suspended = ExecutionContext {
+            stack: mem::replace(&mut self.stack, context.stack),
+            frames: mem::replace(&mut self.frames, context.frames),
+            upvalues,
+        };
+
+        // Whatever was set through the upvalues while suspended is written back
+        for (slot, mut up) in context.upvalues {
+            if let Ok(value) = up.get() {
+                self.stack[slot] = value
+            }
+
+            up.reopen(slot);
+            self.open_upvalues.push(up)
+        }
+
+        suspended
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn class(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.frame_mut().read_constant_at(idx)
+            .as_object()
+            .and_then(|o| self.deref(o).as_string())
+            .cloned()
+            .expect("`Class` requires a string name");
+
+        let count = self.read_byte() as usize;
+        let start = self.stack.len() - count;
+
+        let mut methods = im_rc::HashMap::new();
+
+        // The verifier only counts what's on the stack, hand-written bytecode can put anything there
+        for &method in self.stack[start..].iter() {
+            let closure = method.as_object().and_then(|o| Some((o, self.deref(o).as_closure()?)));
+
+            match closure {
+                Some((handle, closure)) => methods.insert(closure.name().to_owned(), handle),
+                None => {
+                    let message = format!("class methods must be functions, got {}", method.type_name(&self.heap));
+                    return self.runtime_error(RuntimeErrorKind::Type, message)
+                },
+            };
+        }
+
+        // Methods are only popped once the class holds on to them
+        let class = self.allocate(Object::Class(Class::new(&name, methods)));
+
+        self.stack.truncate(start);
+        self.push(class.into())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn get_property(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string_constant(idx);
+        let receiver = self.peek();
+
+        let (field, method) = {
+            let instance = self.instance(receiver, &name)?;
+            let class = self.deref(instance.class).as_class().expect("instances point to classes");
+
+            (instance.fields.get(&name).cloned(), class.method(&name))
+        };
+
+        let value = match (field, method) {
+            (Some(field), _) => field,
+            (None, Some(method)) => self.allocate(Object::BoundMethod(BoundMethod::new(receiver, method))).into(),
+            (None, None) => return self.undefined_property(receiver, &name),
+        };
+
+        self.pop();
+        self.push(value)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn set_property(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string_constant(idx);
+        let value = self.pop();
+        let receiver = self.pop();
+
+        self.instance(receiver, &name)?;
+
+        let handle = receiver.as_object().expect("checked to be an instance");
+
+        if let Some(instance) = self.deref_mut(handle).as_instance_mut() {
+            instance.fields.insert(name, value);
+        }
+
+        self.push(value)
+    }
+
+    fn invoke(&mut self, arity: u8, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string_constant(idx);
+        let slot = self.stack.len() - arity as usize - 1;
+        let receiver = self.stack[slot];
+
+        let (field, method) = {
+            let instance = self.instance(receiver, &name)?;
+            let class = self.deref(instance.class).as_class().expect("instances point to classes");
+
+            (instance.fields.get(&name).cloned(), class.method(&name))
+        };
+
+        match (field, method) {
+            // Fields shadow methods, and may hold anything callable
+            (Some(field), _) => {
+                self.stack[slot] = field;
+                self.call(arity)
+            },
+            (None, Some(method)) => self.call_closure(method, arity),
+            (None, None) => self.undefined_property(receiver, &name),
+        }
+    }
+
+    // Copies down every method the subclass doesn't define itself. Leaves both classes on the stack.
+    #[cfg_attr(feature = "profiling", flame)]
+    fn inherit(&mut self) -> Result<(), RuntimeError> {
+        let class = self.peek();
+        let superclass = self.stack[self.stack.len() - 2];
+
+        let inherited = match superclass.as_object().and_then(|o| self.deref(o).as_class()) {
+            Some(superclass) => superclass.methods.clone(),
+            None => {
+                let message = format!("can't inherit from value of type {}", superclass.type_name(&self.heap));
+                return self.runtime_error(RuntimeErrorKind::Type, message)
+            },
+        };
+
+        let handle = match class.as_object().filter(|&o| self.deref(o).as_class().is_some()) {
+            Some(handle) => handle,
+            None => {
+                let message = format!("can't inherit into value of type {}", class.type_name(&self.heap));
+                return self.runtime_error(RuntimeErrorKind::InvalidOp, message)
+            },
+        };
+
+        if let Object::Class(ref mut class) = self.deref_mut(handle) {
+            for (name, method) in inherited {
+                class.methods.entry(name).or_insert(method);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn get_super(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string_constant(idx);
+        let superclass = self.pop();
+        let receiver = self.peek();
+
+        let method = self.super_method(superclass, &name)?;
+        let bound = self.allocate(Object::BoundMethod(BoundMethod::new(receiver, method)));
+
+        self.pop();
+        self.push(bound.into())
+    }
+
+    fn super_invoke(&mut self, arity: u8, idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string_constant(idx);
+        let superclass = self.pop();
+
+        let method = self.super_method(superclass, &name)?;
+
+        self.call_closure(method, arity)
     }
 
-    #[flame]
-    fn call(&mut self, arity: u8) {
-        let last = self.stack.len();
-
-        let frame_start = if last < arity as usize { 0 } else { last - (arity + 1) as usize };
+    fn super_method(&self, superclass: Value, name: &str) -> Result<Handle<Object>, RuntimeError> {
+        let class = match superclass.as_object().and_then(|o| self.deref(o).as_class()) {
+            Some(class) => class,
+            None => {
+                let message = format!("can't look up `super` method on value of type {}", superclass.type_name(&self.heap));
+                return self.runtime_error(RuntimeErrorKind::InvalidOp, message)
+            },
+        };
 
-        let callee = self.stack[frame_start].decode();
+        match class.method(name) {
+            Some(method) => Ok(method),
+            None => self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("superclass `{}` has no method `{}`", class.name, name)
+            ),
+        }
+    }
 
-        if let Variant::Obj(handle) = callee {
-            use self::Object::*;
+    fn instance(&self, value: Value, property: &str) -> Result<&Instance, RuntimeError> {
+        if let Some(instance) = value.as_object().and_then(|o| self.deref(o).as_instance()) {
+            return Ok(instance)
+        }
 
-            match unsafe { self.heap.get_unchecked(handle) } {
-                Closure(_) => {
-                    self.call_closure(handle, arity)
-                },
-                NativeFunction(ref native) => {
-                    if native.arity != arity {
-                        self.runtime_error(&format!("arity mismatch: {} != {} @ ({} {})", native.arity, arity, native.name, native.arity))
-                    }
+        self.runtime_error(
+            RuntimeErrorKind::Type,
+            format!("can't access property `{}` on value of type {}", property, value.type_name(&self.heap))
+        )
+    }
 
-                    let value = (native.function)(&mut self.heap, &self.stack[frame_start..]);
+    fn undefined_property<T>(&self, receiver: Value, name: &str) -> Result<T, RuntimeError> {
+        let class = receiver.as_object()
+            .and_then(|o| self.deref(o).as_instance())
+            .map(|i| self.class_name(i.class))
+            .unwrap_or_default();
 
-                    self.stack.drain(frame_start + 1..);
+        self.runtime_error(
+            RuntimeErrorKind::UndefinedProperty,
+            format!("`{}` instance has no property `{}`", class, name)
+        )
+    }
 
-                    self.stack.pop();
-                    self.stack.push(value);
-                },
+    fn class_name(&self, class: Handle<Object>) -> String {
+        self.deref(class).as_class().map(|c| c.name.clone()).unwrap_or_default()
+    }
 
-                _ => self.runtime_error("bad call")
-            }
-        }
+    fn read_string_constant(&mut self, idx: usize) -> String {
+        self.frame_mut().read_constant_at(idx)
+            .as_object()
+            .and_then(|o| self.deref(o).as_string())
+            .cloned()
+            .expect("expected constant to be a string value")
     }
 
-    #[flame]
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), RuntimeError> {
         if let Some(frame) = self.frames.pop() {
             let return_value = self.pop();
 
             if frame.stack_start < self.stack.len() {
                 self.close_upvalues(frame.stack_start)
             }
-            
+
             self.stack.truncate(frame.stack_start);
-            self.push(return_value);
+
+            // The coroutine's function returned, so it's done
+            if self.frames.is_empty() && !self.resumers.is_empty() {
+                self.leave_coroutine(CoroutineStatus::Dead);
+            }
+
+            self.push(return_value)
         } else {
-            self.runtime_error("can't return from top-level");
+            self.runtime_error(RuntimeErrorKind::InvalidOp, "can't return from top-level")
         }
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn capture_upvalue(&mut self, idx: usize) -> UpValue {
         let offset = self.frame().stack_start + idx;
 
@@ -293,439 +1412,543 @@ impl VM {
             .expect("valid closure")
     }
 
-    #[flame]
-    fn set_upvalue(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn set_upvalue(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let value = self.peek();
-        let idx = self.frame_mut().read_byte();
         let closure = self.current_closure();
-        let res = closure.get(idx as usize).set(value);
+        let res = closure.get(idx).set(value);
 
         if let Err(i) = res {
             self.stack[i] = value
         }
+
+        Ok(())
     }
 
-    #[flame]
-    fn get_upvalue(&mut self) {
-        let idx = self.frame_mut().read_byte();
+    #[cfg_attr(feature = "profiling", flame)]
+    fn get_upvalue(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let value = self.current_closure()
-            .get(idx as usize)
+            .get(idx)
             .get()
             .unwrap_or_else(|i| self.stack[i]);
-        
+
         self.push(value)
     }
 
-    #[flame]
-    fn close_upvalue(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn close_upvalue(&mut self) -> Result<(), RuntimeError> {
         let end = self.stack.len() - 1;
 
         self.close_upvalues(end);
         self.pop();
+
+        Ok(())
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn close_upvalues(&mut self, stack_end: usize) {
         let mut open_upvalues = Vec::new();
 
         mem::swap(&mut self.open_upvalues, &mut open_upvalues);
 
+        // Only upvalues pointing at or above `stack_end` are closed, the rest stay open. Closures
+        // already traced won't be looked at again, so their new values are marked here.
         for mut up in open_upvalues {
-            if up.get().map_err(|i| i >= stack_end).is_err() {
-                up.close(|i| self.stack[i]);
-                
-                self.open_upvalues.push(up)
+            match up.get() {
+                Err(i) if i >= stack_end => {
+                    if let Some(handle) = self.stack[i].as_object() {
+                        self.heap.shade(handle)
+                    }
+
+                    up.close(|i| self.stack[i])
+                },
+                Err(_) => self.open_upvalues.push(up),
+                Ok(_) => (),
             }
         }
     }
 
-    #[flame]
-    fn allocate(&mut self, object: Object) -> Handle<Object> {
+    #[cfg_attr(feature = "profiling", flame)]
+    pub(crate) fn allocate(&mut self, object: Object) -> Handle<Object> {
         let handle = self.heap.insert(object).into_handle();
 
-        if self.heap.len() * mem::size_of::<Object>() >= self.next_gc {
-            self.next_gc *= HEAP_GROWTH;
+        if self.gc.stress || self.heap.phase() != Phase::Idle || self.heap.bytes() >= self.next_gc {
+            self.collect_step(handle)
+        }
 
-            let upvalue_iter = self.open_upvalues.iter()
-                .flat_map(|u| u.get().ok())
-                .flat_map(|v| v.as_object());
+        handle
+    }
 
-            let globals_iter = self.globals.values().flat_map(Value::as_object);
-            let stack_iter = self.stack.iter().flat_map(Value::as_object);
+    // Moves the running collection along, `handle` is only held on to by the caller.
+    fn collect_step(&mut self, handle: Handle<Object>) {
+        let roots = roots(&self.stack, &self.frames, &self.globals, &self.open_upvalues, &self.resumers).chain(Some(handle));
 
-            let exclude = stack_iter
-                .chain(Some(handle))
-                .chain(globals_iter)
-                .chain(upvalue_iter);
-            
-            self.heap.clean_excluding(exclude);
+        let done = if self.gc.stress || self.gc.step == 0 {
+            self.heap.clean_excluding(roots);
+            true
+        } else {
+            self.heap.step(self.gc.step, roots)
+        };
+
+        if done {
+            self.schedule_collection()
         }
+    }
 
-        handle
+    // Frees everything no longer reachable right away, finishing off the collection that's running if there is one.
+    pub fn collect(&mut self) {
+        let roots = roots(&self.stack, &self.frames, &self.globals, &self.open_upvalues, &self.resumers);
+
+        self.heap.clean_excluding(roots);
+        self.schedule_collection()
+    }
+
+    fn schedule_collection(&mut self) {
+        let grown = self.heap.bytes() as f64 * self.gc.growth;
+
+        self.next_gc = self.gc.threshold.max(grown as usize)
     }
 
-    fn constant(&mut self, idx: u8) {
+    fn constant(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let val = self.frame_mut().read_constant_at(idx);
         self.push(val)
     }
 
-    #[flame]
-    fn print(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn print(&mut self) -> Result<(), RuntimeError> {
         let value = self.pop();
-        println!("{}", value.with_heap(&self.heap))
+        println!("{}", value.with_heap(&self.heap));
+
+        Ok(())
     }
 
-    #[flame]
-    fn add(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn add(&mut self) -> Result<(), RuntimeError> {
         let b = self.pop();
         let a = self.pop();
 
         use self::Variant::*;
 
-        match (a.decode(), b.decode()) {
-            (Float(a), Float(b)) => return self.push((a + b).into()),
-            (Obj(a), Obj(b)) => {
-                let a = self.deref(a).as_string().unwrap();
-                let b = self.deref(b).as_string().unwrap();
-
-                let new = self.allocate(Object::String(format!("{}{}", a, b)));
+        if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
+            return self.push(x.apply(Arith::Add, y).expect("only `IntDiv` fails").into())
+        }
 
-                return self.push(new.into())
+        let joined = match (a.decode(), b.decode()) {
+            (Obj(lhs), Obj(rhs)) => match (self.deref(lhs).as_string(), self.deref(rhs).as_string()) {
+                (Some(lhs), Some(rhs)) => Some(format!("{}{}", lhs, rhs)),
+                _ => None,
             },
-            (Obj(a), Float(b)) => {
-                let a = self.deref(a).as_string().unwrap();
-
-                let new = self.allocate(Object::String(format!("{}{}", a, b)));
+            (Obj(lhs), _) => b.as_number().and_then(|rhs| self.deref(lhs).as_string().map(|lhs| format!("{}{}", lhs, rhs))),
+            (_, Obj(rhs)) => a.as_number().and_then(|lhs| self.deref(rhs).as_string().map(|rhs| format!("{}{}", lhs, rhs))),
+            _ => None,
+        };
 
-                return self.push(new.into())
-            },
-            (Float(a), Obj(b)) => {
-                let b = self.deref(b).as_string().unwrap();
+        match joined {
+            Some(string) => {
+                self.check_length("string", string.len())?;
 
-                let new = self.allocate(Object::String(format!("{}{}", a, b)));
+                let new = self.allocate(Object::String(string));
 
-                return self.push(new.into())
+                self.push(new.into())
             },
-            _ => {}
+            None => self.binary_type_error("+", a, b),
         }
     }
 
-    #[flame]
-    fn get_global(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn get_global(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let global = self.frame_mut()
-            .read_constant()
+            .read_constant_at(idx)
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
             .expect("`GetGlobal` requires a string identifier");
-        
+
         if let Some(value) = self.globals.get(global).cloned() {
             self.push(value)
         } else {
-            self.runtime_error(&format!("undefined global variable: `{}`", global.clone()))
+            self.runtime_error(RuntimeErrorKind::UndefinedGlobal, format!("undefined global variable: `{}`", global))
         }
     }
 
-    #[flame]
-    fn define_global(&mut self) {
-        let var = self.frame_mut().read_constant()
+    #[cfg_attr(feature = "profiling", flame)]
+    fn define_global(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let var = self.frame_mut().read_constant_at(idx)
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
             .cloned()
             .expect("expected constant to be a string value");
-        
-        let lhs = self.stack.pop().unwrap();
+
+        let lhs = self.pop();
 
         self.globals.insert(var, lhs);
+
+        Ok(())
     }
 
-    #[flame]
-    fn set_global(&mut self) {
-        let handle = self.frame_mut().read_constant()
+    #[cfg_attr(feature = "profiling", flame)]
+    fn set_global(&mut self, idx: usize) -> Result<(), RuntimeError> {
+        let handle = self.frame_mut().read_constant_at(idx)
             .as_object()
             .filter(|&o| self.deref(o).as_string().is_some())
             .expect("expected constant to be a string value");
-    
+
         let var = unsafe {
-            handle.get_mut_unchecked()
+            handle.get_unchecked()
                 .as_string()
                 .unwrap()
         };
 
-        let value = *self.stack.last().unwrap();
+        let value = self.peek();
 
         if let Some(slot) = self.globals.get_mut(var) {
             *slot = value
         } else {
             self.globals.insert(var.clone(), value);
         }
+
+        Ok(())
     }
 
-    #[flame]
-    fn dict(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn dict(&mut self, element_count: usize) -> Result<(), RuntimeError> {
         use im_rc::hashmap::HashMap;
 
-        let element_count = self.read_byte();
-
         let mut content = HashMap::new();
 
         for _ in 0 .. element_count {
             let value = self.pop();
-            let key   = HashValue {
-                variant: self.pop().decode().to_hash(&self.heap)
-            };
+            let key = self.pop();
+            let key = self.hash_key(key)?;
 
             content.insert(key, value);
         }
 
+        self.check_length("dict", content.len())?;
+
         let val = self.allocate(Object::Dict(Dict::new(content))).into();
         self.push(val)
     }
 
-    #[flame]
-    fn set_dict_element(&mut self) {
-        // corn
-        let dict  = self.pop();
-        let key = HashValue {
-            variant: self.pop().decode().to_hash(&self.heap)
-        };
-
-        let value = self.pop();
-
-        let dict_object = dict
-            .as_object()
-            .map(|o| self.heap.get_mut_unchecked(o));
-
-        if let Some(Object::Dict(ref mut dict)) = dict_object {
-            dict.insert(key, value)
-        }
-    }
-
-    #[flame]
-    fn get_dict_element(&mut self) {
-        // corn
-        let dict  = self.pop();
-        let key = HashValue {
-            variant: self.pop().decode().to_hash(&self.heap)
-        };
-
-        let dict_handle = dict
-            .as_object()
-            .unwrap();
-
-        let dict = self.deref(dict_handle);
-
-        if let Some(value) = dict.as_dict().unwrap().get(&key) {
-            self.push(*value)
-        } else {
-            panic!("no such field `{:?}` on dict", key)
-        }
-    }
-
-    #[flame]
-    fn list(&mut self) {
-        let element_count = self.read_byte();
-
+    #[cfg_attr(feature = "profiling", flame)]
+    fn list(&mut self, element_count: usize) -> Result<(), RuntimeError> {
         let mut content = Vec::new();
 
         for _ in 0 .. element_count {
             content.push(self.pop())
         }
 
+        self.check_length("list", content.len())?;
+
         let val = self.allocate(Object::List(List::new(content))).into();
         self.push(val)
     }
 
-    #[flame]
-    fn set_list_element(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn set_element(&mut self) -> Result<(), RuntimeError> {
         let list = self.pop();
-        let idx  = if let Variant::Float(ref index) = self.pop().decode() {
-            *index as usize
-        } else {
-            panic!("Can't index list with non-number")
-        };
-
+        let index = self.pop();
         let value = self.pop();
 
-        let list_object = list
-            .as_object()
-            .map(|o| self.heap.get_mut_unchecked(o));
+        if let Some(handle) = list.as_object() {
+            match self.deref(handle) {
+                Object::List(ref list) => {
+                    let idx = self.list_index(index, list.content.len())?;
+
+                    if let Object::List(ref mut list) = self.deref_mut(handle) {
+                        list.set(idx, value)
+                    }
+
+                    return Ok(())
+                },
+
+                Object::Dict(ref dict) => {
+                    let len = dict.content.len();
+                    let key = self.hash_key(index)?;
+
+                    if !dict.content.contains_key(&key) {
+                        self.check_length("dict", len + 1)?
+                    }
+
+                    if let Object::Dict(ref mut dict) = self.deref_mut(handle) {
+                        dict.insert(key, value)
+                    }
+
+                    return Ok(())
+                },
 
-        if let Some(Object::List(ref mut list)) = list_object {
-            list.set(idx as usize, value)
+                _ => ()
+            }
         }
+
+        let list = list.type_name(&self.heap);
+        self.runtime_error(RuntimeErrorKind::Type, format!("can't set element of value of type {}", list))
     }
 
-    #[flame]
-    fn set_element(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn index(&mut self) -> Result<(), RuntimeError> {
         let list = self.pop();
         let index = self.pop();
-        let value = self.pop();
 
-        let variant = match index.decode() {
-            Variant::Float(n) => HashVariant::Int(n as i64),
-            c @ Variant::True | c @ Variant::False => HashVariant::Bool(c == Variant::True),
-            Variant::Obj(ref handle) => {
-                HashVariant::Str(self.deref(*handle).as_string().unwrap().to_owned())
-            },
-            Nil => HashVariant::Nil,
-        };
+        if let Some(handle) = list.as_object() {
+            match self.deref(handle) {
+                Object::List(ref list) => {
+                    let idx = self.list_index(index, list.content.len())?;
+                    let element = list.get(idx);
 
-        let list_object = self.heap.get_mut_unchecked(list.as_object().unwrap());
+                    return self.push(element)
+                },
 
-        if let Object::List(list) = list_object {
-            let idx = if let Variant::Float(ref index) = index.decode() {
-                *index as usize
-            } else {
-                panic!("Can't index list with non-number")
-            };
-    
-            list.set(idx as usize, value);
+                Object::Dict(ref dict) => {
+                    let key = self.hash_key(index)?;
 
-            return
+                    return if let Some(value) = dict.get(&key) {
+                        self.push(*value)
+                    } else {
+                        self.runtime_error(
+                            RuntimeErrorKind::MissingKey,
+                            format!("no such field `{}` on dict", index.with_heap(&self.heap))
+                        )
+                    }
+                },
+
+                _ => ()
+            }
         }
 
-        if let Object::Dict(dict) = list_object {
-            let key = HashValue {
-                variant
-            };
+        let list = list.type_name(&self.heap);
+        self.runtime_error(RuntimeErrorKind::Type, format!("can't index value of type {}", list))
+    }
 
-            dict.insert(key, value);
+    fn check_length(&self, what: &str, len: usize) -> Result<(), RuntimeError> {
+        match self.limits.length {
+            Some(max) if len > max => self.runtime_error(
+                RuntimeErrorKind::LengthLimit,
+                format!("{} of length {} is over the limit of {}", what, len, max)
+            ),
+            _ => Ok(()),
         }
     }
 
-    #[flame]
-    fn index(&mut self) {
-        let list = self.pop();
-        let index = self.pop();
+    fn list_index(&self, index: Value, len: usize) -> Result<usize, RuntimeError> {
+        if let Variant::Int(index) = index.decode() {
+            if index < 0 || index as usize >= len {
+                return self.runtime_error(
+                    RuntimeErrorKind::IndexOutOfBounds,
+                    format!("index {} is out of bounds for list of length {}", index, len)
+                )
+            }
 
-        let list_handle = list
-            .as_object()
-            .unwrap();
+            return Ok(index as usize)
+        }
 
-        let list = self.deref(list_handle);
+        // Whole floats do as well
+        if let Variant::Float(index) = index.decode() {
+            if index.fract() != 0.0 {
+                return self.runtime_error(RuntimeErrorKind::Type, format!("list index must be a whole number, got {}", index))
+            }
 
-        if let Some(list) = list.as_list() {
-            let idx = if let Variant::Float(ref index) = index.decode() {
-                *index as usize
-            } else {
-                panic!("Can't index list with non-number")
-            };
-    
-            let element = list.get(idx as usize);
-    
-            self.push(element);
+            if index < 0.0 || index >= len as f64 {
+                return self.runtime_error(
+                    RuntimeErrorKind::IndexOutOfBounds,
+                    format!("index {} is out of bounds for list of length {}", index, len)
+                )
+            }
 
-            return
+            Ok(index as usize)
+        } else {
+            let index = index.type_name(&self.heap);
+            self.runtime_error(RuntimeErrorKind::Type, format!("can't index list with value of type {}", index))
         }
+    }
 
-        if let Some(dict) = list.as_dict() {
-            let key = HashValue {
-                variant: index.decode().to_hash(&self.heap)
-            };
-
-            if let Some(value) = dict.get(&key) {
-                self.push(*value)
-            } else {
-                panic!("no such field `{:?}` on dict with {:#?}", key, dict.content)
-            }
+    fn hash_key(&self, key: Value) -> Result<HashValue, RuntimeError> {
+        if let Some(variant) = key.decode().to_hash(&self.heap) {
+            Ok(HashValue { variant })
+        } else {
+            let key = key.type_name(&self.heap);
+            self.runtime_error(RuntimeErrorKind::Type, format!("can't use value of type {} as a dict key", key))
         }
     }
 
-    fn runtime_error(&self, err: &str) {
-        eprintln!("[error]: {}.", err);
+    pub(crate) fn runtime_error<T>(&self, kind: RuntimeErrorKind, message: impl Into<String>) -> Result<T, RuntimeError> {
+        Err(self.with_trace(RuntimeError::new(kind, message)))
+    }
+
+    // Fills in the call stack of errors raised outside of the VM, such as by natives.
+    fn with_trace(&self, mut err: RuntimeError) -> RuntimeError {
+        if !err.trace.is_empty() {
+            return err
+        }
+
         for frame in self.frames.iter().rev() {
             let ip = frame.ip;
             frame.with_chunk(|chunk| {
-                let name = chunk.name();
-                let line = chunk.line(ip);
-                eprintln!("         at [line {}] in {}", line, name);
+                err.trace.push(
+                    TraceFrame {
+                        function: chunk.name().to_owned(),
+                        line: chunk.line(ip),
+                    }
+                )
             });
         }
-        ::std::process::exit(1);
+
+        err
     }
 
-    fn on_loop(&mut self) {
-        self.frame_mut().ip -= self.read_u16() as usize
+    fn unknown_op(&mut self, op: u8) -> Result<(), RuntimeError> {
+        self.runtime_error(RuntimeErrorKind::InvalidOp, format!("unknown op {}", op))
     }
 
-    fn get_local(&mut self) {
+    fn get_local(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let start = self.frame().stack_start;
-        let idx = self.read_byte() as usize;
         let val = self.stack[start + idx];
 
         self.push(val)
     }
 
-    fn set_local(&mut self) {
+    fn set_local(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let val = self.peek();
         let start = self.frame().stack_start;
-        let idx = self.read_byte() as usize;
 
-        self.stack[start + idx] = val
+        self.stack[start + idx] = val;
+
+        Ok(())
     }
 
-    fn immediate(&mut self) {
+    fn immediate(&mut self) -> Result<(), RuntimeError> {
         let raw = self.frame_mut().read_u64();
         let val = unsafe { Value::from_raw(raw) };
 
         self.push(val)
     }
 
-    fn imm_nil(&mut self) {
-        self.push(Value::nil());
+    fn add_const(&mut self) -> Result<(), RuntimeError> {
+        self.immediate()?;
+        self.add()
+    }
+
+    fn imm_nil(&mut self) -> Result<(), RuntimeError> {
+        self.push(Value::nil())
+    }
+
+    fn imm_true(&mut self) -> Result<(), RuntimeError> {
+        self.push(Value::truelit())
     }
 
-    fn imm_true(&mut self) {
-        self.push(Value::truelit());
+    fn imm_false(&mut self) -> Result<(), RuntimeError> {
+        self.push(Value::falselit())
     }
 
-    fn imm_false(&mut self) {
-        self.push(Value::falselit());
+    #[cfg_attr(feature = "profiling", flame)]
+    fn sub(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::Sub)
     }
 
-    #[flame]
-    fn sub(&mut self) {
-        binary_op!(self, -);
+    #[cfg_attr(feature = "profiling", flame)]
+    fn mul(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::Mul)
     }
 
-    #[flame]
-    fn mul(&mut self) {
-        binary_op!(self, *);
+    #[cfg_attr(feature = "profiling", flame)]
+    fn rem(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::Rem)
     }
 
-    #[flame]
-    fn rem(&mut self) {
-        binary_op!(self, %);
+    #[cfg_attr(feature = "profiling", flame)]
+    fn pow(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::Pow)
     }
 
-    #[flame]
-    fn pow(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn div(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::Div)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn int_div(&mut self) -> Result<(), RuntimeError> {
+        self.arith(Arith::IntDiv)
+    }
+
+    // Numbers only, see `Number` for when the result is an int
+    fn arith(&mut self, op: Arith) -> Result<(), RuntimeError> {
         let b = self.pop();
         let a = self.pop();
 
-        if let (Variant::Float(a), Variant::Float(b)) = (a.decode(), b.decode()) {
-            let c = a.powf(b);
+        match (a.as_number(), b.as_number()) {
+            (Some(x), Some(y)) => match x.apply(op, y) {
+                Some(result) => self.push(result.into()),
+                None => self.runtime_error(RuntimeErrorKind::DivisionByZero, format!("can't divide int {} by zero", a.with_heap(&self.heap))),
+            },
+            _ => self.binary_type_error(op.symbol(), a, b),
+        }
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn bit_and(&mut self) -> Result<(), RuntimeError> {
+        self.bitwise(Bitwise::And)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn bit_or(&mut self) -> Result<(), RuntimeError> {
+        self.bitwise(Bitwise::Or)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn bit_xor(&mut self) -> Result<(), RuntimeError> {
+        self.bitwise(Bitwise::Xor)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn shl(&mut self) -> Result<(), RuntimeError> {
+        self.bitwise(Bitwise::Shl)
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn shr(&mut self) -> Result<(), RuntimeError> {
+        self.bitwise(Bitwise::Shr)
+    }
+
+    // Ints only, floats aren't rounded into them
+    fn bitwise(&mut self, op: Bitwise) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
 
-            self.push(c.into());
+        match (a.decode(), b.decode()) {
+            (Variant::Int(x), Variant::Int(y)) => self.push(Value::int(op.apply(x, y))),
+            _ => self.binary_type_error(op.symbol(), a, b),
         }
     }
 
-    #[flame]
-    fn div(&mut self) {
-        binary_op!(self, /);
+    #[cfg_attr(feature = "profiling", flame)]
+    fn bit_not(&mut self) -> Result<(), RuntimeError> {
+        let a = self.pop();
+
+        if let Variant::Int(a) = a.decode() {
+            return self.push(Value::int(!a))
+        }
+
+        let message = format!("can't apply `~` to a value of type {}", a.type_name(&self.heap));
+
+        self.runtime_error(RuntimeErrorKind::Type, message)
     }
 
-    #[flame]
-    fn neg(&mut self) {
-        if let Variant::Float(a) = self.pop().decode() {
-            self.push((-a).into());
+    #[cfg_attr(feature = "profiling", flame)]
+    fn neg(&mut self) -> Result<(), RuntimeError> {
+        let a = self.pop();
+
+        if let Some(a) = a.as_number() {
+            return self.push((-a).into())
         }
+
+        let message = format!("can't negate a value of type {}", a.type_name(&self.heap));
+
+        self.runtime_error(RuntimeErrorKind::Type, message)
     }
 
-    #[flame]
-    fn not(&mut self) {
+    #[cfg_attr(feature = "profiling", flame)]
+    fn not(&mut self) -> Result<(), RuntimeError> {
         let a = self.pop();
 
         self.push(
@@ -737,37 +1960,152 @@ impl VM {
         )
     }
 
-    #[flame]
-    fn eq(&mut self) {
-        binary_op!(self, ==);
+    #[cfg_attr(feature = "profiling", flame)]
+    fn eq(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let equal = self.values_equal(a, b);
+
+        self.push(equal.into())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn gt(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let ordering = self.compare(">", a, b)?;
+
+        self.push((ordering == Some(Ordering::Greater)).into())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn lt(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let ordering = self.compare("<", a, b)?;
+
+        self.push((ordering == Some(Ordering::Less)).into())
+    }
+
+    // The fused forms have to agree with `Less; Not` and `Greater; Not`, so NaN is greater than or equal to anything
+    #[cfg_attr(feature = "profiling", flame)]
+    fn ge(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let ordering = self.compare(">=", a, b)?;
+
+        self.push((ordering != Some(Ordering::Less)).into())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn le(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let ordering = self.compare("<=", a, b)?;
+
+        self.push((ordering != Some(Ordering::Greater)).into())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn neq(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let equal = self.values_equal(a, b);
+
+        self.push((!equal).into())
     }
 
-    #[flame]
-    fn gt(&mut self) {
-        binary_op!(self, >);
+    // Numbers compare by value, ints and floats alike, and strings by content. Everything else by identity.
+    fn values_equal(&self, a: Value, b: Value) -> bool {
+        if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+            return a.compare(b) == Some(Ordering::Equal)
+        }
+
+        match (a.decode(), b.decode()) {
+            (Variant::Obj(a), Variant::Obj(b)) => {
+                match (self.deref(a).as_string(), self.deref(b).as_string()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => a == b,
+                }
+            },
+            _ => a == b,
+        }
+    }
+
+    // Ordering is only defined between two numbers or two strings. NaN is unordered.
+    fn compare(&self, op: &str, a: Value, b: Value) -> Result<Option<Ordering>, RuntimeError> {
+        if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
+            return Ok(x.compare(y))
+        }
+
+        if let (Variant::Obj(lhs), Variant::Obj(rhs)) = (a.decode(), b.decode()) {
+            if let (Some(lhs), Some(rhs)) = (self.deref(lhs).as_string(), self.deref(rhs).as_string()) {
+                return Ok(Some(lhs.cmp(rhs)))
+            }
+        }
+
+        self.binary_type_error(op, a, b)
     }
 
-    #[flame]
-    fn lt(&mut self) {
-        binary_op!(self, <);
+    fn binary_type_error<T>(&self, op: &str, a: Value, b: Value) -> Result<T, RuntimeError> {
+        let message = format!(
+            "can't apply `{}` to {} and {}",
+            op,
+            a.type_name(&self.heap),
+            b.type_name(&self.heap)
+        );
+
+        self.runtime_error(RuntimeErrorKind::Type, message)
     }
 
-    #[flame]
-    fn jmp(&mut self) {
-        self.frame_mut().ip = self.read_u16() as usize
+    #[cfg_attr(feature = "profiling", flame)]
+    fn jmp(&mut self, ip: usize) -> Result<(), RuntimeError> {
+        if ip < self.frame().ip {
+            self.check_interrupt()?
+        }
+
+        self.frame_mut().ip = ip;
+
+        Ok(())
     }
 
-    #[flame]
-    fn jze(&mut self) {
-        let ip = self.read_u16();
+    #[cfg_attr(feature = "profiling", flame)]
+    fn jze(&mut self, ip: usize) -> Result<(), RuntimeError> {
         if !self.peek().truthy() {
-            self.frame_mut().ip = ip as usize
+            self.frame_mut().ip = ip
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn jze_pop(&mut self, ip: usize) -> Result<(), RuntimeError> {
+        if !self.pop().truthy() {
+            self.frame_mut().ip = ip
         }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profiling", flame)]
+    fn op_loop(&mut self, sub: usize) -> Result<(), RuntimeError> {
+        self.check_interrupt()?;
+
+        self.frame_mut().ip -= sub;
+
+        Ok(())
     }
 
-    #[flame]
-    fn op_loop(&mut self) {
-        self.frame_mut().ip -= self.read_u16() as usize
+    fn op_pop(&mut self) -> Result<(), RuntimeError> {
+        self.pop();
+
+        Ok(())
     }
 
     fn frame(&self) -> &CallFrame {
@@ -786,31 +2124,106 @@ impl VM {
         self.frame_mut().read_u16()
     }
 
-    fn push(&mut self, value: Value) {
-        if self.stack.len() == STACK_SIZE {
-            panic!("STACK OVERFLOW >:( @ {:#?}", &self.stack[STACK_SIZE - 50 ..]);
+    fn read_u32(&mut self) -> u32 {
+        self.frame_mut().read_u32()
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.limits.stack {
+            return self.runtime_error(RuntimeErrorKind::StackOverflow, format!("stack exceeded {} values", self.limits.stack))
         }
 
         self.stack.push(value);
+
+        Ok(())
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn pop(&mut self) -> Value {
         self.stack.pop().expect("stack to be nonempty")
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn peek(&mut self) -> Value {
         *self.stack.last().expect("stack to be nonempty")
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn deref(&self, o: Handle<Object>) -> &Object {
         unsafe { self.heap.get_unchecked(o) }
     }
 
-    #[flame]
+    #[cfg_attr(feature = "profiling", flame)]
     fn deref_mut(&mut self, o: Handle<Object>) -> &mut Object {
         self.heap.get_mut_unchecked(o)
     }
-}
\ No newline at end of file
+}
+
+// Everything the VM holds on to outside the heap, from its fields so the heap can be borrowed alongside
+fn roots<'a>(
+    stack: &'a [Value],
+    frames: &'a [CallFrame],
+    globals: &'a HashMap<String, Value, FnvBuildHasher>,
+    open_upvalues: &'a [UpValue],
+    resumers: &'a [(Handle<Object>, ExecutionContext)],
+) -> impl Iterator<Item = Handle<Object>> + 'a {
+    let upvalue_iter = open_upvalues.iter()
+        .flat_map(|u| u.get().ok())
+        .flat_map(|v| v.as_object());
+
+    let globals_iter = globals.values().flat_map(Value::as_object);
+    let stack_iter = stack.iter().flat_map(Value::as_object);
+
+    // Running closures, which the stack usually has too, but not always
+    let frame_iter = frames.iter().map(|f| f.closure);
+
+    // Suspended resumers, and the coroutines they're waiting on
+    let resumer_iter = resumers.iter()
+        .flat_map(|(coroutine, context)| {
+            let upvalues = context.upvalues.iter()
+                .flat_map(|(_, u)| u.get().ok())
+                .flat_map(|v| v.as_object());
+
+            context.stack.iter()
+                .flat_map(Value::as_object)
+                .chain(context.frames.iter().map(|f| f.closure))
+                .chain(upvalues)
+                .chain(Some(*coroutine))
+        });
+
+    stack_iter
+        .chain(frame_iter)
+        .chain(globals_iter)
+        .chain(upvalue_iter)
+        .chain(resumer_iter)
+}
+
+// The verifier makes sure global ops name a string constant, and the chunk holds on to it
+#[inline(always)]
+fn global_name(constants: &[Value], idx: u8) -> &str {
+    let handle = constants[idx as usize].as_object().expect("global names are strings");
+
+    unsafe {
+        let object: *const Object = handle.get_unchecked();
+
+        (*object).as_string().expect("global names are strings")
+    }
+}
+
+#[inline(always)]
+fn read_u16(code: &[u8], ip: usize) -> u16 {
+    u16::from_le_bytes([code[ip], code[ip + 1]])
+}
+
+#[inline(always)]
+fn read_u32(code: &[u8], ip: usize) -> u32 {
+    u32::from_le_bytes([code[ip], code[ip + 1], code[ip + 2], code[ip + 3]])
+}
+
+#[inline(always)]
+fn read_u64(code: &[u8], ip: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&code[ip..ip + 8]);
+
+    u64::from_le_bytes(bytes)
+}